@@ -3,6 +3,16 @@
 from unittest import TestCase
 import os
 import errno
+import fcntl
+import mmap
+import select
+import signal
+import socket
+import struct
+import subprocess
+import sys
+import termios
+import threading
 import time
 
 
@@ -50,6 +60,18 @@ class FileTest(TestCase):
     def test_expect_success(self):
         func_under_test(FileTest.FILE_TO_FAIL_ON)
 
+    def test_read_fails_without_delay(self):
+        os.environ['LIBFAULTINJ_ERROR_PATH'] = FileTest.FILE_TO_FAIL_ON
+        os.environ['LIBFAULTINJ_DELAY_PATH'] = FileTest.FILE_TO_FAIL_ON
+        os.environ['LIBFAULTINJ_ERROR_READ_ERRNO'] = str(errno.EIO)
+        os.environ['LIBFAULTINJ_DELAY_READ_MS'] = '1000'
+        os.environ['LIBFAULTINJ_DELAY_LIKELIHOOD_PCT'] = '0'
+
+        start = time.time()
+        with self.assertRaises(IOFailure):
+            func_under_test(FileTest.FILE_TO_FAIL_ON)
+        self.assertLess(time.time() - start, 1.)
+
 
 class NetTest(TestCase):
     # Value should represent the injected delay duration, in seconds.
@@ -115,15 +137,22 @@ class NetTest(TestCase):
         return connect_dur_sec, write_dur_sec
 
     def test_send_delay(self):
-        os.environ['LIBFAULTINJ_DELAY_PATH'] = 'ignore'
+        os.environ['LIBFAULTINJ_DELAY_ADDR'] = '192.0.2.1'
         connect_dur_sec, write_dur_sec = self._connect_and_send(b'T')
         assert write_dur_sec < NetTest.MAX_WRITE_DUR_SEC
 
         os.environ['LIBFAULTINJ_DELAY_SEND_MS'] = str(int(NetTest.INJECTED_WRITE_DELAY_DUR_SEC * 1000))
-        os.environ['LIBFAULTINJ_DELAY_PATH'] = self.local_addr
+        os.environ['LIBFAULTINJ_DELAY_ADDR'] = '{}:{}'.format(*self.server.server_address)
         connect_dur_sec, send_dur_sec = self._connect_and_send(b't')
         assert send_dur_sec > NetTest.INJECTED_WRITE_DELAY_DUR_SEC
 
+    def test_send_delay_distribution(self):
+        os.environ['LIBFAULTINJ_DELAY_SEND_DIST'] = 'uniform:300,400'
+        os.environ['LIBFAULTINJ_DELAY_ADDR'] = '{}:{}'.format(*self.server.server_address)
+        connect_dur_sec, send_dur_sec = self._connect_and_send(b't')
+        self.assertGreaterEqual(send_dur_sec, .3)
+        self.assertLess(send_dur_sec, .4 + NetTest.MAX_WRITE_DUR_SEC)
+
     def tearDown(self):
         self.server.shutdown()
         self.server.server_close()
@@ -132,3 +161,1044 @@ class NetTest(TestCase):
         cleanup_env()
 
 #        print('request occurred', self.request_occurred.is_set())
+
+
+class AcceptTest(TestCase):
+    def setUp(self):
+        cleanup_env()
+        assert 'LD_PRELOAD' in os.environ
+
+    def tearDown(self):
+        cleanup_env()
+
+    def test_accept_aborted(self):
+        from contextlib import closing
+
+        # The listener's faults are inherited by the sockets it accepts.
+        os.environ['LIBFAULTINJ_ERROR_ADDR'] = '127.0.0.1'
+        with closing(socket.socket(socket.AF_INET, socket.SOCK_STREAM)) as listener:
+            listener.bind(('127.0.0.1', 0))
+            listener.listen(2)
+
+            os.environ['LIBFAULTINJ_ERROR_ACCEPT_ERRNO'] = str(errno.ECONNABORTED)
+            with closing(socket.create_connection(listener.getsockname())):
+                with self.assertRaises(ConnectionAbortedError):
+                    listener.accept()
+
+            del os.environ['LIBFAULTINJ_ERROR_ACCEPT_ERRNO']
+            os.environ['LIBFAULTINJ_ERROR_RECV_ERRNO'] = str(errno.ECONNRESET)
+            with closing(socket.create_connection(listener.getsockname())):
+                conn, _ = listener.accept()
+                with closing(conn):
+                    with self.assertRaises(ConnectionResetError):
+                        conn.recv(1)
+
+    def test_accept_aborted_queue(self):
+        from contextlib import closing
+
+        os.environ['LIBFAULTINJ_ERROR_ADDR'] = '127.0.0.1'
+        os.environ['LIBFAULTINJ_ERROR_ACCEPT_ERRNO'] = str(errno.ECONNABORTED)
+        with closing(socket.socket(socket.AF_INET, socket.SOCK_STREAM)) as listener:
+            listener.bind(('127.0.0.1', 0))
+            listener.listen(2)
+
+            # Nothing's queued, and a blocking listener mustn't wait for it.
+            signal.alarm(5)
+            try:
+                with self.assertRaises(ConnectionAbortedError):
+                    listener.accept()
+            finally:
+                signal.alarm(0)
+
+            # A non-blocking listener loses the connection that was pending.
+            listener.setblocking(False)
+            with closing(socket.create_connection(listener.getsockname())):
+                with self.assertRaises(ConnectionAbortedError):
+                    listener.accept()
+                del os.environ['LIBFAULTINJ_ERROR_ACCEPT_ERRNO']
+                with self.assertRaises(BlockingIOError):
+                    listener.accept()
+
+
+class ResetTest(TestCase):
+    def setUp(self):
+        cleanup_env()
+        assert 'LD_PRELOAD' in os.environ
+
+        self.listener = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        self.listener.bind(('127.0.0.1', 0))
+        self.listener.listen(1)
+        os.environ['LIBFAULTINJ_ERROR_ADDR'] = '*:{}'.format(self.listener.getsockname()[1])
+
+    def tearDown(self):
+        self.listener.close()
+        cleanup_env()
+
+    def _connect(self):
+        client = socket.create_connection(self.listener.getsockname())
+        server, _ = self.listener.accept()
+        server.sendall(b'0123456789')
+        return client, server
+
+    def test_reset_after_bytes(self):
+        os.environ['LIBFAULTINJ_RESET_AFTER_BYTES'] = '4'
+        client, server = self._connect()
+
+        self.assertEqual(client.recv(16), b'0123')
+        with self.assertRaises(ConnectionResetError):
+            client.recv(16)
+        with self.assertRaises(BrokenPipeError):
+            client.send(b'x')
+
+        client.close()
+        server.close()
+
+    def test_msg_reset_after_bytes(self):
+        os.environ['LIBFAULTINJ_RESET_AFTER_BYTES'] = '4'
+        client, server = self._connect()
+
+        bufs = [bytearray(2), bytearray(8)]
+        self.assertEqual(client.recvmsg_into(bufs)[0], 4)
+        self.assertEqual(bytes(bufs[1][:2]), b'23')
+        with self.assertRaises(ConnectionResetError):
+            client.sendmsg([b'x'])
+
+        client.close()
+        server.close()
+
+        client, server = self._connect()
+        self.assertEqual(client.sendmsg([b'01', b'2345']), 4)
+        self.assertEqual(server.recv(16), b'0123')
+
+        client.close()
+        server.close()
+
+    def test_eof_after_calls(self):
+        os.environ['LIBFAULTINJ_RESET_AFTER_CALLS'] = '1'
+        os.environ['LIBFAULTINJ_RESET_MODE'] = 'eof'
+        client, server = self._connect()
+
+        self.assertEqual(client.recv(16), b'0123456789')
+        self.assertEqual(client.recv(16), b'')
+        self.assertEqual(client.send(b'x'), 1)
+
+        client.close()
+        server.close()
+
+
+class StormTest(TestCase):
+    def setUp(self):
+        cleanup_env()
+        assert 'LD_PRELOAD' in os.environ
+
+        self.listener = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        self.listener.bind(('127.0.0.1', 0))
+        self.listener.listen(1)
+        os.environ['LIBFAULTINJ_ERROR_ADDR'] = '*:{}'.format(self.listener.getsockname()[1])
+
+        self.client = socket.create_connection(self.listener.getsockname())
+        self.server, _ = self.listener.accept()
+        self.server.sendall(b'0123456789')
+
+    def tearDown(self):
+        self.client.close()
+        self.server.close()
+        self.listener.close()
+        cleanup_env()
+
+    def test_eintr_retried(self):
+        # Python retries on EINTR, so the storm goes unnoticed.
+        os.environ['LIBFAULTINJ_STORM_RECV_COUNT'] = '5'
+        self.assertEqual(self.client.recv(16), b'0123456789')
+
+    def test_eagain_storm(self):
+        os.environ['LIBFAULTINJ_STORM_COUNT'] = '2'
+        self.client.setblocking(False)
+
+        for _ in range(2):
+            with self.assertRaises(BlockingIOError):
+                self.client.recv(16)
+        self.assertEqual(self.client.recv(16), b'0123456789')
+
+
+class ConnectTest(TestCase):
+    def setUp(self):
+        cleanup_env()
+        assert 'LD_PRELOAD' in os.environ
+
+        self.listener = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        self.listener.bind(('127.0.0.1', 0))
+        self.listener.listen(1)
+        os.environ['LIBFAULTINJ_ERROR_ADDR'] = '*:{}'.format(self.listener.getsockname()[1])
+        os.environ['LIBFAULTINJ_DELAY_CONNECT_MS'] = '100'
+
+    def tearDown(self):
+        self.listener.close()
+        cleanup_env()
+
+    def test_connect_hangs(self):
+        os.environ['LIBFAULTINJ_CONNECT_MODE'] = 'hang'
+
+        with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as client:
+            client.settimeout(.5)
+            with self.assertRaises(socket.timeout):
+                client.connect(self.listener.getsockname())
+
+    def test_connect_delayed(self):
+        os.environ['LIBFAULTINJ_CONNECT_MODE'] = 'delay'
+
+        with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as client:
+            client.setblocking(False)
+            self.assertEqual(client.connect_ex(self.listener.getsockname()), errno.EINPROGRESS)
+
+            ep = select.epoll()
+            ep.register(client.fileno(), select.EPOLLOUT)
+            self.assertEqual(ep.poll(0), [])
+            self.assertEqual(ep.poll(1.), [(client.fileno(), select.EPOLLOUT)])
+            self.assertEqual(client.getsockopt(socket.SOL_SOCKET, socket.SO_ERROR), 0)
+            ep.close()
+
+    def test_connect_refused(self):
+        os.environ['LIBFAULTINJ_CONNECT_MODE'] = 'refuse'
+
+        with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as client:
+            client.settimeout(1.)
+            start = time.time()
+            with self.assertRaises(ConnectionRefusedError):
+                client.connect(self.listener.getsockname())
+            self.assertGreaterEqual(time.time() - start, .1)
+
+
+class ReadinessTest(TestCase):
+    def setUp(self):
+        cleanup_env()
+        assert 'LD_PRELOAD' in os.environ
+
+        self.listener = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        self.listener.bind(('127.0.0.1', 0))
+        self.listener.listen(1)
+        os.environ['LIBFAULTINJ_DELAY_ADDR'] = '*:{}'.format(self.listener.getsockname()[1])
+        os.environ['LIBFAULTINJ_DELAY_RECV_MS'] = '0'
+
+        self.client = socket.create_connection(self.listener.getsockname())
+        self.server, _ = self.listener.accept()
+        self.client.setblocking(False)
+
+    def tearDown(self):
+        self.client.close()
+        self.server.close()
+        self.listener.close()
+        cleanup_env()
+
+    def test_readiness_delayed(self):
+        os.environ['LIBFAULTINJ_DELAY_SELECT_MS'] = '300'
+        self.server.send(b'x')
+
+        start = time.time()
+        readable, _, _ = select.select([self.client], [], [], 2.)
+        self.assertEqual(readable, [self.client])
+        self.assertGreaterEqual(time.time() - start, .3)
+        self.assertEqual(self.client.recv(1), b'x')
+
+    def test_async_delay(self):
+        os.environ['LIBFAULTINJ_DELAY_RECV_MS'] = '300'
+        os.environ['LIBFAULTINJ_DELAY_SELECT_MS'] = '0'
+        self.server.send(b'x')
+
+        start = time.time()
+        with self.assertRaises(BlockingIOError):
+            self.client.recv(1)
+        self.assertLess(time.time() - start, .3)
+
+        readable, _, _ = select.select([self.client], [], [], 2.)
+        self.assertEqual(readable, [self.client])
+        self.assertGreaterEqual(time.time() - start, .3)
+        self.assertEqual(self.client.recv(1), b'x')
+
+    def test_async_delay_sendto(self):
+        receiver = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+        receiver.bind(('127.0.0.1', 0))
+        os.environ['LIBFAULTINJ_DELAY_ADDR'] = '*:{}'.format(receiver.getsockname()[1])
+        os.environ['LIBFAULTINJ_DELAY_SENDTO_MS'] = '300'
+
+        with socket.socket(socket.AF_INET, socket.SOCK_DGRAM) as sender:
+            sender.connect(receiver.getsockname())
+            sender.setblocking(False)
+
+            start = time.time()
+            with self.assertRaises(BlockingIOError):
+                sender.sendto(b'x', receiver.getsockname())
+            self.assertLess(time.time() - start, .3)
+
+            time.sleep(.3)
+            self.assertEqual(sender.sendto(b'x', receiver.getsockname()), 1)
+            self.assertEqual(receiver.recv(1), b'x')
+        receiver.close()
+
+    def test_spurious_readiness(self):
+        os.environ['LIBFAULTINJ_DELAY_POLL_MS'] = '0'
+        os.environ['LIBFAULTINJ_POLL_SPURIOUS_PCT'] = '100'
+
+        poller = select.poll()
+        poller.register(self.client, select.POLLIN)
+        self.assertEqual(poller.poll(1000), [(self.client.fileno(), select.POLLIN)])
+        with self.assertRaises(BlockingIOError):
+            self.client.recv(1)
+
+
+class ThrottleTest(TestCase):
+    def setUp(self):
+        cleanup_env()
+        assert 'LD_PRELOAD' in os.environ
+
+        self.listener = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        self.listener.bind(('127.0.0.1', 0))
+        self.listener.listen(1)
+        os.environ['LIBFAULTINJ_DELAY_ADDR'] = '*:{}'.format(self.listener.getsockname()[1])
+        os.environ['LIBFAULTINJ_DELAY_SEND_MS'] = '0'
+        os.environ['LIBFAULTINJ_THROTTLE_BPS'] = '10000'
+        os.environ['LIBFAULTINJ_THROTTLE_BURST'] = '1000'
+
+        self.client = socket.create_connection(self.listener.getsockname())
+        self.server, _ = self.listener.accept()
+
+    def tearDown(self):
+        self.client.close()
+        self.server.close()
+        self.listener.close()
+        cleanup_env()
+
+    def test_send_throttled(self):
+        start = time.time()
+        self.client.sendall(b'x' * 3000)
+        self.assertGreaterEqual(time.time() - start, .2)
+
+    def test_short_sends(self):
+        os.environ['LIBFAULTINJ_THROTTLE_MODE'] = 'short'
+        self.assertEqual(self.client.send(b'x' * 5000), 1000)
+
+        # The bucket's empty, so the next send moves what trickles in.
+        sent = self.client.send(b'x' * 5000)
+        self.assertGreaterEqual(sent, 1)
+        self.assertLess(sent, 1000)
+
+    def test_short_sendmsg(self):
+        os.environ['LIBFAULTINJ_THROTTLE_MODE'] = 'short'
+        self.assertEqual(self.client.sendmsg([b'x' * 3000, b'x' * 2000]), 1000)
+
+
+class HangTest(TestCase):
+    FILE_TO_HANG_ON = './somefile.txt'
+    RELEASE_PATH = './release'
+
+    def setUp(self):
+        cleanup_env()
+        with open(HangTest.FILE_TO_HANG_ON, 'wt') as f:
+            f.write('file contents')
+        assert 'LD_PRELOAD' in os.environ
+
+        os.environ['LIBFAULTINJ_DELAY_PATH'] = HangTest.FILE_TO_HANG_ON
+        os.environ['LIBFAULTINJ_DELAY_READ_MS'] = 'hang'
+        os.environ['LIBFAULTINJ_HANG_RELEASE_PATH'] = HangTest.RELEASE_PATH
+
+    def tearDown(self):
+        signal.setitimer(signal.ITIMER_REAL, 0)
+        signal.signal(signal.SIGALRM, signal.SIG_DFL)
+        for path in (HangTest.FILE_TO_HANG_ON, HangTest.RELEASE_PATH):
+            if os.path.exists(path):
+                os.unlink(path)
+        cleanup_env()
+
+    def test_released_by_file(self):
+        def release():
+            time.sleep(.3)
+            open(HangTest.RELEASE_PATH, 'w').close()
+        threading.Thread(target=release).start()
+
+        start = time.time()
+        self.assertEqual(func_under_test(HangTest.FILE_TO_HANG_ON), 'file contents')
+        self.assertGreaterEqual(time.time() - start, .3)
+
+    def test_released_by_signal(self):
+        os.environ['LIBFAULTINJ_HANG_RELEASE_SIGNAL'] = str(int(signal.SIGUSR1))
+        caught = []
+        previous = signal.signal(signal.SIGUSR1, lambda signo, frame: caught.append(signo))
+
+        def release():
+            time.sleep(.3)
+            os.kill(os.getpid(), signal.SIGUSR1)
+        threading.Thread(target=release).start()
+
+        # Only the calls hung at the time are released, so it's read once.
+        fd = os.open(HangTest.FILE_TO_HANG_ON, os.O_RDONLY)
+        try:
+            self.assertEqual(os.read(fd, 100), b'file contents')
+            self.assertEqual(caught, [signal.SIGUSR1])
+        finally:
+            os.close(fd)
+            signal.signal(signal.SIGUSR1, previous)
+
+    def test_interrupted(self):
+        class Deadline(Exception):
+            pass
+
+        def on_alarm(signo, frame):
+            raise Deadline()
+        signal.signal(signal.SIGALRM, on_alarm)
+        signal.setitimer(signal.ITIMER_REAL, .2)
+
+        with self.assertRaises(Deadline):
+            func_under_test(HangTest.FILE_TO_HANG_ON)
+
+
+class DnsTest(TestCase):
+    def setUp(self):
+        cleanup_env()
+        assert 'LD_PRELOAD' in os.environ
+
+    def tearDown(self):
+        cleanup_env()
+
+    def test_lookup_fails(self):
+        os.environ['LIBFAULTINJ_DNS_RULES'] = '*.example.com=EAI_AGAIN'
+
+        with self.assertRaises(socket.gaierror) as cm:
+            socket.getaddrinfo('www.example.com', 80)
+        self.assertEqual(cm.exception.errno, socket.EAI_AGAIN)
+
+    def test_lookup_substituted(self):
+        os.environ['LIBFAULTINJ_DNS_RULES'] = 'db.example.com=10.255.255.1'
+
+        self.assertEqual(socket.gethostbyname('db.example.com'), '10.255.255.1')
+
+
+class DatagramTest(TestCase):
+    def setUp(self):
+        cleanup_env()
+        assert 'LD_PRELOAD' in os.environ
+
+        self.receiver = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+        self.receiver.bind(('127.0.0.1', 0))
+        self.receiver.settimeout(1.)
+        self.sender = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
+        self.sender.bind(('127.0.0.1', 0))
+
+    def tearDown(self):
+        self.sender.close()
+        self.receiver.close()
+        cleanup_env()
+
+    def test_sendto_error(self):
+        # The destination of each datagram is matched, not the socket.
+        os.environ['LIBFAULTINJ_ERROR_ADDR'] = '*:{}'.format(self.receiver.getsockname()[1])
+        os.environ['LIBFAULTINJ_ERROR_SENDTO_ERRNO'] = str(errno.ENETUNREACH)
+
+        with self.assertRaises(OSError):
+            self.sender.sendto(b'x', self.receiver.getsockname())
+
+        self.sender.sendto(b'y', self.sender.getsockname())
+
+    def test_recvfrom_error(self):
+        os.environ['LIBFAULTINJ_ERROR_ADDR'] = '*:{}'.format(self.sender.getsockname()[1])
+        os.environ['LIBFAULTINJ_ERROR_RECVFROM_ERRNO'] = str(errno.ECONNREFUSED)
+
+        self.sender.sendto(b'x', self.receiver.getsockname())
+        with self.assertRaises(ConnectionRefusedError):
+            self.receiver.recvfrom(1)
+
+        del os.environ['LIBFAULTINJ_ERROR_RECVFROM_ERRNO']
+        self.sender.sendto(b'y', self.receiver.getsockname())
+        data, addr = self.receiver.recvfrom(1)
+        self.assertEqual(data, b'y')
+        self.assertEqual(addr, self.sender.getsockname())
+
+    def _drain(self):
+        received = []
+        try:
+            while True:
+                received.append(self.receiver.recvfrom(16)[0])
+        except socket.timeout:
+            return received
+
+    def test_datagram_loss_and_duplication(self):
+        os.environ['LIBFAULTINJ_DGRAM_ADDR'] = '*:{}'.format(self.receiver.getsockname()[1])
+
+        os.environ['LIBFAULTINJ_DGRAM_DROP_PCT'] = '100'
+        self.assertEqual(self.sender.sendto(b'lost', self.receiver.getsockname()), 4)
+        del os.environ['LIBFAULTINJ_DGRAM_DROP_PCT']
+
+        os.environ['LIBFAULTINJ_DGRAM_DUP_PCT'] = '100'
+        self.sender.sendto(b'twice', self.receiver.getsockname())
+        del os.environ['LIBFAULTINJ_DGRAM_DUP_PCT']
+
+        self.assertEqual(self._drain(), [b'twice', b'twice'])
+
+
+class QuotaTest(TestCase):
+    QUOTA_DIR = './quota'
+
+    def setUp(self):
+        cleanup_env()
+        os.mkdir(QuotaTest.QUOTA_DIR)
+        self.path = os.path.join(QuotaTest.QUOTA_DIR, 'file')
+        os.environ['LIBFAULTINJ_QUOTA_PATH'] = QuotaTest.QUOTA_DIR
+        os.environ['LIBFAULTINJ_QUOTA_BYTES'] = '10'
+
+        assert 'LD_PRELOAD' in os.environ
+
+    def tearDown(self):
+        cleanup_env()
+        for name in os.listdir(QuotaTest.QUOTA_DIR):
+            os.unlink(os.path.join(QuotaTest.QUOTA_DIR, name))
+        os.rmdir(QuotaTest.QUOTA_DIR)
+
+    def test_writes_short_then_fail(self):
+        fd = os.open(self.path, os.O_WRONLY | os.O_CREAT)
+        try:
+            self.assertEqual(os.write(fd, b'x' * 6), 6)
+            self.assertEqual(os.write(fd, b'x' * 6), 4)
+            with self.assertRaises(OSError) as cm:
+                os.write(fd, b'x')
+            self.assertEqual(cm.exception.errno, errno.ENOSPC)
+
+            # Overwriting takes no more room.
+            os.lseek(fd, 0, os.SEEK_SET)
+            self.assertEqual(os.write(fd, b'y' * 10), 10)
+            with self.assertRaises(OSError):
+                os.ftruncate(fd, 11)
+            self.assertEqual(os.fstatvfs(fd).f_bavail, 0)
+        finally:
+            os.close(fd)
+
+        os.unlink(self.path)
+        fd = os.open(self.path, os.O_WRONLY | os.O_CREAT)
+        try:
+            self.assertEqual(os.write(fd, b'z' * 10), 10)
+        finally:
+            os.close(fd)
+
+    def test_copies_and_truncates(self):
+        source = os.path.join(QuotaTest.QUOTA_DIR, 'source')
+        with open(source, 'wb') as f:
+            f.write(b'x' * 8)
+        open(self.path, 'wb').close()
+        with self.assertRaises(OSError) as cm:
+            os.truncate(self.path, 16)
+        self.assertEqual(cm.exception.errno, errno.ENOSPC)
+
+        fd_in = os.open(source, os.O_RDONLY)
+        fd_out = os.open(self.path, os.O_WRONLY | os.O_CREAT)
+        try:
+            self.assertEqual(os.copy_file_range(fd_in, fd_out, 8), 2)
+        finally:
+            os.close(fd_in)
+            os.close(fd_out)
+
+    def test_edquot(self):
+        os.environ['LIBFAULTINJ_QUOTA_BYTES'] = '0'
+        os.environ['LIBFAULTINJ_QUOTA_ERRNO'] = str(errno.EDQUOT)
+
+        fd = os.open(self.path, os.O_WRONLY | os.O_CREAT)
+        try:
+            with self.assertRaises(OSError) as cm:
+                os.write(fd, b'x')
+            self.assertEqual(cm.exception.errno, errno.EDQUOT)
+        finally:
+            os.close(fd)
+
+
+class NamespaceTest(TestCase):
+    DIR = './namespace'
+    # Replaces `target` atomically, then crashes.
+    REPLACE = '''
+import os, signal, sys
+target, sync = sys.argv[1], sys.argv[2] == 'sync'
+with open(target + '.tmp', 'w') as f:
+    f.write('new')
+os.rename(target + '.tmp', target)
+if sync:
+    fd = os.open(os.path.dirname(target), os.O_RDONLY)
+    os.fsync(fd)
+    os.close(fd)
+os.kill(os.getpid(), signal.SIGUSR1)
+'''
+
+    def setUp(self):
+        cleanup_env()
+        os.mkdir(NamespaceTest.DIR)
+        self.path = os.path.join(NamespaceTest.DIR, 'file')
+        with open(self.path, 'w') as f:
+            f.write('old')
+
+        assert 'LD_PRELOAD' in os.environ
+
+    def tearDown(self):
+        cleanup_env()
+        for name in os.listdir(NamespaceTest.DIR):
+            os.unlink(os.path.join(NamespaceTest.DIR, name))
+        os.rmdir(NamespaceTest.DIR)
+
+    def test_rename_fails(self):
+        os.environ['LIBFAULTINJ_ERROR_PATH'] = NamespaceTest.DIR
+        os.environ['LIBFAULTINJ_ERROR_RENAME_ERRNO'] = str(errno.EIO)
+        os.environ['LIBFAULTINJ_ERROR_MKDIR_ERRNO'] = str(errno.ENOSPC)
+
+        with self.assertRaises(OSError) as cm:
+            os.rename(self.path, self.path + '.new')
+        self.assertEqual(cm.exception.errno, errno.EIO)
+        with self.assertRaises(OSError) as cm:
+            os.mkdir(os.path.join(NamespaceTest.DIR, 'sub'))
+        self.assertEqual(cm.exception.errno, errno.ENOSPC)
+        self.assertEqual(os.listdir(NamespaceTest.DIR), ['file'])
+
+        # Renames out of the path are selected by their source.
+        with self.assertRaises(OSError):
+            os.rename(self.path, './elsewhere')
+
+    def _crash_replacing(self, sync):
+        env = dict(os.environ,
+                   LIBFAULTINJ_LAZY_RENAME_PATH=NamespaceTest.DIR,
+                   LIBFAULTINJ_CRASH_SIGNAL=str(int(signal.SIGUSR1)))
+        ret = subprocess.call([sys.executable, '-c', NamespaceTest.REPLACE, self.path,
+                               'sync' if sync else 'nosync'], env=env)
+        self.assertEqual(ret, -signal.SIGKILL)
+        with open(self.path) as f:
+            return f.read()
+
+    def test_rename_lost_in_crash(self):
+        self.assertEqual(self._crash_replacing(sync=False), 'old')
+        self.assertEqual(sorted(os.listdir(NamespaceTest.DIR)), ['file', 'file.tmp'])
+
+    def test_synced_rename_survives_crash(self):
+        self.assertEqual(self._crash_replacing(sync=True), 'new')
+        self.assertEqual(os.listdir(NamespaceTest.DIR), ['file'])
+
+
+class TransferTest(TestCase):
+    FILE_TO_FAIL_ON = './somefile.txt'
+
+    def setUp(self):
+        cleanup_env()
+        with open(TransferTest.FILE_TO_FAIL_ON, 'wt') as f:
+            f.write('file contents')
+        self.fd = os.open(TransferTest.FILE_TO_FAIL_ON, os.O_RDWR)
+        self.reader, self.writer = socket.socketpair()
+
+        assert 'LD_PRELOAD' in os.environ
+
+    def tearDown(self):
+        cleanup_env()
+        os.close(self.fd)
+        self.reader.close()
+        self.writer.close()
+        os.unlink(TransferTest.FILE_TO_FAIL_ON)
+
+    def test_sendfile_fails(self):
+        os.environ['LIBFAULTINJ_ERROR_PATH'] = TransferTest.FILE_TO_FAIL_ON
+        os.environ['LIBFAULTINJ_ERROR_SENDFILE_ERRNO'] = str(errno.EIO)
+        self.fd_matched = os.open(TransferTest.FILE_TO_FAIL_ON, os.O_RDONLY)
+
+        try:
+            with self.assertRaises(OSError) as cm:
+                os.sendfile(self.writer.fileno(), self.fd_matched, 0, 4)
+            self.assertEqual(cm.exception.errno, errno.EIO)
+            # Only the matched fd is selected.
+            self.assertEqual(os.sendfile(self.writer.fileno(), self.fd, 0, 4), 4)
+        finally:
+            os.close(self.fd_matched)
+
+    def test_posix_fallocate_fails(self):
+        os.environ['LIBFAULTINJ_ERROR_PATH'] = TransferTest.FILE_TO_FAIL_ON
+        os.environ['LIBFAULTINJ_ERROR_POSIX_FALLOCATE_ERRNO'] = str(errno.ENOSPC)
+        fd = os.open(TransferTest.FILE_TO_FAIL_ON, os.O_RDWR)
+
+        try:
+            with self.assertRaises(OSError) as cm:
+                os.posix_fallocate(fd, 0, 4096)
+            self.assertEqual(cm.exception.errno, errno.ENOSPC)
+        finally:
+            os.close(fd)
+
+    def test_copy_file_range_delayed(self):
+        os.environ['LIBFAULTINJ_DELAY_PATH'] = TransferTest.FILE_TO_FAIL_ON
+        os.environ['LIBFAULTINJ_DELAY_COPY_FILE_RANGE_MS'] = '300'
+        fd = os.open(TransferTest.FILE_TO_FAIL_ON, os.O_RDONLY)
+        del os.environ['LIBFAULTINJ_DELAY_PATH']
+
+        try:
+            out = os.open(TransferTest.FILE_TO_FAIL_ON + '.copy', os.O_WRONLY | os.O_CREAT)
+            start = time.time()
+            self.assertEqual(os.copy_file_range(fd, out, 4), 4)
+            self.assertGreaterEqual(time.time() - start, 0.3)
+        finally:
+            os.close(fd)
+            os.close(out)
+            os.unlink(TransferTest.FILE_TO_FAIL_ON + '.copy')
+
+
+class StatfsTest(TestCase):
+    FILE_TO_FAIL_ON = './somefile.txt'
+
+    def setUp(self):
+        cleanup_env()
+        with open(StatfsTest.FILE_TO_FAIL_ON, 'wt') as f:
+            f.write('file contents')
+        os.environ['LIBFAULTINJ_STATFS_PATH'] = StatfsTest.FILE_TO_FAIL_ON
+
+        assert 'LD_PRELOAD' in os.environ
+
+    def tearDown(self):
+        cleanup_env()
+        os.unlink(StatfsTest.FILE_TO_FAIL_ON)
+
+    def test_low_space(self):
+        os.environ['LIBFAULTINJ_STATFS_BAVAIL'] = '3'
+        os.environ['LIBFAULTINJ_STATFS_FILES'] = '5'
+
+        stats = os.statvfs(StatfsTest.FILE_TO_FAIL_ON)
+        self.assertEqual((stats.f_bavail, stats.f_bfree), (3, 3))
+        self.assertEqual(stats.f_files, 5)
+        self.assertLessEqual(stats.f_favail, 5)
+        self.assertFalse(stats.f_flag & os.ST_RDONLY)
+        self.assertNotEqual(os.statvfs('.').f_bavail, 3)
+
+    def test_remounted_read_only(self):
+        with open(StatfsTest.FILE_TO_FAIL_ON, 'a') as f:
+            os.environ['LIBFAULTINJ_STATFS_RDONLY'] = 'erofs'
+            self.assertTrue(os.fstatvfs(f.fileno()).f_flag & os.ST_RDONLY)
+            with self.assertRaises(OSError) as cm:
+                os.write(f.fileno(), b'more')
+            self.assertEqual(cm.exception.errno, errno.EROFS)
+
+        with self.assertRaises(OSError) as cm:
+            open(StatfsTest.FILE_TO_FAIL_ON, 'w')
+        self.assertEqual(cm.exception.errno, errno.EROFS)
+        with self.assertRaises(OSError) as cm:
+            os.unlink(StatfsTest.FILE_TO_FAIL_ON)
+        self.assertEqual(cm.exception.errno, errno.EROFS)
+        self.assertEqual(func_under_test(StatfsTest.FILE_TO_FAIL_ON), 'file contents')
+
+
+class AllocTest(TestCase):
+    BIG = 1 << 28
+    ALLOCATE = """
+import errno, mmap
+try:
+    bytearray(1 << 28)
+    print('allocated')
+except MemoryError:
+    print('MemoryError')
+try:
+    mmap.mmap(-1, 1 << 28)
+    print('mapped')
+except OSError as e:
+    print(errno.errorcode[e.errno])
+print(len(bytearray(1024)))
+"""
+
+    def setUp(self):
+        cleanup_env()
+
+        assert 'LD_PRELOAD' in os.environ
+
+    def tearDown(self):
+        cleanup_env()
+
+    # The triggers are read once, so each test needs a process of its own.
+    def _allocate(self, **env):
+        env = dict(os.environ, LIBFAULTINJ_ALLOC_MIN_BYTES=str(AllocTest.BIG), **env)
+        return subprocess.run([sys.executable, '-c', AllocTest.ALLOCATE], env=env,
+                              stdout=subprocess.PIPE).stdout.split()
+
+    def test_big_allocations_fail(self):
+        self.assertEqual(self._allocate(), [b'MemoryError', b'ENOMEM', b'1024'])
+
+    def test_other_callers_unaffected(self):
+        self.assertEqual(self._allocate(LIBFAULTINJ_ALLOC_CALLER='libnothing.so'),
+                         [b'allocated', b'mapped', b'1024'])
+
+
+class FdLimitTest(TestCase):
+    FILE_TO_OPEN = './somefile.txt'
+
+    def setUp(self):
+        cleanup_env()
+        with open(FdLimitTest.FILE_TO_OPEN, 'wt') as f:
+            f.write('file contents')
+        self.fds = []
+
+        assert 'LD_PRELOAD' in os.environ
+
+    def tearDown(self):
+        cleanup_env()
+        for fd in self.fds:
+            os.close(fd)
+        os.unlink(FdLimitTest.FILE_TO_OPEN)
+
+    def limit_to(self, more):
+        held = len(os.listdir('/proc/self/fd')) - 1
+        os.environ['LIBFAULTINJ_FD_LIMIT'] = str(held + more)
+
+    def test_emfile(self):
+        self.limit_to(2)
+        self.fds.append(os.open(FdLimitTest.FILE_TO_OPEN, os.O_RDONLY))
+
+        # Both ends of a pipe need room.
+        with self.assertRaises(OSError) as cm:
+            os.pipe()
+        self.assertEqual(cm.exception.errno, errno.EMFILE)
+        self.fds.append(os.open(FdLimitTest.FILE_TO_OPEN, os.O_RDONLY))
+        with self.assertRaises(OSError) as cm:
+            socket.socket()
+        self.assertEqual(cm.exception.errno, errno.EMFILE)
+
+        # Closing one makes room again.
+        os.close(self.fds.pop())
+        self.fds.append(os.open(FdLimitTest.FILE_TO_OPEN, os.O_RDONLY))
+
+    def test_enfile(self):
+        self.limit_to(0)
+        os.environ['LIBFAULTINJ_FD_LIMIT_ERRNO'] = str(errno.ENFILE)
+
+        with self.assertRaises(OSError) as cm:
+            open(FdLimitTest.FILE_TO_OPEN)
+        self.assertEqual(cm.exception.errno, errno.ENFILE)
+
+
+class SocketKindTest(TestCase):
+    def setUp(self):
+        cleanup_env()
+
+        assert 'LD_PRELOAD' in os.environ
+
+    def tearDown(self):
+        cleanup_env()
+
+    def test_socket_fails(self):
+        os.environ['LIBFAULTINJ_ERROR_SOCKET_KIND'] = 'inet:dgram,AF_INET6'
+        os.environ['LIBFAULTINJ_ERROR_SOCKET_ERRNO'] = str(errno.EAFNOSUPPORT)
+
+        for family, kind in [(socket.AF_INET, socket.SOCK_DGRAM),
+                             (socket.AF_INET6, socket.SOCK_STREAM)]:
+            with self.assertRaises(OSError) as cm:
+                socket.socket(family, kind)
+            self.assertEqual(cm.exception.errno, errno.EAFNOSUPPORT)
+        socket.socket(socket.AF_INET, socket.SOCK_STREAM).close()
+
+    def test_selected_before_connect(self):
+        os.environ['LIBFAULTINJ_ERROR_SOCKET_KIND'] = 'inet:stream:tcp'
+        os.environ['LIBFAULTINJ_ERROR_SEND_ERRNO'] = str(errno.EIO)
+
+        with socket.socket(socket.AF_INET, socket.SOCK_STREAM) as s:
+            with self.assertRaises(OSError) as cm:
+                s.send(b'data')
+            self.assertEqual(cm.exception.errno, errno.EIO)
+        with socket.socket(socket.AF_INET, socket.SOCK_DGRAM) as s:
+            with self.assertRaises(OSError) as cm:
+                s.send(b'data')
+            self.assertNotEqual(cm.exception.errno, errno.EIO)
+
+
+class SockoptTest(TestCase):
+    def setUp(self):
+        cleanup_env()
+        assert 'LD_PRELOAD' in os.environ
+
+        self.listener = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+        self.listener.bind(('127.0.0.1', 0))
+        self.listener.listen(1)
+        os.environ['LIBFAULTINJ_ERROR_ADDR'] = '*:{}'.format(self.listener.getsockname()[1])
+        self.client = socket.create_connection(self.listener.getsockname())
+        self.server, _ = self.listener.accept()
+
+    def tearDown(self):
+        cleanup_env()
+        self.client.close()
+        self.server.close()
+        self.listener.close()
+
+    def test_setsockopt_fails(self):
+        os.environ['LIBFAULTINJ_ERROR_SETSOCKOPT_ERRNO'] = str(errno.ENOPROTOOPT)
+        os.environ['LIBFAULTINJ_SOCKOPT_NAMES'] = 'TCP_NODELAY'
+
+        with self.assertRaises(OSError) as cm:
+            self.client.setsockopt(socket.IPPROTO_TCP, socket.TCP_NODELAY, 1)
+        self.assertEqual(cm.exception.errno, errno.ENOPROTOOPT)
+        self.client.setsockopt(socket.SOL_SOCKET, socket.SO_KEEPALIVE, 1)
+        self.server.setsockopt(socket.IPPROTO_TCP, socket.TCP_NODELAY, 1)
+
+    def test_getsockopt_lies(self):
+        os.environ['LIBFAULTINJ_SOCKOPT_VALUES'] = 'SO_SNDBUF=4096,SO_ERROR={}'.format(
+            errno.ECONNRESET)
+
+        self.assertEqual(self.client.getsockopt(socket.SOL_SOCKET, socket.SO_SNDBUF), 4096)
+        self.assertEqual(self.client.getsockopt(socket.SOL_SOCKET, socket.SO_ERROR),
+                         errno.ECONNRESET)
+        self.assertNotEqual(self.server.getsockopt(socket.SOL_SOCKET, socket.SO_SNDBUF), 4096)
+        self.assertEqual(self.server.getsockopt(socket.SOL_SOCKET, socket.SO_ERROR), 0)
+
+    def test_getsockopt_leaves_structs_alone(self):
+        os.environ['LIBFAULTINJ_SOCKOPT_VALUES'] = 'SO_LINGER=7,{}:{}=7'.format(
+            socket.SOL_SOCKET, socket.SO_RCVTIMEO)
+
+        self.assertEqual(self.client.getsockopt(socket.SOL_SOCKET, socket.SO_LINGER, 8),
+                         b'\0' * 8)
+        self.assertEqual(self.client.getsockopt(socket.SOL_SOCKET, socket.SO_RCVTIMEO, 16),
+                         b'\0' * 16)
+
+    def test_shutdown_and_getpeername_fail(self):
+        os.environ['LIBFAULTINJ_ERROR_SHUTDOWN_ERRNO'] = str(errno.ENOTCONN)
+        os.environ['LIBFAULTINJ_ERROR_GETPEERNAME_ERRNO'] = str(errno.ENOTCONN)
+
+        with self.assertRaises(OSError) as cm:
+            self.client.shutdown(socket.SHUT_WR)
+        self.assertEqual(cm.exception.errno, errno.ENOTCONN)
+        with self.assertRaises(OSError) as cm:
+            self.client.getpeername()
+        self.assertEqual(cm.exception.errno, errno.ENOTCONN)
+        self.assertEqual(self.server.getpeername(), self.client.getsockname())
+
+
+class FdKindTest(TestCase):
+    FIFO = './somefifo'
+
+    def setUp(self):
+        cleanup_env()
+
+        assert 'LD_PRELOAD' in os.environ
+
+    def tearDown(self):
+        cleanup_env()
+        if os.path.exists(FdKindTest.FIFO):
+            os.unlink(FdKindTest.FIFO)
+
+    def test_pipe_epipe(self):
+        os.environ['LIBFAULTINJ_ERROR_FD_KIND'] = 'kind=pipe'
+        os.environ['LIBFAULTINJ_ERROR_WRITE_ERRNO'] = str(errno.EPIPE)
+        r, w = os.pipe()
+        a, b = socket.socketpair()
+
+        try:
+            with self.assertRaises(BrokenPipeError):
+                os.write(w, b'data')
+            self.assertEqual(os.write(a.fileno(), b'data'), 4)
+        finally:
+            for fd in (r, w):
+                os.close(fd)
+            a.close()
+            b.close()
+
+    def test_short_pipe_writes(self):
+        os.environ['LIBFAULTINJ_DELAY_FD_KIND'] = 'pipe,eventfd'
+        os.environ['LIBFAULTINJ_THROTTLE_BPS'] = '40'
+        os.environ['LIBFAULTINJ_THROTTLE_MODE'] = 'short'
+        r, w = os.pipe()
+        efd = os.eventfd(0)
+
+        try:
+            self.assertLess(os.write(w, b'x' * 100), 100)
+            # An eventfd's counter can't be written in part.
+            os.eventfd_write(efd, 3)
+            self.assertEqual(os.eventfd_read(efd), 3)
+        finally:
+            for fd in (r, w, efd):
+                os.close(fd)
+
+    def test_socketpair_delayed(self):
+        os.environ['LIBFAULTINJ_DELAY_FD_KIND'] = 'socketpair'
+        os.environ['LIBFAULTINJ_DELAY_SEND_MS'] = '300'
+        a, b = socket.socketpair()
+
+        try:
+            start = time.time()
+            b.send(b'data')
+            self.assertGreaterEqual(time.time() - start, 0.3)
+            self.assertEqual(a.recv(4), b'data')
+        finally:
+            a.close()
+            b.close()
+
+    def test_fifo_read_fails(self):
+        os.mkfifo(FdKindTest.FIFO)
+        os.environ['LIBFAULTINJ_ERROR_FD_KIND'] = 'fifo'
+        os.environ['LIBFAULTINJ_ERROR_READ_ERRNO'] = str(errno.EIO)
+        fd = os.open(FdKindTest.FIFO, os.O_RDWR)
+
+        try:
+            os.write(fd, b'data')
+            with self.assertRaises(OSError) as cm:
+                os.read(fd, 4)
+            self.assertEqual(cm.exception.errno, errno.EIO)
+        finally:
+            os.close(fd)
+
+
+class IoctlTest(TestCase):
+    FILE_TO_FAIL_ON = './somefile.txt'
+
+    def setUp(self):
+        cleanup_env()
+        with open(IoctlTest.FILE_TO_FAIL_ON, 'wt') as f:
+            f.write('file contents')
+        os.environ['LIBFAULTINJ_ERROR_PATH'] = IoctlTest.FILE_TO_FAIL_ON
+        self.fd = os.open(IoctlTest.FILE_TO_FAIL_ON, os.O_RDONLY)
+
+        assert 'LD_PRELOAD' in os.environ
+
+    def tearDown(self):
+        cleanup_env()
+        os.close(self.fd)
+        os.unlink(IoctlTest.FILE_TO_FAIL_ON)
+
+    def test_selected_requests_fail(self):
+        os.environ['LIBFAULTINJ_ERROR_IOCTL_ERRNO'] = str(errno.EIO)
+        os.environ['LIBFAULTINJ_IOCTL_REQUESTS'] = 'FIONREAD'
+
+        with self.assertRaises(OSError) as cm:
+            fcntl.ioctl(self.fd, termios.FIONREAD, b'\0' * 4)
+        self.assertEqual(cm.exception.errno, errno.EIO)
+        fcntl.ioctl(self.fd, termios.FIOCLEX)
+
+    def test_faked_values(self):
+        os.environ['LIBFAULTINJ_IOCTL_VALUES'] = 'FIONREAD=7,TIOCGWINSZ=24x80'
+
+        fionread = fcntl.ioctl(self.fd, termios.FIONREAD, b'\0' * 4)
+        self.assertEqual(struct.unpack('i', fionread)[0], 7)
+        winsize = fcntl.ioctl(self.fd, termios.TIOCGWINSZ, b'\0' * 8)
+        self.assertEqual(struct.unpack('HHHH', winsize)[:2], (24, 80))
+
+
+class InheritedFdTest(TestCase):
+    WRITE = 'import os; os.write(1, b"x")'
+    FILE_TO_FAIL_ON = './somefile.txt'
+
+    def setUp(self):
+        cleanup_env()
+
+        assert 'LD_PRELOAD' in os.environ
+
+    def tearDown(self):
+        cleanup_env()
+        if os.path.exists(InheritedFdTest.FILE_TO_FAIL_ON):
+            os.unlink(InheritedFdTest.FILE_TO_FAIL_ON)
+
+    def _write_to_stdout(self, stdout, **env):
+        env = dict(os.environ, LIBFAULTINJ_ERROR_WRITE_ERRNO=str(errno.EPIPE), **env)
+        return subprocess.run([sys.executable, '-c', InheritedFdTest.WRITE], env=env,
+                              stdout=stdout, stderr=subprocess.PIPE)
+
+    def test_stdout_by_number(self):
+        ret = self._write_to_stdout(subprocess.PIPE, LIBFAULTINJ_ERROR_FD='stdout')
+        self.assertNotEqual(ret.returncode, 0)
+        self.assertIn(b'BrokenPipeError', ret.stderr)
+        self.assertEqual(ret.stdout, b'')
+
+        ret = self._write_to_stdout(subprocess.PIPE, LIBFAULTINJ_ERROR_FD='stdin,2')
+        self.assertEqual(ret.returncode, 0)
+        self.assertEqual(ret.stdout, b'x')
+
+    def test_stdout_by_path(self):
+        with open(InheritedFdTest.FILE_TO_FAIL_ON, 'wb') as f:
+            ret = self._write_to_stdout(f, LIBFAULTINJ_ERROR_PATH=InheritedFdTest.FILE_TO_FAIL_ON)
+        self.assertIn(b'BrokenPipeError', ret.stderr)
+        self.assertEqual(os.path.getsize(InheritedFdTest.FILE_TO_FAIL_ON), 0)
@@ -0,0 +1,414 @@
+extern crate libc;
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::net::ToSocketAddrs;
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::{cmp, mem, ptr};
+
+use dns;
+
+/// The host portion of an address selector.
+#[derive(Debug, PartialEq)]
+pub enum HostSelector {
+    /// `*` -- any host.
+    Any,
+    /// An address with a prefix length, `10.0.0.0/8` or `::1` (== `::1/128`).
+    Net(IpAddr, u8),
+    /// A hostname, with the addresses it resolved to.  Only
+    ///   `resolved_selectors()` resolves it; as parsed, it matches nothing.
+    Name(String, Vec<IpAddr>),
+}
+
+/**
+ * A single socket address selector, as found in `LIBFAULTINJ_ERROR_ADDR`
+ * and `LIBFAULTINJ_DELAY_ADDR`.  Accepted forms:
+ *
+ * ```text
+ * 127.0.0.1           10.0.0.0/8          10.0.0.0/8:80
+ * ::1                 [::1]:6379          [fe80::/10]
+ * *:5432              db.example.com      localhost:6379
+ * ```
+ *
+ * A selector without a port matches any port.
+ */
+#[derive(Debug, PartialEq)]
+pub struct AddrSelector {
+    pub host: HostSelector,
+    pub port: Option<u16>,
+}
+
+fn parse_net(s: &str) -> Option<(IpAddr, u8)> {
+    let (ip_str, prefix_str) = match s.find('/') {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+
+    let ip = IpAddr::from_str(ip_str).ok()?;
+    let max_prefix = match ip {
+        IpAddr::V4(_) => 32,
+        IpAddr::V6(_) => 128,
+    };
+
+    match prefix_str {
+        Some(p) => {
+            match p.parse::<u8>() {
+                Ok(prefix) if prefix <= max_prefix => Some((ip, prefix)),
+                _ => None,
+            }
+        }
+        None => Some((ip, max_prefix)),
+    }
+}
+
+fn parse_host(s: &str) -> Option<HostSelector> {
+    if s == "*" {
+        return Some(HostSelector::Any);
+    }
+
+    if let Some((ip, prefix)) = parse_net(s) {
+        return Some(HostSelector::Net(ip, prefix));
+    }
+
+    let valid_name = !s.is_empty() &&
+                     s.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '.' || c == '_');
+    if valid_name {
+        Some(HostSelector::Name(s.to_string(), Vec::new()))
+    } else {
+        None
+    }
+}
+
+impl AddrSelector {
+    pub fn parse(selector: &str) -> Option<AddrSelector> {
+        let selector = selector.trim();
+
+        // "[v6addr]" or "[v6addr/prefix]", optionally followed by ":port"
+        if selector.starts_with('[') {
+            let end = selector.find(']')?;
+            let port = match &selector[end + 1..] {
+                "" => None,
+                rest if rest.starts_with(':') => {
+                    match rest[1..].parse::<u16>() {
+                        Ok(port) => Some(port),
+                        Err(_) => return None,
+                    }
+                }
+                _ => return None,
+            };
+            return match parse_net(&selector[1..end]) {
+                Some((ip @ IpAddr::V6(_), prefix)) => {
+                    Some(AddrSelector {
+                        host: HostSelector::Net(ip, prefix),
+                        port,
+                    })
+                }
+                _ => None,
+            };
+        }
+
+        // Bare IPv6 addresses contain more than one colon and can't carry a port.
+        if selector.matches(':').count() > 1 {
+            return parse_net(selector).map(|(ip, prefix)| {
+                AddrSelector {
+                    host: HostSelector::Net(ip, prefix),
+                    port: None,
+                }
+            });
+        }
+
+        let (host_str, port) = match selector.rfind(':') {
+            Some(i) => {
+                match selector[i + 1..].parse::<u16>() {
+                    Ok(port) => (&selector[..i], Some(port)),
+                    Err(_) => return None,
+                }
+            }
+            None => (selector, None),
+        };
+
+        parse_host(host_str).map(|host| {
+            AddrSelector {
+                host,
+                port,
+            }
+        })
+    }
+
+    pub fn matches(&self, addr: &SocketAddr) -> bool {
+        if let Some(port) = self.port {
+            if port != addr.port() {
+                return false;
+            }
+        }
+
+        let ip = unmap_ip(addr.ip());
+        match self.host {
+            HostSelector::Any => true,
+            HostSelector::Net(net, prefix) => ip_in_net(&ip, &unmap_ip(net), prefix),
+            HostSelector::Name(_, ref resolved) => resolved.contains(&ip),
+        }
+    }
+}
+
+/**
+ * @return every selector that could be parsed from the comma-separated
+ *      list `selectors`.  Unparseable entries are ignored.
+ */
+pub fn parse_selectors(selectors: &str) -> Vec<AddrSelector> {
+    selectors.split(',')
+             .filter(|s| !s.trim().is_empty())
+             .filter_map(AddrSelector::parse)
+             .collect()
+}
+
+lazy_static! {
+    // Selectors by the env value they were parsed from, so each name is
+    //   looked up once rather than on every connect() and sendto().
+    static ref RESOLVED: Mutex<HashMap<String, Arc<Vec<AddrSelector>>>>
+            = Mutex::new(HashMap::new());
+}
+
+fn resolve(name: &str) -> Vec<IpAddr> {
+    match dns::resolve_internally(|| (name, 0).to_socket_addrs()) {
+        Ok(resolved) => resolved.map(|a| unmap_ip(a.ip())).collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+/**
+ * @return the selectors parsed from `selectors`, with their hostnames
+ *      resolved the first time that list is seen.
+ */
+pub fn resolved_selectors(selectors: &str) -> Arc<Vec<AddrSelector>> {
+    if let Some(parsed) = RESOLVED.lock().unwrap().get(selectors) {
+        return parsed.clone();
+    }
+
+    // The lock isn't held across lookups, which may come back through the
+    //   hooks.  Those see the names unresolved, and don't cache them.
+    let mut parsed = parse_selectors(selectors);
+    if dns::is_resolving_internally() {
+        return Arc::new(parsed);
+    }
+    for selector in &mut parsed {
+        if let HostSelector::Name(ref name, ref mut resolved) = selector.host {
+            *resolved = resolve(name);
+        }
+    }
+    RESOLVED.lock()
+            .unwrap()
+            .entry(selectors.to_string())
+            .or_insert_with(|| Arc::new(parsed))
+            .clone()
+}
+
+// An IPv4-mapped IPv6 address (`::ffff:10.0.0.1`) is matched as IPv4 so that
+//   dual-stack sockets are selected by the same rules as AF_INET ones.
+fn unmap_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => {
+            let s = v6.segments();
+            if s[0..5] == [0, 0, 0, 0, 0] && s[5] == 0xffff {
+                IpAddr::V4(Ipv4Addr::new((s[6] >> 8) as u8,
+                                         s[6] as u8,
+                                         (s[7] >> 8) as u8,
+                                         s[7] as u8))
+            } else {
+                IpAddr::V6(v6)
+            }
+        }
+        v4 => v4,
+    }
+}
+
+fn ip_in_net(ip: &IpAddr, net: &IpAddr, prefix: u8) -> bool {
+    match (*ip, *net) {
+        (IpAddr::V4(ip), IpAddr::V4(net)) => {
+            let mask = if prefix == 0 {
+                0
+            } else {
+                !0u32 << (32 - prefix as u32)
+            };
+            (u32::from(ip) & mask) == (u32::from(net) & mask)
+        }
+        (IpAddr::V6(ip), IpAddr::V6(net)) => {
+            let mask = if prefix == 0 {
+                0
+            } else {
+                !0u128 << (128 - prefix as u32)
+            };
+            (u128::from(ip) & mask) == (u128::from(net) & mask)
+        }
+        _ => false,
+    }
+}
+
+/**
+ * @return the address stored in `addr`, converted from network byte order,
+ *      or None for families other than AF_INET and AF_INET6.
+ *
+ * `addr` must be null or point to a structure that's valid for the family
+ * stored in its `sa_family` field.
+ */
+pub unsafe fn sockaddr_to_socket_addr(addr: *const libc::sockaddr) -> Option<SocketAddr> {
+    if addr.is_null() {
+        return None;
+    }
+
+    match (*addr).sa_family as libc::c_int {
+        libc::AF_INET => {
+            let sin = &*(addr as *const libc::sockaddr_in);
+            let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(sin.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let sin6 = &*(addr as *const libc::sockaddr_in6);
+            let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+
+            Some(SocketAddr::V6(SocketAddrV6::new(ip,
+                                                  u16::from_be(sin6.sin6_port),
+                                                  sin6.sin6_flowinfo,
+                                                  sin6.sin6_scope_id)))
+        }
+        _ => None,
+    }
+}
+
+/**
+ * Storage for the source address of a received datagram.  Hooks receive
+ * into this instead of the caller's buffer, which might be null or too
+ * short, so that the source can always be matched.  `copy_out()` then
+ * hands it to the caller like the kernel would have.
+ */
+pub struct PeerAddrBuf {
+    storage: libc::sockaddr_storage,
+    pub len: libc::socklen_t,
+}
+
+impl Default for PeerAddrBuf {
+    fn default() -> PeerAddrBuf {
+        PeerAddrBuf {
+            storage: unsafe { mem::zeroed() },
+            len: mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t,
+        }
+    }
+}
+
+impl PeerAddrBuf {
+    pub fn as_mut_ptr(&mut self) -> *mut libc::sockaddr {
+        &mut self.storage as *mut libc::sockaddr_storage as *mut libc::sockaddr
+    }
+
+    pub fn from_raw(raw: &[u8]) -> PeerAddrBuf {
+        let mut peer = PeerAddrBuf::default();
+        let len = cmp::min(raw.len(), mem::size_of::<libc::sockaddr_storage>());
+
+        unsafe { ptr::copy_nonoverlapping(raw.as_ptr(), peer.as_mut_ptr() as *mut u8, len) };
+        peer.len = len as libc::socklen_t;
+        peer
+    }
+
+    pub fn to_raw(&self) -> Vec<u8> {
+        let len = cmp::min(self.len as usize, mem::size_of::<libc::sockaddr_storage>());
+        let ptr = &self.storage as *const libc::sockaddr_storage as *const u8;
+
+        unsafe { ::std::slice::from_raw_parts(ptr, len).to_vec() }
+    }
+
+    pub fn socket_addr(&self) -> Option<SocketAddr> {
+        if self.len as usize > mem::size_of::<libc::sockaddr_storage>() {
+            return None;
+        }
+        let ptr = &self.storage as *const libc::sockaddr_storage as *const libc::sockaddr;
+
+        unsafe { sockaddr_to_socket_addr(ptr) }
+    }
+
+    /**
+     * Copies the address to `addr`, truncated to `*addrlen` bytes, and
+     * stores the full length in `*addrlen`.  Does nothing if either
+     * pointer is null.
+     */
+    pub unsafe fn copy_out(&self, addr: *mut libc::sockaddr, addrlen: *mut libc::socklen_t) {
+        if addr.is_null() || addrlen.is_null() {
+            return;
+        }
+
+        let len = cmp::min(cmp::min(*addrlen, self.len) as usize,
+                           mem::size_of::<libc::sockaddr_storage>());
+        ptr::copy_nonoverlapping(&self.storage as *const libc::sockaddr_storage as *const u8,
+                                 addr as *mut u8,
+                                 len);
+        *addrlen = self.len;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, SocketAddr};
+    use std::str::FromStr;
+    use super::{AddrSelector, HostSelector, parse_selectors};
+
+    fn sock(s: &str) -> SocketAddr {
+        SocketAddr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn test_parse_selector() {
+        assert_eq!(AddrSelector::parse("*:5432"),
+                   Some(AddrSelector {
+                       host: HostSelector::Any,
+                       port: Some(5432),
+                   }));
+        assert_eq!(AddrSelector::parse("[::1]:6379"),
+                   Some(AddrSelector {
+                       host: HostSelector::Net(IpAddr::from_str("::1").unwrap(), 128),
+                       port: Some(6379),
+                   }));
+        assert_eq!(AddrSelector::parse("10.0.0.0/8"),
+                   Some(AddrSelector {
+                       host: HostSelector::Net(IpAddr::from_str("10.0.0.0").unwrap(), 8),
+                       port: None,
+                   }));
+        assert_eq!(AddrSelector::parse("db.example.com:5432"),
+                   Some(AddrSelector {
+                       host: HostSelector::Name("db.example.com".to_string(), Vec::new()),
+                       port: Some(5432),
+                   }));
+
+        assert_eq!(AddrSelector::parse("10.0.0.0/33"), None);
+        assert_eq!(AddrSelector::parse("[10.0.0.1]:80"), None);
+        assert_eq!(AddrSelector::parse("*:bogus"), None);
+        assert_eq!(AddrSelector::parse("./Cargo.toml"), None);
+    }
+
+    #[test]
+    fn test_match_selectors() {
+        let postgres_only = parse_selectors("*:5432");
+        assert!(postgres_only.iter().any(|s| s.matches(&sock("127.0.0.1:5432"))));
+        assert!(!postgres_only.iter().any(|s| s.matches(&sock("127.0.0.1:6379"))));
+
+        let nets = parse_selectors("10.0.0.0/8, [::1]:6379,bogus:entry:");
+        assert_eq!(nets.len(), 2);
+        assert!(nets.iter().any(|s| s.matches(&sock("10.1.2.3:80"))));
+        assert!(nets.iter().any(|s| s.matches(&sock("[::ffff:10.1.2.3]:80"))));
+        assert!(nets.iter().any(|s| s.matches(&sock("[::1]:6379"))));
+        assert!(!nets.iter().any(|s| s.matches(&sock("[::1]:5432"))));
+        assert!(!nets.iter().any(|s| s.matches(&sock("11.0.0.1:80"))));
+
+        // Names only match what they resolved to.
+        let mut named = parse_selectors("db.example.com:5432");
+        assert!(!named[0].matches(&sock("10.1.2.3:5432")));
+        named[0].host = HostSelector::Name("db.example.com".to_string(),
+                                           vec![IpAddr::from_str("10.1.2.3").unwrap()]);
+        assert!(named[0].matches(&sock("[::ffff:10.1.2.3]:5432")));
+        assert!(!named[0].matches(&sock("10.1.2.4:5432")));
+
+        let everything = parse_selectors("0.0.0.0/0,::/0");
+        assert!(everything.iter().any(|s| s.matches(&sock("192.168.1.1:1"))));
+        assert!(everything.iter().any(|s| s.matches(&sock("[fe80::1]:1"))));
+    }
+}
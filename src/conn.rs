@@ -0,0 +1,590 @@
+extern crate libc;
+
+// Connections that die part way through a stream.  Once a stream socket in
+//   ERR_FDS has moved LIBFAULTINJ_RESET_AFTER_BYTES bytes or made
+//   LIBFAULTINJ_RESET_AFTER_CALLS calls, it's reset by its peer or, with
+//   LIBFAULTINJ_RESET_MODE=eof, the peer closes its end.
+//
+// Connects to an address in LIBFAULTINJ_ERROR_ADDR can also be made to hang,
+//   finish late or fail late, per LIBFAULTINJ_CONNECT_MODE.  Non-blocking
+//   sockets see this through the readiness hooks and SO_ERROR.
+
+use std::collections::HashMap;
+use std::{cmp, slice};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+
+use libc::{c_int, c_void, ssize_t};
+use errors::{SomeHashState, ERR_FDS, get_env_value, get_socket_type, is_nonblocking};
+use ready;
+use latency::Selector;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Direction {
+    Send,
+    Recv,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Phase {
+    Alive,
+    /// The peer sent a RST that the application hasn't seen yet.
+    ResetPending,
+    /// The RST has been reported; sends now fail with EPIPE.
+    Reset,
+    /// The peer shut down its end: receives see EOF, sends still work.
+    Eof,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct ConnState {
+    stream: bool,
+    bytes: u64,
+    calls: u64,
+    pub phase: Phase,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Limits {
+    bytes: Option<u64>,
+    calls: Option<u64>,
+    eof: bool,
+}
+
+/**
+ * Evaluates to the number of bytes that an I/O call on $fd may move, or
+ *   returns from the hook if the connection has died.
+ */
+macro_rules! checkConnection(
+    ($fd: expr, $dir: expr, $len: expr, $flags: expr) =>
+    ({
+        match conn::before_io($fd, $dir, $len, $flags) {
+            conn::Outcome::Proceed(len) => len,
+            conn::Outcome::Return(ret, _) => return ret,
+        }
+    }));
+
+// Spares the I/O hooks a lock when no connection is being tracked.
+static TRACKING: AtomicBool = AtomicBool::new(false);
+static CONNECTING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref CONNS: Mutex<HashMap<c_int, ConnState, SomeHashState>>
+            = Mutex::new(HashMap::with_hasher(SomeHashState::default()));
+    static ref PENDING: Mutex<HashMap<c_int, PendingConnect, SomeHashState>>
+            = Mutex::new(HashMap::with_hasher(SomeHashState::default()));
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectMode {
+    /// Never connects.
+    Hang,
+    /// Connects, but not until LIBFAULTINJ_DELAY_CONNECT_MS has passed.
+    Delay,
+    /// Fails with this errno after LIBFAULTINJ_DELAY_CONNECT_MS.
+    Fail(c_int),
+}
+
+/// A non-blocking connect that hasn't been seen to finish yet.
+#[derive(Debug)]
+struct PendingConnect {
+    mode: ConnectMode,
+    /// None when it never finishes.
+    done_at: Option<Instant>,
+}
+
+impl ConnectMode {
+    pub fn parse(mode: &str) -> Option<ConnectMode> {
+        match mode.trim().to_lowercase().as_str() {
+            "hang" => Some(ConnectMode::Hang),
+            "delay" => Some(ConnectMode::Delay),
+            "refuse" => Some(ConnectMode::Fail(libc::ECONNREFUSED)),
+            "timeout" => Some(ConnectMode::Fail(libc::ETIMEDOUT)),
+            _ => None,
+        }
+    }
+
+    pub fn from_env() -> Option<ConnectMode> {
+        match ::std::env::var("LIBFAULTINJ_CONNECT_MODE") {
+            Ok(mode) => ConnectMode::parse(&mode),
+            Err(_) => None,
+        }
+    }
+}
+
+impl Limits {
+    pub fn from_env() -> Option<Limits> {
+        let bytes = get_env_value::<u64>("LIBFAULTINJ_RESET_AFTER_BYTES");
+        let calls = get_env_value::<u64>("LIBFAULTINJ_RESET_AFTER_CALLS");
+        if bytes.is_none() && calls.is_none() {
+            return None;
+        }
+
+        let eof = match ::std::env::var("LIBFAULTINJ_RESET_MODE") {
+            Ok(mode) => mode.trim().eq_ignore_ascii_case("eof"),
+            Err(_) => false,
+        };
+
+        Some(Limits { bytes, calls, eof })
+    }
+}
+
+/// The result of checking a connection before an I/O call.
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// Go ahead, moving at most this many bytes.
+    Proceed(usize),
+    /// Return this instead, errno is `.1` (or 0 for none).
+    Return(ssize_t, c_int),
+}
+
+impl ConnState {
+    pub fn new(stream: bool) -> ConnState {
+        ConnState {
+            stream,
+            bytes: 0,
+            calls: 0,
+            phase: Phase::Alive,
+        }
+    }
+
+    /**
+     * Advances the state machine for an I/O call of `len` bytes.
+     */
+    pub fn before_io(&mut self, limits: &Limits, dir: Direction, len: usize) -> Outcome {
+        if !self.stream {
+            return Outcome::Proceed(len);
+        }
+
+        if self.phase == Phase::Alive {
+            let calls_exhausted = limits.calls.is_some_and(|calls| self.calls >= calls);
+            let bytes_exhausted = limits.bytes.is_some_and(|bytes| self.bytes >= bytes);
+
+            if calls_exhausted || bytes_exhausted {
+                self.phase = if limits.eof {
+                    Phase::Eof
+                } else {
+                    Phase::ResetPending
+                };
+            }
+        }
+
+        match (self.phase, dir) {
+            (Phase::Alive, _) => {
+                // Stop exactly at the byte limit, as a real reset would.
+                match limits.bytes {
+                    Some(bytes) => {
+                        Outcome::Proceed(::std::cmp::min(len as u64, bytes - self.bytes) as usize)
+                    }
+                    None => Outcome::Proceed(len),
+                }
+            }
+            (Phase::ResetPending, _) => {
+                self.phase = Phase::Reset;
+                Outcome::Return(-1, libc::ECONNRESET)
+            }
+            (Phase::Reset, Direction::Send) => Outcome::Return(-1, libc::EPIPE),
+            (Phase::Reset, Direction::Recv) |
+            (Phase::Eof, Direction::Recv) => Outcome::Return(0, 0),
+            (Phase::Eof, Direction::Send) => Outcome::Proceed(len),
+        }
+    }
+
+    pub fn after_io(&mut self, ret: ssize_t) {
+        self.calls += 1;
+        if ret > 0 {
+            self.bytes += ret as u64;
+        }
+    }
+}
+
+/**
+ * Checks the connection on `fd` before an I/O call of `len` bytes.  On
+ * Outcome::Return, errno has been set and, for an EPIPE on a send without
+ * MSG_NOSIGNAL, SIGPIPE has been raised.
+ */
+pub fn before_io(fd: c_int, dir: Direction, len: usize, flags: c_int) -> Outcome {
+    use errno::{Errno, set_errno};
+
+    let limits = match Limits::from_env() {
+        Some(limits) => limits,
+        None => return Outcome::Proceed(len),
+    };
+    if !ERR_FDS.read().unwrap().contains(&fd) {
+        return Outcome::Proceed(len);
+    }
+
+    TRACKING.store(true, Ordering::Relaxed);
+    let outcome = CONNS.lock()
+                       .unwrap()
+                       .entry(fd)
+                       .or_insert_with(|| {
+                           ConnState::new(get_socket_type(fd) == Some(libc::SOCK_STREAM))
+                       })
+                       .before_io(&limits, dir, len);
+
+    if let Outcome::Return(_, err) = outcome {
+        // raise() does nothing if SIGPIPE is ignored, just like the kernel.
+        if err == libc::EPIPE && flags & libc::MSG_NOSIGNAL == 0 {
+            unsafe { libc::raise(libc::SIGPIPE) };
+        }
+        if err != 0 {
+            set_errno(Errno(err));
+        }
+    }
+
+    outcome
+}
+
+pub fn after_io(fd: c_int, ret: ssize_t) {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    if let Some(state) = CONNS.lock().unwrap().get_mut(&fd) {
+        state.after_io(ret);
+    }
+}
+
+pub fn forget_fd(fd: c_int) {
+    if CONNECTING.load(Ordering::Relaxed) {
+        PENDING.lock().unwrap().remove(&fd);
+    }
+    if TRACKING.load(Ordering::Relaxed) {
+        CONNS.lock().unwrap().remove(&fd);
+    }
+}
+
+/**
+ * @return the total length of the `iovlen` buffers at `iov`.
+ */
+pub unsafe fn iov_len(iov: *const libc::iovec, iovlen: usize) -> usize {
+    if iov.is_null() {
+        return 0;
+    }
+    slice::from_raw_parts(iov, iovlen).iter().map(|vec| vec.iov_len).sum()
+}
+
+/// The buffers a msghdr pointed at, while it points at a shortened copy.
+pub struct ClampedIov {
+    iov: *mut libc::iovec,
+    iovlen: usize,
+    short: Vec<libc::iovec>,
+}
+
+/**
+ * Points `hdr` at a copy of its buffers that's cut short to `len` bytes,
+ *   if they hold more than that.  The sendmsg() family can't be told how
+ *   much to move any other way.
+ *
+ * @return what `unclamp()` needs to put them back.
+ */
+pub unsafe fn clamp(hdr: &mut libc::msghdr, len: usize) -> Option<ClampedIov> {
+    if iov_len(hdr.msg_iov, hdr.msg_iovlen) <= len {
+        return None;
+    }
+
+    let mut left = len;
+    let mut short = Vec::new();
+    for vec in slice::from_raw_parts(hdr.msg_iov, hdr.msg_iovlen) {
+        if left == 0 {
+            break;
+        }
+        let n = cmp::min(vec.iov_len, left);
+        short.push(libc::iovec {
+            iov_base: vec.iov_base,
+            iov_len: n,
+        });
+        left -= n;
+    }
+
+    let clamped = ClampedIov {
+        iov: hdr.msg_iov,
+        iovlen: hdr.msg_iovlen,
+        short,
+    };
+    hdr.msg_iov = clamped.short.as_ptr() as *mut libc::iovec;
+    hdr.msg_iovlen = clamped.short.len();
+    Some(clamped)
+}
+
+pub fn unclamp(hdr: &mut libc::msghdr, clamped: Option<ClampedIov>) {
+    if let Some(clamped) = clamped {
+        hdr.msg_iov = clamped.iov;
+        hdr.msg_iovlen = clamped.iovlen;
+    }
+}
+
+/**
+ * @return the total length of the buffers of all of `msgs`.
+ */
+pub unsafe fn mmsg_len(msgs: &[libc::mmsghdr]) -> usize {
+    msgs.iter().map(|msg| iov_len(msg.msg_hdr.msg_iov, msg.msg_hdr.msg_iovlen)).sum()
+}
+
+/**
+ * Cuts `msgs` down to `len` bytes, as `clamp()` does for one message: those
+ *   past the cut are left out, and the one it falls in is clamped.
+ *
+ * @return how many messages are left, with the clamped one's index.
+ */
+pub unsafe fn clamp_mmsgs(msgs: &mut [libc::mmsghdr],
+                          len: usize)
+                          -> (usize, Option<(usize, ClampedIov)>) {
+    let mut left = len;
+    for (i, msg) in msgs.iter_mut().enumerate() {
+        if left == 0 && i > 0 {
+            return (i, None);
+        }
+        let msg_len = iov_len(msg.msg_hdr.msg_iov, msg.msg_hdr.msg_iovlen);
+        if msg_len > left {
+            return (i + 1, clamp(&mut msg.msg_hdr, left).map(|clamped| (i, clamped)));
+        }
+        left -= msg_len;
+    }
+    (msgs.len(), None)
+}
+
+pub fn unclamp_mmsgs(msgs: &mut [libc::mmsghdr], clamped: Option<(usize, ClampedIov)>) {
+    if let Some((i, clamped)) = clamped {
+        unclamp(&mut msgs[i].msg_hdr, Some(clamped));
+    }
+}
+
+/**
+ * Connects `fd`, or pretends to, according to LIBFAULTINJ_CONNECT_MODE.
+ *   `real_connect` makes the actual connect() call.
+ *
+ * @return connect()'s return value, with errno set.
+ */
+pub fn connect<F>(fd: c_int, real_connect: F) -> c_int
+    where F: FnOnce() -> c_int
+{
+    use errno::{Errno, errno, set_errno};
+    use std::thread::sleep;
+
+    if let Some(ret) = reconnect(fd) {
+        return ret;
+    }
+
+    let mode = match ConnectMode::from_env() {
+        Some(mode) if ERR_FDS.read().unwrap().contains(&fd) => mode,
+        _ => return real_connect(),
+    };
+    let delay = get_delay_amount_ms!("connect", Selector::Addr);
+
+    if !is_nonblocking(fd) {
+        sleep(delay);
+        return match mode {
+            ConnectMode::Delay => real_connect(),
+            // A blocking connect gives up eventually.
+            ConnectMode::Hang => {
+                set_errno(Errno(libc::ETIMEDOUT));
+                -1
+            }
+            ConnectMode::Fail(err) => {
+                set_errno(Errno(err));
+                -1
+            }
+        };
+    }
+
+    let done_at = match mode {
+        ConnectMode::Hang => None,
+        _ => Some(Instant::now() + delay),
+    };
+    if mode == ConnectMode::Delay {
+        let ret = real_connect();
+        if ret != 0 && errno().0 != libc::EINPROGRESS {
+            return ret;
+        }
+    }
+
+    CONNECTING.store(true, Ordering::Relaxed);
+    PENDING.lock().unwrap().insert(fd, PendingConnect { mode, done_at });
+    ready::hide(fd, done_at);
+    if let ConnectMode::Fail(_) = mode {
+        ready::set_extra_events(fd, libc::POLLERR);
+    }
+
+    set_errno(Errno(libc::EINPROGRESS));
+    -1
+}
+
+/**
+ * A connect() on a socket whose connect is pending reports how it's going.
+ *
+ * @return None if `fd` has no pending connect, or it should be passed on to
+ *      the real connect().
+ */
+fn reconnect(fd: c_int) -> Option<c_int> {
+    use errno::{Errno, set_errno};
+
+    if !CONNECTING.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    let mut pending = PENDING.lock().unwrap();
+    let (mode, done) = match pending.get(&fd) {
+        Some(conn) => (conn.mode, conn.done_at.is_some_and(|at| Instant::now() >= at)),
+        None => return None,
+    };
+
+    if !done {
+        set_errno(Errno(libc::EALREADY));
+        return Some(-1);
+    }
+
+    pending.remove(&fd);
+    match mode {
+        ConnectMode::Fail(err) => {
+            ready::set_extra_events(fd, 0);
+            set_errno(Errno(err));
+            Some(-1)
+        }
+        _ => None,
+    }
+}
+
+/**
+ * @return the SO_ERROR of `fd` if it has a pending connect, or None if the
+ *      real one should be used.  Like the real one, reading it clears it.
+ */
+pub fn take_so_error(fd: c_int) -> Option<c_int> {
+    if !CONNECTING.load(Ordering::Relaxed) {
+        return None;
+    }
+
+    let mut pending = PENDING.lock().unwrap();
+    let (mode, done) = match pending.get(&fd) {
+        Some(conn) => (conn.mode, conn.done_at.is_some_and(|at| Instant::now() >= at)),
+        None => return None,
+    };
+
+    if !done {
+        return Some(0);
+    }
+
+    pending.remove(&fd);
+    match mode {
+        ConnectMode::Fail(err) => {
+            ready::set_extra_events(fd, 0);
+            Some(err)
+        }
+        _ => None,
+    }
+}
+
+/**
+ * Answers getsockopt(SO_ERROR) for sockets with a pending connect.
+ *
+ * @return Some(return value) if it was answered, None otherwise.
+ */
+pub unsafe fn getsockopt_so_error(fd: c_int, optval: *mut c_void, optlen: *mut libc::socklen_t)
+                           -> Option<c_int> {
+    use std::mem::size_of;
+
+    if optval.is_null() || optlen.is_null() ||
+       unsafe { (*optlen as usize) < size_of::<c_int>() } {
+        return None;
+    }
+
+    let err = take_so_error(fd)?;
+    unsafe {
+        *(optval as *mut c_int) = err;
+        *optlen = size_of::<c_int>() as libc::socklen_t;
+    }
+    Some(0)
+}
+
+#[cfg(test)]
+mod test {
+    use std::mem;
+    use super::{ConnState, ConnectMode, Direction, Limits, Outcome, Phase, clamp, clamp_mmsgs,
+                iov_len, unclamp, unclamp_mmsgs};
+    extern crate libc;
+
+    #[test]
+    fn test_reset_after_bytes() {
+        let limits = Limits {
+            bytes: Some(10),
+            calls: None,
+            eof: false,
+        };
+        let mut conn = ConnState::new(true);
+
+        assert_eq!(conn.before_io(&limits, Direction::Send, 8), Outcome::Proceed(8));
+        conn.after_io(8);
+        assert_eq!(conn.before_io(&limits, Direction::Send, 8), Outcome::Proceed(2));
+        conn.after_io(2);
+
+        assert_eq!(conn.before_io(&limits, Direction::Recv, 8),
+                   Outcome::Return(-1, libc::ECONNRESET));
+        assert_eq!(conn.before_io(&limits, Direction::Recv, 8), Outcome::Return(0, 0));
+        assert_eq!(conn.before_io(&limits, Direction::Send, 8),
+                   Outcome::Return(-1, libc::EPIPE));
+        assert_eq!(conn.phase, Phase::Reset);
+    }
+
+    #[test]
+    fn test_eof_after_calls() {
+        let limits = Limits {
+            bytes: None,
+            calls: Some(1),
+            eof: true,
+        };
+        let mut conn = ConnState::new(true);
+
+        assert_eq!(conn.before_io(&limits, Direction::Recv, 8), Outcome::Proceed(8));
+        conn.after_io(8);
+        assert_eq!(conn.before_io(&limits, Direction::Recv, 8), Outcome::Return(0, 0));
+        assert_eq!(conn.before_io(&limits, Direction::Send, 8), Outcome::Proceed(8));
+
+        let mut not_stream = ConnState::new(false);
+        not_stream.after_io(8);
+        assert_eq!(not_stream.before_io(&limits, Direction::Recv, 8), Outcome::Proceed(8));
+    }
+
+    #[test]
+    fn test_clamp() {
+        let mut first = [0u8; 4];
+        let mut second = [0u8; 4];
+        let mut iov = [libc::iovec {
+                           iov_base: first.as_mut_ptr() as *mut libc::c_void,
+                           iov_len: first.len(),
+                       },
+                       libc::iovec {
+                           iov_base: second.as_mut_ptr() as *mut libc::c_void,
+                           iov_len: second.len(),
+                       }];
+        let mut hdr: libc::msghdr = unsafe { mem::zeroed() };
+        hdr.msg_iov = iov.as_mut_ptr();
+        hdr.msg_iovlen = iov.len();
+
+        assert!(unsafe { clamp(&mut hdr, 8) }.is_none());
+        let clamped = unsafe { clamp(&mut hdr, 6) };
+        assert_eq!((hdr.msg_iovlen, unsafe { iov_len(hdr.msg_iov, hdr.msg_iovlen) }), (2, 6));
+        unclamp(&mut hdr, clamped);
+        assert_eq!((hdr.msg_iov, hdr.msg_iovlen), (iov.as_mut_ptr(), 2));
+
+        let mut msgs: [libc::mmsghdr; 3] = unsafe { mem::zeroed() };
+        for msg in msgs.iter_mut() {
+            msg.msg_hdr = hdr;
+        }
+        let (count, clamped) = unsafe { clamp_mmsgs(&mut msgs, 10) };
+        assert_eq!((count, clamped.as_ref().map(|&(i, _)| i)), (2, Some(1)));
+        assert_eq!(unsafe { iov_len(msgs[1].msg_hdr.msg_iov, msgs[1].msg_hdr.msg_iovlen) }, 2);
+        unclamp_mmsgs(&mut msgs, clamped);
+        assert_eq!(msgs[1].msg_hdr.msg_iov, iov.as_mut_ptr());
+        assert_eq!(unsafe { clamp_mmsgs(&mut msgs, 16) }.0, 2);
+    }
+
+    #[test]
+    fn test_connect_mode() {
+        assert_eq!(ConnectMode::parse("hang"), Some(ConnectMode::Hang));
+        assert_eq!(ConnectMode::parse(" Delay"), Some(ConnectMode::Delay));
+        assert_eq!(ConnectMode::parse("refuse"), Some(ConnectMode::Fail(libc::ECONNREFUSED)));
+        assert_eq!(ConnectMode::parse("timeout"), Some(ConnectMode::Fail(libc::ETIMEDOUT)));
+        assert_eq!(ConnectMode::parse("sometimes"), None);
+    }
+}
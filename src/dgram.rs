@@ -0,0 +1,414 @@
+extern crate libc;
+
+// Datagram-level faults.  An errno is the wrong failure for most UDP
+//   traffic: datagrams silently go missing, arrive twice or arrive out
+//   of order instead.  These actions apply to datagrams sent to, or
+//   received from, an address that matches LIBFAULTINJ_DGRAM_ADDR.
+
+use std::collections::HashMap;
+use std::collections::VecDeque;
+use std::{cmp, ptr, slice};
+use std::sync::Mutex;
+
+use libc::{c_int, c_void, size_t, ssize_t, sockaddr, socklen_t};
+use addr::PeerAddrBuf;
+use errors::{SomeHashState, SendToFunc, GETPEERNAME_FUNC, chance, get_env_value, get_socket_type,
+             matches_addr, matches_peer_addr};
+
+pub const DGRAM_ADDR_ENV: &str = "LIBFAULTINJ_DGRAM_ADDR";
+const DEFAULT_REORDER_WINDOW: usize = 3;
+
+/// A copy of a datagram that was held back or is to be delivered twice.
+pub struct Datagram {
+    pub data: Vec<u8>,
+    /// The raw sockaddr it's addressed to or came from, empty if none.
+    pub addr: Vec<u8>,
+    pub flags: c_int,
+}
+
+struct HeldDatagram {
+    // The number of later datagrams to send before this one.
+    remaining: usize,
+    datagram: Datagram,
+}
+
+lazy_static! {
+    static ref HELD: Mutex<HashMap<c_int, Vec<HeldDatagram>, SomeHashState>>
+            = Mutex::new(HashMap::with_hasher(SomeHashState::default()));
+    static ref DUPLICATES: Mutex<HashMap<c_int, VecDeque<Datagram>, SomeHashState>>
+            = Mutex::new(HashMap::with_hasher(SomeHashState::default()));
+    static ref DEFERRED_ERRORS: Mutex<HashMap<c_int, c_int, SomeHashState>>
+            = Mutex::new(HashMap::with_hasher(SomeHashState::default()));
+    static ref SENDTO_FUNC: SendToFunc = get_libc_func!(SendToFunc, "sendto");
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SendAction {
+    Deliver,
+    Drop,
+    Duplicate,
+    /// Hold the datagram back until this many later ones have been sent.
+    Hold(usize),
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RecvAction {
+    Deliver,
+    Drop,
+    Duplicate,
+}
+
+pub fn choose_send_action() -> SendAction {
+    use rand;
+    use rand::Rng;
+
+    if chance("LIBFAULTINJ_DGRAM_DROP_PCT") {
+        SendAction::Drop
+    } else if chance("LIBFAULTINJ_DGRAM_DUP_PCT") {
+        SendAction::Duplicate
+    } else if chance("LIBFAULTINJ_DGRAM_REORDER_PCT") {
+        let window = get_env_value::<usize>("LIBFAULTINJ_DGRAM_REORDER_WINDOW")
+                         .unwrap_or(DEFAULT_REORDER_WINDOW);
+        SendAction::Hold(rand::thread_rng().gen_range(1, cmp::max(window, 1) + 1))
+    } else {
+        SendAction::Deliver
+    }
+}
+
+/**
+ * @return what to do with a datagram that's about to be sent on `fd` to
+ *      `dest`.  A null `dest` means the socket's connected peer.
+ */
+pub unsafe fn send_action(fd: c_int, dest: *const sockaddr) -> SendAction {
+    if send_selected(fd, dest) {
+        choose_send_action()
+    } else {
+        SendAction::Deliver
+    }
+}
+
+pub fn choose_recv_action() -> RecvAction {
+    if chance("LIBFAULTINJ_DGRAM_DROP_PCT") {
+        RecvAction::Drop
+    } else if chance("LIBFAULTINJ_DGRAM_DUP_PCT") {
+        RecvAction::Duplicate
+    } else {
+        RecvAction::Deliver
+    }
+}
+
+pub fn is_dgram_socket(fd: c_int) -> bool {
+    get_socket_type(fd) == Some(libc::SOCK_DGRAM)
+}
+
+/**
+ * @return true if datagrams sent on `fd` to `dest` are subject to datagram
+ *      faults.  A null `dest` means the socket's connected peer.
+ */
+pub unsafe fn send_selected(fd: c_int, dest: *const sockaddr) -> bool {
+    if ::std::env::var_os(DGRAM_ADDR_ENV).is_none() || !is_dgram_socket(fd) {
+        return false;
+    }
+
+    if !dest.is_null() {
+        return matches_addr(dest, DGRAM_ADDR_ENV);
+    }
+
+    let mut peer = PeerAddrBuf::default();
+    let peer_ptr = peer.as_mut_ptr();
+    GETPEERNAME_FUNC(fd, peer_ptr, &mut peer.len) == 0 && matches_peer_addr(&peer, DGRAM_ADDR_ENV)
+}
+
+/**
+ * @return true if a datagram received on `fd` from `peer` is subject to
+ *      datagram faults.
+ */
+pub fn recv_selected(fd: c_int, peer: &PeerAddrBuf) -> bool {
+    ::std::env::var_os(DGRAM_ADDR_ENV).is_some() && is_dgram_socket(fd) &&
+    matches_peer_addr(peer, DGRAM_ADDR_ENV)
+}
+
+/**
+ * @return the length to report for a received datagram of `len` bytes, and
+ *      whether it was cut short by LIBFAULTINJ_DGRAM_TRUNCATE_BYTES.
+ */
+pub fn truncated_len(len: ssize_t, flags: c_int) -> (ssize_t, bool) {
+    match get_env_value::<ssize_t>("LIBFAULTINJ_DGRAM_TRUNCATE_BYTES") {
+        Some(limit) if limit >= 0 && len > limit => {
+            // With MSG_TRUNC the caller asked for the real length.
+            if flags & libc::MSG_TRUNC != 0 {
+                (len, true)
+            } else {
+                (limit, true)
+            }
+        }
+        _ => (len, false),
+    }
+}
+
+pub unsafe fn raw_addr(addr: *const sockaddr, addrlen: socklen_t) -> Vec<u8> {
+    if addr.is_null() {
+        Vec::new()
+    } else {
+        slice::from_raw_parts(addr as *const u8, addrlen as usize).to_vec()
+    }
+}
+
+fn send_datagram(fd: c_int, datagram: &Datagram) -> ssize_t {
+    let addr = if datagram.addr.is_empty() {
+        ptr::null()
+    } else {
+        datagram.addr.as_ptr() as *const sockaddr
+    };
+
+    SENDTO_FUNC(fd,
+                datagram.data.as_ptr() as *const c_void,
+                datagram.data.len() as size_t,
+                datagram.flags,
+                addr,
+                datagram.addr.len() as socklen_t)
+}
+
+/**
+ * Counts one more datagram sent on `fd` and sends any held datagrams whose
+ * turn has come.
+ */
+pub fn release_held(fd: c_int) {
+    let due: Vec<Datagram> = {
+        let mut held = HELD.lock().unwrap();
+        let queue = match held.get_mut(&fd) {
+            Some(queue) => queue,
+            None => return,
+        };
+
+        for entry in queue.iter_mut() {
+            entry.remaining -= 1;
+        }
+        let (due, waiting): (Vec<HeldDatagram>, Vec<HeldDatagram>) =
+            queue.drain(..).partition(|entry| entry.remaining == 0);
+        *queue = waiting;
+
+        due.into_iter().map(|entry| entry.datagram).collect()
+    };
+
+    for datagram in due {
+        send_datagram(fd, &datagram);
+    }
+}
+
+/**
+ * Sends everything still held for `fd`, which is about to be closed.
+ */
+pub fn flush_held(fd: c_int) {
+    DUPLICATES.lock().unwrap().remove(&fd);
+    DEFERRED_ERRORS.lock().unwrap().remove(&fd);
+
+    let held = HELD.lock().unwrap().remove(&fd);
+    for entry in held.into_iter().flat_map(|queue| queue.into_iter()) {
+        send_datagram(fd, &entry.datagram);
+    }
+}
+
+/**
+ * Sends a datagram of `len` bytes with `real_send` unless `action` says
+ * otherwise.  `copy` is only called when the datagram needs to be held.
+ */
+pub fn send<F, C>(fd: c_int, action: SendAction, len: usize, copy: C, real_send: F) -> ssize_t
+    where F: Fn() -> ssize_t,
+          C: FnOnce() -> Datagram
+{
+    match action {
+        SendAction::Deliver => {
+            let ret = real_send();
+            if ret >= 0 {
+                release_held(fd);
+            }
+            ret
+        }
+        SendAction::Duplicate => {
+            let ret = real_send();
+            if ret >= 0 {
+                real_send();
+                release_held(fd);
+            }
+            ret
+        }
+        SendAction::Drop => {
+            release_held(fd);
+            len as ssize_t
+        }
+        SendAction::Hold(remaining) => {
+            release_held(fd);
+            HELD.lock().unwrap().entry(fd).or_default().push(HeldDatagram {
+                remaining,
+                datagram: copy(),
+            });
+            len as ssize_t
+        }
+    }
+}
+
+/**
+ * Applies the receive-side datagram faults to a datagram of `len` bytes
+ * that was received on `fd` from `peer`.  `copy(n)` returns the first `n`
+ * bytes of it, and is only called if it's to be delivered twice.
+ *
+ * @return None if the datagram was dropped and the caller should receive
+ *      again, otherwise the length to report and whether it was truncated.
+ */
+pub fn on_receive<C>(fd: c_int,
+                     flags: c_int,
+                     peer: &PeerAddrBuf,
+                     len: ssize_t,
+                     copy: C)
+                     -> Option<(ssize_t, bool)>
+    where C: FnOnce(usize) -> Vec<u8>
+{
+    if !recv_selected(fd, peer) {
+        return Some((len, false));
+    }
+
+    // A peeked datagram is still queued, so it's dropped or duplicated
+    //   when it's actually received.
+    let peeking = flags & libc::MSG_PEEK != 0;
+    let (reported, truncated) = truncated_len(len, flags);
+
+    match choose_recv_action() {
+        RecvAction::Drop if !peeking => return None,
+        RecvAction::Duplicate if !peeking => {
+            queue_duplicate(fd,
+                            Datagram {
+                                data: copy(cmp::min(reported, len) as usize),
+                                addr: peer.to_raw(),
+                                flags: 0,
+                            })
+        }
+        _ => {}
+    }
+
+    Some((reported, truncated))
+}
+
+pub fn queue_duplicate(fd: c_int, datagram: Datagram) {
+    DUPLICATES.lock().unwrap().entry(fd).or_default().push_back(datagram);
+}
+
+/**
+ * @return the next duplicate to be delivered on `fd`, if any.  Peeking
+ *      leaves it in place.
+ */
+pub fn take_duplicate(fd: c_int, flags: c_int) -> Option<Datagram> {
+    let mut duplicates = DUPLICATES.lock().unwrap();
+    let queue = duplicates.get_mut(&fd)?;
+
+    if flags & libc::MSG_PEEK != 0 {
+        queue.front().map(|datagram| {
+            Datagram {
+                data: datagram.data.clone(),
+                addr: datagram.addr.clone(),
+                flags: datagram.flags,
+            }
+        })
+    } else {
+        queue.pop_front()
+    }
+}
+
+/**
+ * Has the next batch received on `fd` fail with `err`, the way the kernel
+ *   reports an error that recvmmsg() hits after receiving some messages.
+ */
+pub fn defer_error(fd: c_int, err: c_int) {
+    DEFERRED_ERRORS.lock().unwrap().insert(fd, err);
+}
+
+/**
+ * @return the error deferred for `fd` by defer_error(), if any.
+ */
+pub fn take_deferred_error(fd: c_int) -> Option<c_int> {
+    DEFERRED_ERRORS.lock().unwrap().remove(&fd)
+}
+
+/**
+ * Copies `data` into the caller's scatter list.
+ *
+ * @return the number of bytes copied.
+ */
+pub unsafe fn scatter(iov: *const libc::iovec, iovlen: usize, data: &[u8]) -> usize {
+    let mut copied = 0;
+
+    for vec in slice::from_raw_parts(iov, iovlen) {
+        let n = cmp::min(vec.iov_len, data.len() - copied);
+        ptr::copy_nonoverlapping(data[copied..].as_ptr(), vec.iov_base as *mut u8, n);
+        copied += n;
+        if copied == data.len() {
+            break;
+        }
+    }
+
+    copied
+}
+
+/**
+ * @return the contents of the caller's gather list as one buffer.
+ */
+pub unsafe fn gather(iov: *const libc::iovec, iovlen: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+
+    for vec in slice::from_raw_parts(iov, iovlen) {
+        data.extend_from_slice(slice::from_raw_parts(vec.iov_base as *const u8, vec.iov_len));
+    }
+
+    data
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use super::{truncated_len, scatter, gather, defer_error, take_deferred_error, flush_held};
+    extern crate libc;
+
+    #[test]
+    fn test_truncated_len() {
+        assert_eq!(truncated_len(1500, 0), (1500, false));
+
+        env::set_var("LIBFAULTINJ_DGRAM_TRUNCATE_BYTES", "512");
+        assert_eq!(truncated_len(1500, 0), (512, true));
+        assert_eq!(truncated_len(1500, libc::MSG_TRUNC), (1500, true));
+        assert_eq!(truncated_len(100, 0), (100, false));
+        env::remove_var("LIBFAULTINJ_DGRAM_TRUNCATE_BYTES");
+    }
+
+    #[test]
+    fn test_scatter_gather() {
+        let mut first = [0u8; 3];
+        let mut second = [0u8; 4];
+        let iov = [libc::iovec {
+                       iov_base: first.as_mut_ptr() as *mut libc::c_void,
+                       iov_len: first.len(),
+                   },
+                   libc::iovec {
+                       iov_base: second.as_mut_ptr() as *mut libc::c_void,
+                       iov_len: second.len(),
+                   }];
+
+        assert_eq!(unsafe { scatter(iov.as_ptr(), iov.len(), b"abcde") }, 5);
+        assert_eq!(&first, b"abc");
+        assert_eq!(&second, b"de\0\0");
+        assert_eq!(unsafe { gather(iov.as_ptr(), iov.len()) }, b"abcde\0\0".to_vec());
+    }
+
+    #[test]
+    fn test_deferred_error() {
+        let fd = 9001;
+        assert_eq!(take_deferred_error(fd), None);
+
+        defer_error(fd, libc::ECONNREFUSED);
+        assert_eq!(take_deferred_error(fd), Some(libc::ECONNREFUSED));
+        assert_eq!(take_deferred_error(fd), None);
+
+        defer_error(fd, libc::ECONNREFUSED);
+        flush_held(fd);
+        assert_eq!(take_deferred_error(fd), None);
+    }
+}
@@ -0,0 +1,258 @@
+extern crate libc;
+
+// Name resolution faults.  LIBFAULTINJ_DNS_RULES is a comma-separated list
+//   of host=action rules, e.g.
+//
+//     db.internal=EAI_AGAIN,*.example.com=delay:500,api.test=10.255.255.1
+//
+//   A lookup of a matching host fails with that EAI_* error, is delayed
+//   by that many milliseconds, or resolves to that address instead.
+
+use std::cell::Cell;
+use std::ffi::{CStr, CString};
+use std::net::IpAddr;
+use std::time::Duration;
+
+use libc::{c_char, c_int};
+use errors::roll_faults;
+
+pub const DNS_RULES_ENV: &str = "LIBFAULTINJ_DNS_RULES";
+
+// From <netdb.h>.
+const HOST_NOT_FOUND: c_int = 1;
+const TRY_AGAIN: c_int = 2;
+const NO_RECOVERY: c_int = 3;
+const NO_DATA: c_int = 4;
+
+extern "C" {
+    fn __h_errno_location() -> *mut c_int;
+}
+
+thread_local! {
+    // Set while the library itself resolves names, like for the hostname
+    //   selectors of LIBFAULTINJ_ERROR_ADDR.
+    static RESOLVING_INTERNALLY: Cell<bool> = const { Cell::new(false) };
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum DnsAction {
+    /// Fail with this EAI_* error.
+    Error(c_int),
+    Delay(Duration),
+    /// Resolve to this address instead.
+    Address(IpAddr),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct DnsRule {
+    /// A host name, "*.domain" for anything within domain, or "*".
+    host: String,
+    action: DnsAction,
+}
+
+/// What a lookup should do.
+#[derive(Debug, PartialEq)]
+pub enum Lookup {
+    Real,
+    Fail(c_int),
+    /// Look up this address literal instead.
+    Substitute(CString),
+}
+
+fn normalize_host(host: &str) -> String {
+    host.trim().trim_end_matches('.').to_lowercase()
+}
+
+impl DnsAction {
+    pub fn parse(action: &str) -> Option<DnsAction> {
+        let action = action.trim();
+
+        match action.to_uppercase().as_str() {
+            "EAI_AGAIN" => return Some(DnsAction::Error(libc::EAI_AGAIN)),
+            "EAI_NONAME" => return Some(DnsAction::Error(libc::EAI_NONAME)),
+            "EAI_FAIL" => return Some(DnsAction::Error(libc::EAI_FAIL)),
+            "EAI_NODATA" => return Some(DnsAction::Error(libc::EAI_NODATA)),
+            _ => (),
+        }
+
+        if let Some(ms) = action.strip_prefix("delay:") {
+            return ms.trim()
+                     .parse::<u64>()
+                     .ok()
+                     .map(|ms| DnsAction::Delay(Duration::from_millis(ms)));
+        }
+
+        action.parse::<IpAddr>().ok().map(DnsAction::Address)
+    }
+}
+
+impl DnsRule {
+    pub fn parse(rule: &str) -> Option<DnsRule> {
+        let mut parts = rule.splitn(2, '=');
+        let host = normalize_host(parts.next()?);
+        let action = DnsAction::parse(parts.next()?)?;
+
+        if host.is_empty() {
+            None
+        } else {
+            Some(DnsRule { host, action })
+        }
+    }
+
+    /**
+     * @return true if this rule applies to `host`, which is normalized.
+     */
+    pub fn matches(&self, host: &str) -> bool {
+        if self.host == "*" {
+            return true;
+        }
+        match self.host.strip_prefix("*.") {
+            Some(domain) => host.len() > domain.len() && host.ends_with(domain) &&
+                            host[..host.len() - domain.len()].ends_with('.'),
+            None => self.host == host,
+        }
+    }
+}
+
+/**
+ * @return every rule that could be parsed from `rules`.  Unparseable ones
+ *      are ignored.
+ */
+pub fn parse_rules(rules: &str) -> Vec<DnsRule> {
+    rules.split(',')
+         .filter(|rule| !rule.trim().is_empty())
+         .filter_map(DnsRule::parse)
+         .collect()
+}
+
+/**
+ * @return the actions of the rules in LIBFAULTINJ_DNS_RULES for `host`, in
+ *      order.
+ */
+fn actions_for(host: &str) -> Vec<DnsAction> {
+    let rules = match ::std::env::var(DNS_RULES_ENV) {
+        Ok(rules) => parse_rules(&rules),
+        Err(_) => return Vec::new(),
+    };
+    let host = normalize_host(host);
+
+    rules.into_iter()
+         .filter(|rule| rule.matches(&host))
+         .map(|rule| rule.action)
+         .collect()
+}
+
+/**
+ * Runs `f` without faults for the names it resolves, though substituted
+ *   addresses still apply.
+ */
+pub fn resolve_internally<T, F>(f: F) -> T
+    where F: FnOnce() -> T
+{
+    RESOLVING_INTERNALLY.with(|internal| {
+        let was_internal = internal.replace(true);
+        let ret = f();
+        internal.set(was_internal);
+        ret
+    })
+}
+
+pub fn is_resolving_internally() -> bool {
+    RESOLVING_INTERNALLY.with(|internal| internal.get())
+}
+
+/**
+ * Sleeps for any delay rules that apply to `host`.
+ *
+ * @return what the lookup of `host` by `funcname` should do.
+ */
+pub fn lookup_str(funcname: &str, host: &str) -> Lookup {
+    use std::thread::sleep;
+
+    let actions = actions_for(host);
+    let (delays, errors) = if actions.is_empty() || is_resolving_internally() {
+        (false, false)
+    } else {
+        roll_faults(funcname, true, true)
+    };
+
+    for action in actions {
+        match action {
+            DnsAction::Delay(delay) if delays => sleep(delay),
+            DnsAction::Error(err) if errors => return Lookup::Fail(err),
+            DnsAction::Address(ip) => {
+                return match CString::new(ip.to_string()) {
+                    Ok(ip) => Lookup::Substitute(ip),
+                    Err(_) => Lookup::Real,
+                };
+            }
+            _ => (),
+        }
+    }
+
+    Lookup::Real
+}
+
+/**
+ * @return what the forward lookup of the C string `host` by `funcname`
+ *      should do.
+ */
+pub unsafe fn lookup(funcname: &str, host: *const c_char) -> Lookup {
+    if host.is_null() || ::std::env::var_os(DNS_RULES_ENV).is_none() {
+        return Lookup::Real;
+    }
+
+    match unsafe { CStr::from_ptr(host) }.to_str() {
+        // Address literals don't need resolving.
+        Ok(host) if host.parse::<IpAddr>().is_ok() => Lookup::Real,
+        Ok(host) => lookup_str(funcname, host),
+        Err(_) => Lookup::Real,
+    }
+}
+
+/**
+ * @return the h_errno for the EAI_* error `err`.
+ */
+pub fn eai_to_h_errno(err: c_int) -> c_int {
+    match err {
+        libc::EAI_AGAIN => TRY_AGAIN,
+        libc::EAI_FAIL => NO_RECOVERY,
+        libc::EAI_NODATA => NO_DATA,
+        _ => HOST_NOT_FOUND,
+    }
+}
+
+pub fn set_h_errno(err: c_int) {
+    unsafe { *__h_errno_location() = err };
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DnsAction, DnsRule, parse_rules};
+    use std::time::Duration;
+    extern crate libc;
+
+    #[test]
+    fn test_parse_rules() {
+        let rules = parse_rules("db.internal=EAI_AGAIN, *.Example.COM.=delay:500,,api=::1,x=y");
+        assert_eq!(rules.len(), 3);
+        assert_eq!(rules[0].action, DnsAction::Error(libc::EAI_AGAIN));
+        assert_eq!(rules[1].action, DnsAction::Delay(Duration::from_millis(500)));
+        assert_eq!(rules[2].action, DnsAction::Address("::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_match_rules() {
+        let wild = DnsRule::parse("*.example.com=EAI_NONAME").unwrap();
+        assert!(wild.matches("www.example.com"));
+        assert!(wild.matches("a.b.example.com"));
+        assert!(!wild.matches("example.com"));
+        assert!(!wild.matches("badexample.com"));
+
+        let exact = DnsRule::parse("DB.internal.=10.0.0.1").unwrap();
+        assert!(exact.matches("db.internal"));
+        assert!(!exact.matches("db.internal.example.com"));
+
+        assert!(DnsRule::parse("*=EAI_FAIL").unwrap().matches("anything"));
+    }
+}
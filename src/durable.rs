@@ -0,0 +1,315 @@
+extern crate libc;
+
+// Renames that aren't durable.  A rename() only survives a crash once the
+//   directories it changed have been fsync()ed, which atomic-replace code
+//   often forgets.  Renames under LIBFAULTINJ_LAZY_RENAME_PATH are
+//   remembered until then, and LIBFAULTINJ_CRASH_SIGNAL simulates a crash:
+//   the renames that weren't made durable are undone, restoring any file
+//   they replaced, and the process is killed.
+
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::{Arc, Mutex, Once};
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicPtr, AtomicUsize, Ordering};
+
+use libc::{c_int, c_void};
+use errors::{LinkFunc, UnlinkFunc, get_env_value};
+use namespace::fd_path;
+
+// Spares fsync() a lock when no rename's been remembered.
+static REMEMBERING: AtomicBool = AtomicBool::new(false);
+// Names the files kept to restore what a rename replaced.
+static BACKUPS: AtomicUsize = AtomicUsize::new(0);
+static SET_UP: Once = Once::new();
+
+// The crash handler can't take locks or allocate, so it reads a copy of
+//   what's in PENDING, latest first, that's swapped in whenever that
+//   changes.  Once it's set CRASHING, old copies are never freed, since it
+//   may still be reading one.
+static UNDO: AtomicPtr<Vec<Arc<Undo>>> = AtomicPtr::new(ptr::null_mut());
+static CRASHING: AtomicBool = AtomicBool::new(false);
+// The handler the program had for LIBFAULTINJ_CRASH_SIGNAL, and its flags.
+static PREVIOUS_HANDLER: AtomicUsize = AtomicUsize::new(libc::SIG_DFL);
+static PREVIOUS_FLAGS: AtomicI32 = AtomicI32::new(0);
+
+lazy_static! {
+    static ref PENDING: Mutex<Vec<Rename>> = Mutex::new(Vec::new());
+    static ref LINK_FUNC: LinkFunc = get_libc_func!(LinkFunc, "link");
+    static ref UNLINK_FUNC: UnlinkFunc = get_libc_func!(UnlinkFunc, "unlink");
+}
+
+/// What it takes to undo a rename, made up front for the crash handler.
+#[derive(Debug)]
+struct Undo {
+    from: CString,
+    to: CString,
+    /// A link to the file `to` replaced, if it replaced one.
+    backup: Option<CString>,
+}
+
+impl Undo {
+    fn undo(&self) {
+        rename_raw(&self.to, &self.from);
+        if let Some(ref backup) = self.backup {
+            rename_raw(backup, &self.to);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Rename {
+    undo: Arc<Undo>,
+    /// The directories still to be fsync()ed.
+    unsynced: Vec<PathBuf>,
+}
+
+impl Rename {
+    /**
+     * @return whether the rename is durable once `dir` has been fsync()ed.
+     */
+    pub fn synced(&mut self, dir: &Path) -> bool {
+        self.unsynced.retain(|unsynced| unsynced != dir);
+        self.unsynced.is_empty()
+    }
+}
+
+fn c_string(path: &Path) -> CString {
+    CString::new(path.as_os_str().as_bytes()).unwrap_or_default()
+}
+
+// The rename() hook isn't safe to call from a signal handler, so this goes
+//   straight to the kernel.
+fn rename_raw(from: &CStr, to: &CStr) {
+    unsafe {
+        libc::syscall(libc::SYS_renameat2,
+                      libc::AT_FDCWD,
+                      from.as_ptr(),
+                      libc::AT_FDCWD,
+                      to.as_ptr(),
+                      0);
+    }
+}
+
+fn absolute(path: &Path) -> PathBuf {
+    match ::std::env::current_dir() {
+        Ok(cwd) => cwd.join(path),
+        Err(_) => path.to_path_buf(),
+    }
+}
+
+fn parent_dir(path: &Path) -> PathBuf {
+    let parent = path.parent().unwrap_or_else(|| Path::new("/"));
+    fs::canonicalize(parent).unwrap_or_else(|_| parent.to_path_buf())
+}
+
+/**
+ * Installs the handler for LIBFAULTINJ_CRASH_SIGNAL, the first time a
+ *   rename's remembered.  Any handler the program had is called before the
+ *   process is killed.
+ */
+fn set_up() {
+    SET_UP.call_once(|| unsafe {
+        libc::atexit(on_exit);
+
+        let signo = match get_env_value::<c_int>("LIBFAULTINJ_CRASH_SIGNAL") {
+            Some(signo) if signo > 0 => signo,
+            _ => return,
+        };
+        let mut action: libc::sigaction = ::std::mem::zeroed();
+        action.sa_sigaction = on_crash_signal as extern "C" fn(c_int, *mut libc::siginfo_t,
+                                                                *mut c_void)
+                                                  as libc::sighandler_t;
+        action.sa_flags = libc::SA_SIGINFO;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        let mut previous: libc::sigaction = ::std::mem::zeroed();
+        if libc::sigaction(signo, &action, &mut previous) == 0 {
+            PREVIOUS_HANDLER.store(previous.sa_sigaction, Ordering::SeqCst);
+            PREVIOUS_FLAGS.store(previous.sa_flags, Ordering::SeqCst);
+        }
+    });
+}
+
+/**
+ * Swaps in a copy of `pending` for the crash handler.  Call it with the
+ *   PENDING lock held.
+ */
+fn publish(pending: &[Rename]) {
+    let undo: Vec<Arc<Undo>> = pending.iter().rev().map(|rename| rename.undo.clone()).collect();
+    let old = UNDO.swap(Box::into_raw(Box::new(undo)), Ordering::SeqCst);
+    if !old.is_null() && !CRASHING.load(Ordering::SeqCst) {
+        drop(unsafe { Box::from_raw(old) });
+    }
+}
+
+// Exiting isn't crashing, so the renames will reach the disk after all.
+extern "C" fn on_exit() {
+    if let Ok(mut pending) = PENDING.lock() {
+        for rename in pending.drain(..) {
+            drop_backup(rename);
+        }
+        publish(&pending);
+    }
+}
+
+/**
+ * Calls the handler the program had for the crash signal, if it had one.
+ */
+fn chain(signo: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    let handler = PREVIOUS_HANDLER.load(Ordering::SeqCst);
+    if handler == libc::SIG_DFL || handler == libc::SIG_IGN {
+        return;
+    }
+
+    unsafe {
+        if PREVIOUS_FLAGS.load(Ordering::SeqCst) & libc::SA_SIGINFO != 0 {
+            let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) =
+                ::std::mem::transmute(handler);
+            handler(signo, info, context);
+        } else {
+            let handler: extern "C" fn(c_int) = ::std::mem::transmute(handler);
+            handler(signo);
+        }
+    }
+}
+
+// Only rename(2), the program's own handler and kill(2) are called here.
+extern "C" fn on_crash_signal(signo: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    CRASHING.store(true, Ordering::SeqCst);
+    let undo = UNDO.load(Ordering::SeqCst);
+    if !undo.is_null() {
+        for rename in unsafe { &*undo }.iter() {
+            rename.undo();
+        }
+    }
+
+    chain(signo, info, context);
+    unsafe {
+        libc::kill(libc::getpid(), libc::SIGKILL);
+    }
+}
+
+/**
+ * @return the rename of `from` to `to` to remember if it's under
+ *      LIBFAULTINJ_LAZY_RENAME_PATH, with the file it'll replace kept
+ *      aside.  Pass it to `renamed()` once it's done.
+ */
+pub fn renaming(from: &Path, to: &Path) -> Option<Rename> {
+    if !matchesPath!(from, "LIBFAULTINJ_LAZY_RENAME_PATH") &&
+       !matchesPath!(to, "LIBFAULTINJ_LAZY_RENAME_PATH") {
+        return None;
+    }
+    let (from, to) = (absolute(from), absolute(to));
+    let to_c = c_string(&to);
+
+    let replaces = fs::symlink_metadata(&to).map(|meta| !meta.is_dir()).unwrap_or(false);
+    let backup = if replaces {
+        let name = format!(".faultinj-rename-{}-{}",
+                           unsafe { libc::getpid() },
+                           BACKUPS.fetch_add(1, Ordering::Relaxed));
+        let backup = c_string(&to.with_file_name(name));
+        if LINK_FUNC(to_c.as_ptr(), backup.as_ptr()) != 0 {
+            return None;
+        }
+        Some(backup)
+    } else {
+        None
+    };
+
+    let mut unsynced = vec![parent_dir(&from)];
+    let to_dir = parent_dir(&to);
+    if !unsynced.contains(&to_dir) {
+        unsynced.push(to_dir);
+    }
+
+    Some(Rename {
+        undo: Arc::new(Undo {
+            from: c_string(&from),
+            to: to_c,
+            backup,
+        }),
+        unsynced,
+    })
+}
+
+/**
+ * Remembers `rename` until it's durable if it went through, and drops its
+ *   backup if not.
+ */
+pub fn renamed(rename: Option<Rename>, ret: c_int) {
+    let rename = match rename {
+        Some(rename) => rename,
+        None => return,
+    };
+    if ret != 0 {
+        drop_backup(rename);
+        return;
+    }
+
+    set_up();
+    REMEMBERING.store(true, Ordering::Relaxed);
+    let mut pending = PENDING.lock().unwrap();
+    pending.push(rename);
+    publish(&pending);
+}
+
+fn drop_backup(rename: Rename) {
+    if let Some(ref backup) = rename.undo.backup {
+        UNLINK_FUNC(backup.as_ptr());
+    }
+}
+
+/**
+ * Makes the renames in the directory `fd` is open on durable, once it's
+ *   been fsync()ed.
+ */
+pub fn synced(fd: c_int) {
+    if !REMEMBERING.load(Ordering::Relaxed) {
+        return;
+    }
+    let dir = match fd_path(fd) {
+        Some(dir) => dir,
+        None => return,
+    };
+
+    let mut pending = PENDING.lock().unwrap();
+    let count = pending.len();
+    let mut index = 0;
+    while index < pending.len() {
+        if pending[index].synced(&dir) {
+            drop_backup(pending.remove(index));
+        } else {
+            index += 1;
+        }
+    }
+    if pending.len() != count {
+        publish(&pending);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Rename, Undo};
+    use std::ffi::CString;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_synced() {
+        let mut rename = Rename {
+            undo: Arc::new(Undo {
+                from: CString::new("/a/x").unwrap(),
+                to: CString::new("/b/x").unwrap(),
+                backup: None,
+            }),
+            unsynced: vec![PathBuf::from("/a"), PathBuf::from("/b")],
+        };
+
+        assert!(!rename.synced(Path::new("/c")));
+        assert!(!rename.synced(Path::new("/b")));
+        assert!(rename.synced(Path::new("/a")));
+    }
+}
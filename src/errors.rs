@@ -7,7 +7,9 @@ extern crate libc;
 // unsafe impl Sync for DynamicLibrary { }
 // unsafe impl Send for DynamicLibrary { }
 
-use std::sync::RwLock;
+use std::fs;
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::hash::Hasher;
 use std::collections::hash_set::HashSet;
 use std::hash::BuildHasher;
@@ -46,11 +48,18 @@ impl Hasher for SomeHashState {
 
 pub type AlternateHashSet = HashSet<c_int, SomeHashState>;
 
+// Spares the hooks a lock until something asks how many fds are open.
+static COUNTING: AtomicBool = AtomicBool::new(false);
+
 lazy_static! {
     pub static ref DELAY_FDS: RwLock<AlternateHashSet>
             = RwLock::new(HashSet::with_hasher(SomeHashState::default()));
     pub static ref ERR_FDS: RwLock<AlternateHashSet>
             = RwLock::new(HashSet::with_hasher(SomeHashState::default()));
+    // The fds open once COUNTING: those the hooks saw made and not closed,
+    //   along with those already open when counting started.
+    static ref OPEN_FDS: Mutex<AlternateHashSet>
+            = Mutex::new(HashSet::with_hasher(SomeHashState::default()));
 //    static ref LIBC: RwLock<DynamicLibrary>
 //            = RwLock::new(DynamicLibrary::open(Some(Path::new(SYSTEM_C_LIBRARY))).unwrap());
 }
@@ -60,11 +69,14 @@ macro_rules! get_libc_func(
     (
         {
             use dylib::DynamicLibrary;
+            use oom::suspend;
             use std::mem::transmute;
             use std::path::Path;
 
             const SYSTEM_C_LIBRARY: &'static str = "libc.so.6";
 
+            // dlopen() and dlsym() allocate, and mustn't fail.
+            let _suspended = suspend();
             unsafe {
                 let libc_dl = match DynamicLibrary::open(Some(Path::new(SYSTEM_C_LIBRARY))) {
                     Ok(libc) => libc,
@@ -83,51 +95,167 @@ macro_rules! get_libc_func(
 
 
 
-pub use libc::{c_char, c_int, c_ulong, c_void, off_t, size_t, mode_t, ssize_t, sockaddr,
-               sockaddr_in};
-
-#[allow(non_camel_case_types)]
-pub type socklen_t = u8;
+pub use libc::{c_char, c_int, c_uint, c_ulong, c_void, off_t, size_t, mode_t, ssize_t, sockaddr,
+               socklen_t};
+use std::net::SocketAddr;
+use addr::PeerAddrBuf;
+use latency::Selector;
 
 pub type OpenFunc = extern "C" fn(*const c_char, c_int, mode_t) -> c_int;
-pub type ReadFunc = extern "C" fn(fd: c_int, buf: *mut c_void, nbytes: c_int) -> ssize_t;
+pub type ReadFunc = extern "C" fn(fd: c_int, buf: *mut c_void, nbytes: size_t) -> ssize_t;
 pub type WriteFunc = ReadFunc;
-pub type MmapFunc = extern "C" fn(addr: *mut c_void,
-                                  length_: size_t,
-                                  prot: c_int,
-                                  flags: c_int,
-                                  fd: c_int,
-                                  offset: off_t)
-                                  -> *mut c_void;
 pub type CloseFunc = extern "C" fn(fd: c_int) -> c_int;
+// The real ioctl() is variadic, and must be called as such.  The hook can't
+//   be, see `ioctl` in fault.rs for why it needn't.
 pub type IoctlFunc = extern "C" fn(c_int, c_ulong, ...) -> c_int;
 pub type SeekFunc = extern "C" fn(c_int, off_t, c_int) -> off_t;
 pub type Dup2Func = extern "C" fn(c_int, c_int) -> c_int;
 pub type Dup3Func = extern "C" fn(c_int, c_int, c_int) -> c_int;
+pub type DupFunc = CloseFunc;
+pub type PipeFunc = extern "C" fn(*mut c_int) -> c_int;
+pub type Pipe2Func = extern "C" fn(*mut c_int, c_int) -> c_int;
+pub type EventfdFunc = extern "C" fn(c_uint, c_int) -> c_int;
+pub type EpollCreateFunc = CloseFunc;
+pub type SocketPairFunc = extern "C" fn(c_int, c_int, c_int, *mut c_int) -> c_int;
+pub type SignalfdFunc = extern "C" fn(c_int, *const libc::sigset_t, c_int) -> c_int;
+pub type TimerfdCreateFunc = extern "C" fn(libc::clockid_t, c_int) -> c_int;
 pub type BindFunc = extern "C" fn(c_int, *const sockaddr, socklen_t) -> c_int;
 pub type SocketFunc = extern "C" fn(c_int, c_int, c_int) -> c_int;
+pub type AcceptFunc = extern "C" fn(c_int, *mut sockaddr, *mut socklen_t) -> c_int;
+pub type Accept4Func = extern "C" fn(c_int, *mut sockaddr, *mut socklen_t, c_int) -> c_int;
 pub type ConnectFunc = extern "C" fn(c_int, *const sockaddr, socklen_t) -> c_int;
 pub type StatFunc = extern "C" fn(*const c_char, *mut libc::stat) -> c_int;
+pub type StatfsFunc = extern "C" fn(*const c_char, *mut libc::statfs) -> c_int;
+pub type Statfs64Func = extern "C" fn(*const c_char, *mut libc::statfs64) -> c_int;
+pub type FstatfsFunc = extern "C" fn(c_int, *mut libc::statfs) -> c_int;
+pub type Fstatfs64Func = extern "C" fn(c_int, *mut libc::statfs64) -> c_int;
+pub type StatvfsFunc = extern "C" fn(*const c_char, *mut libc::statvfs) -> c_int;
+pub type Statvfs64Func = extern "C" fn(*const c_char, *mut libc::statvfs64) -> c_int;
+pub type FstatvfsFunc = extern "C" fn(c_int, *mut libc::statvfs) -> c_int;
+pub type Fstatvfs64Func = extern "C" fn(c_int, *mut libc::statvfs64) -> c_int;
+pub type FtruncateFunc = extern "C" fn(c_int, off_t) -> c_int;
+pub type FallocateFunc = extern "C" fn(c_int, c_int, off_t, off_t) -> c_int;
+pub type TruncateFunc = extern "C" fn(*const c_char, off_t) -> c_int;
+pub type PosixFallocateFunc = extern "C" fn(c_int, off_t, off_t) -> c_int;
+pub type CopyFileRangeFunc = extern "C" fn(c_int, *mut off_t, c_int, *mut off_t, size_t, c_uint)
+                                           -> ssize_t;
+pub type SendfileFunc = extern "C" fn(c_int, c_int, *mut off_t, size_t) -> ssize_t;
+pub type SpliceFunc = CopyFileRangeFunc;
+pub type TeeFunc = extern "C" fn(c_int, c_int, size_t, c_uint) -> ssize_t;
+pub type UnlinkFunc = extern "C" fn(*const c_char) -> c_int;
+pub type UnlinkatFunc = extern "C" fn(c_int, *const c_char, c_int) -> c_int;
+pub type RmdirFunc = UnlinkFunc;
+pub type RenameFunc = extern "C" fn(*const c_char, *const c_char) -> c_int;
+pub type RenameatFunc = extern "C" fn(c_int, *const c_char, c_int, *const c_char) -> c_int;
+pub type Renameat2Func = extern "C" fn(c_int, *const c_char, c_int, *const c_char, c_uint)
+                                       -> c_int;
+pub type MkdirFunc = extern "C" fn(*const c_char, mode_t) -> c_int;
+pub type MkdiratFunc = extern "C" fn(c_int, *const c_char, mode_t) -> c_int;
+pub type LinkFunc = RenameFunc;
+pub type LinkatFunc = extern "C" fn(c_int, *const c_char, c_int, *const c_char, c_int) -> c_int;
+pub type SymlinkFunc = RenameFunc;
+pub type SymlinkatFunc = extern "C" fn(*const c_char, c_int, *const c_char) -> c_int;
+pub type FsyncFunc = CloseFunc;
 pub type FstatFunc = extern "C" fn(c_int, *const libc::stat) -> c_int;
 pub type SendRecvFunc = extern "C" fn(c_int, *mut c_void, size_t, c_int) -> ssize_t;
+pub type SendToFunc = extern "C" fn(c_int, *const c_void, size_t, c_int, *const sockaddr, socklen_t)
+                                    -> ssize_t;
+pub type RecvFromFunc = extern "C" fn(c_int,
+                                      *mut c_void,
+                                      size_t,
+                                      c_int,
+                                      *mut sockaddr,
+                                      *mut socklen_t)
+                                      -> ssize_t;
+pub type SendMsgFunc = extern "C" fn(c_int, *const libc::msghdr, c_int) -> ssize_t;
+pub type RecvMsgFunc = extern "C" fn(c_int, *mut libc::msghdr, c_int) -> ssize_t;
+pub type SendMmsgFunc = extern "C" fn(c_int, *mut libc::mmsghdr, c_uint, c_int) -> c_int;
+pub type RecvMmsgFunc = extern "C" fn(c_int,
+                                      *mut libc::mmsghdr,
+                                      c_uint,
+                                      c_int,
+                                      *mut libc::timespec)
+                                      -> c_int;
+pub type GetSockOptFunc = extern "C" fn(c_int, c_int, c_int, *mut c_void, *mut socklen_t) -> c_int;
+pub type SetSockOptFunc = extern "C" fn(c_int, c_int, c_int, *const c_void, socklen_t) -> c_int;
+pub type ShutdownFunc = extern "C" fn(c_int, c_int) -> c_int;
+pub type GetSockNameFunc = extern "C" fn(c_int, *mut sockaddr, *mut socklen_t) -> c_int;
+pub type GetPeerNameFunc = GetSockNameFunc;
+pub type PollFunc = extern "C" fn(*mut libc::pollfd, libc::nfds_t, c_int) -> c_int;
+pub type GetAddrInfoFunc = extern "C" fn(*const c_char,
+                                         *const c_char,
+                                         *const libc::addrinfo,
+                                         *mut *mut libc::addrinfo)
+                                         -> c_int;
+pub type GetNameInfoFunc = extern "C" fn(*const sockaddr,
+                                         socklen_t,
+                                         *mut c_char,
+                                         socklen_t,
+                                         *mut c_char,
+                                         socklen_t,
+                                         c_int)
+                                         -> c_int;
+// struct hostent is only ever passed through, so it's left opaque.
+pub type GetHostByNameFunc = extern "C" fn(*const c_char) -> *mut c_void;
+pub type GetHostByNameRFunc = extern "C" fn(*const c_char,
+                                            *mut c_void,
+                                            *mut c_char,
+                                            size_t,
+                                            *mut *mut c_void,
+                                            *mut c_int)
+                                            -> c_int;
+pub type ResQueryFunc = extern "C" fn(*const c_char, c_int, c_int, *mut u8, c_int) -> c_int;
+pub type PpollFunc = extern "C" fn(*mut libc::pollfd,
+                                   libc::nfds_t,
+                                   *const libc::timespec,
+                                   *const libc::sigset_t)
+                                   -> c_int;
+pub type SelectFunc = extern "C" fn(c_int,
+                                    *mut libc::fd_set,
+                                    *mut libc::fd_set,
+                                    *mut libc::fd_set,
+                                    *mut libc::timeval)
+                                    -> c_int;
+pub type PselectFunc = extern "C" fn(c_int,
+                                     *mut libc::fd_set,
+                                     *mut libc::fd_set,
+                                     *mut libc::fd_set,
+                                     *const libc::timespec,
+                                     *const libc::sigset_t)
+                                     -> c_int;
+pub type EpollCtlFunc = extern "C" fn(c_int, c_int, c_int, *mut libc::epoll_event) -> c_int;
+pub type EpollWaitFunc = extern "C" fn(c_int, *mut libc::epoll_event, c_int, c_int) -> c_int;
+pub type EpollPwaitFunc = extern "C" fn(c_int,
+                                        *mut libc::epoll_event,
+                                        c_int,
+                                        c_int,
+                                        *const libc::sigset_t)
+                                        -> c_int;
 
+lazy_static! {
+    // The library asks about sockets itself, and mustn't be failed or lied
+    //   to by its own hooks.
+    pub static ref GETSOCKOPT_FUNC: GetSockOptFunc = get_libc_func!(GetSockOptFunc, "getsockopt");
+    pub static ref GETPEERNAME_FUNC: GetPeerNameFunc =
+        get_libc_func!(GetPeerNameFunc, "getpeername");
+}
+
+/**
+ * @return how long to delay $funcname, drawn from whatever distribution
+ *      applies to what $selector picked.  See `latency::delay_for`.
+ */
 macro_rules! get_delay_amount_ms(
-        ($funcname: expr) =>
+        ($funcname: expr, $selector: expr) =>
     ({
-        use std::env;
-        use std::time::Duration;
+        use latency::delay_for;
 
-        let default_delay_amount : Duration = Duration::from_millis(200);;
-        let err_prefix = "LIBFAULTINJ_DELAY_".to_string();
-        let env_name = err_prefix + &String::from($funcname).to_uppercase() + "_MS";
+        delay_for($funcname, $selector)
+    });
+        ($funcname: expr) =>
+    ({
+        use latency::Selector;
 
-        match env::var(env_name) {
-            Ok(p) => match p.parse::<u64>() {
-                Ok(i) => Duration::from_millis(i),
-                Err(_) => default_delay_amount,
-            },
-            Err(_) => default_delay_amount,
-        }
+        get_delay_amount_ms!($funcname, Selector::Any)
     })
 );
 
@@ -153,44 +281,210 @@ macro_rules! checkErrno(
 
 pub static LIKELIHOOD_CERTAIN_PCT: f32 = 100f32;
 
+/**
+ * @return Some(errno) if an error should be injected into $funcname's
+ *      operation on $fd, None otherwise.  The `matched:` form is for calls
+ *      that are selected by something other than their fd, like the
+ *      destination address of a datagram.
+ */
+macro_rules! injectedErrno(
+        (matched: $matched:expr, $funcname:expr) =>
+    ({
+        use errors::roll_faults;
+
+        if roll_faults($funcname, false, $matched).1 {
+            checkErrno!($funcname)
+        } else {
+            None
+        }
+    });
+        ($fd: expr, $funcname:expr) =>
+    (
+        injectedErrno!(matched: ERR_FDS.read().unwrap().contains(&$fd), $funcname)
+    )
+);
+
 macro_rules! returnError(
-        ($fd: expr, $funcname:expr, $ret_err:expr) =>
+        (matched: $matched:expr, $funcname:expr, $ret_err:expr) =>
     ({
         use errno::set_errno;
-        use errors::get_rand_likelihood;
-        use errors::get_item_likelihood;
 
-        if ERR_FDS.read().unwrap().contains(&$fd) {
-            let err_thresh = get_item_likelihood("LIBFAULTINJ_ERROR_LIKELIHOOD_PCT");
+        if let Some(err) = injectedErrno!(matched: $matched, $funcname) {
+            set_errno(err);
+            return $ret_err;
+        }
+    });
+        ($fd: expr, $funcname:expr, $ret_err:expr) =>
+    (
+        returnError!(matched: ERR_FDS.read().unwrap().contains(&$fd), $funcname, $ret_err)
+    )
+);
 
-            if  get_rand_likelihood() < (err_thresh) {
-                let errno = checkErrno!($funcname);
+pub fn get_item_likelihood(env_var: &str) -> f32 {
+    use self::LIKELIHOOD_CERTAIN_PCT;
 
-                if let Some(err) = errno {
-                    set_errno(err);
-                    return $ret_err;
-                }
+    get_env_value::<f32>(env_var).unwrap_or(LIKELIHOOD_CERTAIN_PCT)
+}
 
-            };
+/**
+ * @return the percent chance that a selected call of `funcname` fails,
+ *      from LIBFAULTINJ_ERROR_<FUNC>_LIKELIHOOD_PCT or else
+ *      LIBFAULTINJ_ERROR_LIKELIHOOD_PCT.
+ */
+pub fn error_likelihood(funcname: &str) -> f32 {
+    let env_name = "LIBFAULTINJ_ERROR_".to_string() + &funcname.to_uppercase() + "_LIKELIHOOD_PCT";
+
+    get_env_value::<f32>(&env_name)
+        .unwrap_or_else(|| get_item_likelihood("LIBFAULTINJ_ERROR_LIKELIHOOD_PCT"))
+}
+
+/**
+ * @return the percent chance that a selected call of `funcname` is
+ *      delayed, from LIBFAULTINJ_DELAY_<FUNC>_LIKELIHOOD_PCT or else
+ *      LIBFAULTINJ_DELAY_LIKELIHOOD_PCT.  Without either, delays follow
+ *      LIBFAULTINJ_ERROR_LIKELIHOOD_PCT as they always have.
+ */
+pub fn delay_likelihood(funcname: &str) -> f32 {
+    let env_name = "LIBFAULTINJ_DELAY_".to_string() + &funcname.to_uppercase() + "_LIKELIHOOD_PCT";
+
+    get_env_value::<f32>(&env_name)
+        .or_else(|| get_env_value::<f32>("LIBFAULTINJ_DELAY_LIKELIHOOD_PCT"))
+        .unwrap_or_else(|| get_item_likelihood("LIBFAULTINJ_ERROR_LIKELIHOOD_PCT"))
+}
+
+/// How the delay and error decisions for a call relate, from
+///   LIBFAULTINJ_FAULT_COMBINE.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Combine {
+    /// Each is decided on its own, so a call may be delayed and then fail.
+    Independent,
+    /// A delayed call never also fails.
+    Exclusive,
+    /// Both are decided by the same roll, so the less likely fault only
+    ///   happens along with the more likely one.
+    Correlated,
+}
+
+impl Combine {
+    pub fn from_env() -> Combine {
+        match ::std::env::var("LIBFAULTINJ_FAULT_COMBINE") {
+            Ok(ref mode) if mode == "exclusive" => Combine::Exclusive,
+            Ok(ref mode) if mode == "correlated" => Combine::Correlated,
+            _ => Combine::Independent,
         }
-    })
-);
+    }
 
-pub fn get_item_likelihood(env_var: &'static str) -> f32 {
-    use std::env;
-    use self::LIKELIHOOD_CERTAIN_PCT;
+    /**
+     * @return whether to delay and whether to fail, given the likelihoods
+     *      of each, or None for one that isn't selected.  `roll` draws a
+     *      percentage.
+     */
+    pub fn decide<R>(self,
+                     delay_pct: Option<f32>,
+                     err_pct: Option<f32>,
+                     mut roll: R)
+                     -> (bool, bool)
+        where R: FnMut() -> f32
+    {
+        let mut happens = |pct: Option<f32>| pct.is_some_and(|pct| roll() < pct);
 
-    match env::var(env_var) {
-        Ok(p) => {
-            match p.parse::<f32>() {
-                Ok(i) => i,
-                Err(_) => LIKELIHOOD_CERTAIN_PCT,
+        match self {
+            Combine::Independent => {
+                let delay = happens(delay_pct);
+                (delay, happens(err_pct))
+            }
+            Combine::Exclusive => {
+                let delay = happens(delay_pct);
+                (delay, !delay && happens(err_pct))
+            }
+            Combine::Correlated => {
+                let shared = roll();
+                (delay_pct.is_some_and(|pct| shared < pct), err_pct.is_some_and(|pct| shared < pct))
             }
         }
-        Err(_) => LIKELIHOOD_CERTAIN_PCT,
     }
 }
 
+/**
+ * @return whether a call of `funcname` is delayed and whether it fails,
+ *      when it's been selected for delays and errors respectively.
+ */
+pub fn roll_faults(funcname: &str, delay_matched: bool, err_matched: bool) -> (bool, bool) {
+    if !delay_matched && !err_matched {
+        return (false, false);
+    }
+
+    let delay_pct = if delay_matched {
+        Some(delay_likelihood(funcname))
+    } else {
+        None
+    };
+    let err_pct = if err_matched {
+        Some(error_likelihood(funcname))
+    } else {
+        None
+    };
+    Combine::from_env().decide(delay_pct, err_pct, get_rand_likelihood)
+}
+
+/**
+ * @return std::env::var($env_name) parsed as a T, or None if it's not set
+ *      or can't be parsed.
+ */
+pub fn get_env_value<T: ::std::str::FromStr>(env_name: &str) -> Option<T> {
+    use std::env;
+
+    match env::var(env_name) {
+        Ok(p) => p.trim().parse::<T>().ok(),
+        Err(_) => None,
+    }
+}
+
+/**
+ * @return the SO_TYPE of socket $fd, or None if it's not a socket.
+ */
+pub fn get_socket_type(fd: c_int) -> Option<c_int> {
+    use std::mem;
+    use kind::fd_kind;
+
+    // Spares a syscall for fds the hooks saw made.
+    if let Some(kind) = fd_kind(fd) {
+        return kind.socket_kind().map(|kind| kind.type_);
+    }
+
+    let mut sock_type: c_int = 0;
+    let mut len = mem::size_of::<c_int>() as socklen_t;
+
+    let ret = GETSOCKOPT_FUNC(fd,
+                              libc::SOL_SOCKET,
+                              libc::SO_TYPE,
+                              &mut sock_type as *mut c_int as *mut c_void,
+                              &mut len);
+    if ret == 0 {
+        Some(sock_type)
+    } else {
+        None
+    }
+}
+
+/**
+ * @return true $env_name percent of the time, never if it's not set.
+ */
+pub fn chance(env_name: &str) -> bool {
+    match get_env_value::<f32>(env_name) {
+        Some(pct) => get_rand_likelihood() < pct,
+        None => false,
+    }
+}
+
+/**
+ * @return true if `fd` has O_NONBLOCK set.
+ */
+pub fn is_nonblocking(fd: c_int) -> bool {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    flags >= 0 && flags & libc::O_NONBLOCK != 0
+}
+
 pub fn get_rand_likelihood() -> f32 {
     use rand;
     use rand::Rng;
@@ -200,21 +494,127 @@ pub fn get_rand_likelihood() -> f32 {
     rng.gen_range::<f32>(0., LIKELIHOOD_CERTAIN_PCT)
 }
 
-macro_rules! injectFaults(
+/**
+ * Sleeps if $funcname should be delayed, with the delay and error decided
+ *   together as LIBFAULTINJ_FAULT_COMBINE says.  Non-blocking fds aren't
+ *   slept on but fail with EAGAIN, see `nonblock`.  The `matched:` form
+ *   takes the `latency::Selector` that picked the call.
+ *
+ * @return Some(errno) if an error should then be injected, the delay was
+ *      put off, or a hang was interrupted, None otherwise.
+ */
+macro_rules! delayedErrno(
+        (matched: $err_match:expr, $delay_match:expr, $funcname:expr, $selector:expr) =>
+        ({
+            use errno::Errno;
+            use errors::roll_faults;
+            use latency::delay_call;
+            use nonblock::{Deferred, deferred};
+
+            let deferral = deferred($funcname, $selector);
+            let (delay, error) = match deferral {
+                Deferred::No => roll_faults($funcname, $delay_match, $err_match),
+                Deferred::Waiting => (false, false),
+                Deferred::Done => (false, roll_faults($funcname, false, $err_match).1),
+            };
+            let interrupted = if deferral == Deferred::Waiting {
+                Some(libc::EAGAIN)
+            } else if delay {
+                delay_call($funcname, $selector)
+            } else {
+                None
+            };
+            if interrupted.is_some() {
+                interrupted.map(Errno)
+            } else if error {
+                checkErrno!($funcname)
+            } else {
+                None
+            }
+        });
+        ($fd: expr, $funcname:expr) =>
+        ({
+            use latency::Selector;
+
+            delayedErrno!(matched: ERR_FDS.read().unwrap().contains(&$fd),
+                          DELAY_FDS.read().unwrap().contains(&$fd),
+                          $funcname,
+                          Selector::Fd($fd))
+        }));
+
+/**
+ * Sleeps if $funcname should be delayed, returning $err from the hook if
+ *   a hang is interrupted.  The `matched:` forms are for sockets selected
+ *   by address, and `socket:` names the socket when it's selected itself.
+ */
+macro_rules! injectDelay(
+        (matched: $matched:expr, socket: $sockfd:expr, $funcname:expr, $err:expr) =>
+        ({
+            use errno::set_errno;
+            use errors::socket_selector;
+
+            if let Some(err) = delayedErrno!(matched: false, $matched, $funcname,
+                                             socket_selector($sockfd)) {
+                set_errno(err);
+                return $err;
+            }
+        });
+        (matched: $matched:expr, $funcname:expr, $err:expr) =>
+        ({
+            use errno::set_errno;
+            use latency::Selector;
+
+            if let Some(err) = delayedErrno!(matched: false, $matched, $funcname, Selector::Addr) {
+                set_errno(err);
+                return $err;
+            }
+        });
         ($fd: expr, $funcname:expr, $err:expr) =>
         ({
-            use std::thread::sleep;
-            use errors::get_item_likelihood;
-            use errors::get_rand_likelihood;
+            use errno::set_errno;
+            use latency::Selector;
+
+            if let Some(err) = delayedErrno!(matched: false,
+                                             DELAY_FDS.read().unwrap().contains(&$fd),
+                                             $funcname,
+                                             Selector::Fd($fd)) {
+                set_errno(err);
+                return $err;
+            }
+        }));
 
-            let delay_match = DELAY_FDS.read().unwrap().contains(&$fd);
-            let delay_likelihood =  get_item_likelihood("LIBFAULTINJ_ERROR_LIKELIHOOD_PCT");
+macro_rules! injectFaults(
+        (matched: $err_match:expr, $delay_match:expr, socket: $sockfd:expr, $funcname:expr,
+         $err:expr) =>
+        ({
+            use errno::set_errno;
+            use errors::socket_selector;
 
-            if delay_match && (get_rand_likelihood() < delay_likelihood) {
-                sleep(get_delay_amount_ms!($funcname));
+            if let Some(err) = delayedErrno!(matched: $err_match, $delay_match, $funcname,
+                                             socket_selector($sockfd)) {
+                set_errno(err);
+                return $err;
             }
+        });
+        (matched: $err_match:expr, $delay_match:expr, $funcname:expr, $err:expr) =>
+        ({
+            use errno::set_errno;
+            use latency::Selector;
 
-            returnError!($fd, $funcname, $err);
+            if let Some(err) = delayedErrno!(matched: $err_match, $delay_match, $funcname,
+                                             Selector::Addr) {
+                set_errno(err);
+                return $err;
+            }
+        });
+        ($fd: expr, $funcname:expr, $err:expr) =>
+        ({
+            use errno::set_errno;
+
+            if let Some(err) = delayedErrno!($fd, $funcname) {
+                set_errno(err);
+                return $err;
+            }
         }));
 
 /**
@@ -241,37 +641,58 @@ macro_rules! matchesPath(
     }));
 
 /**
- * @return true if $addr matches any of the addresses specified by
- *      std::env::var($env_name), false otherwise.
+ * @return true if $addr matches any of the address selectors specified by
+ *      std::env::var($env_name), false otherwise.  See `addr::AddrSelector`
+ *      for the selector syntax.
  */
 pub unsafe fn matches_addr(addr: *const libc::sockaddr, env_name: &str) -> bool {
-    use std::env;
-    use std::net::ToSocketAddrs;
-    use std::net::{SocketAddrV4, Ipv4Addr, IpAddr};
+    use addr::sockaddr_to_socket_addr;
 
-    let addr_ = {
-        let ptr: *const libc::sockaddr = addr;
-        *(ptr as *const libc::sockaddr_in)
-    };
+    match sockaddr_to_socket_addr(addr) {
+        Some(socket_addr) => matches_socket_addr(&socket_addr, env_name),
+        None => false,
+    }
+}
 
-    let s_addr = addr_.sin_addr.s_addr;
-    let ipv4_addr = SocketAddrV4::new(Ipv4Addr::new((s_addr & 0x0000000ff) as u8,
-                                                    ((s_addr & 0x00000ff00) >> 8) as u8,
-                                                    ((s_addr & 0x000ff0000) >> 16) as u8,
-                                                    ((s_addr & 0x0ff000000) >> 24) as u8),
-                                      addr_.sin_port);
+/**
+ * @return true if $fd is in $fds or if $addr, the address of a datagram
+ *      sent or received on an unconnected socket, matches the selectors
+ *      in std::env::var($env_name).
+ */
+pub unsafe fn fd_or_addr_matches(fds: &RwLock<AlternateHashSet>,
+                                 fd: c_int,
+                                 addr: *const libc::sockaddr,
+                                 env_name: &str)
+                                 -> bool {
+    fds.read().unwrap().contains(&fd) || matches_addr(addr, env_name)
+}
 
-    let socket_addr = ToSocketAddrs::to_socket_addrs(&ipv4_addr).unwrap();
-    let addr_to_match = socket_addr.clone().next().unwrap();
-    use std::str::FromStr;
+/**
+ * @return the selector that delays a call on the socket `fd`, matched by
+ *      its own selection or by address: the fd's if it's selected, so a
+ *      non-blocking one's delay is put off, see `nonblock`.
+ */
+pub fn socket_selector(fd: c_int) -> Selector {
+    if DELAY_FDS.read().unwrap().contains(&fd) {
+        Selector::Fd(fd)
+    } else {
+        Selector::Addr
+    }
+}
+
+pub fn matches_peer_addr(peer: &PeerAddrBuf, env_name: &str) -> bool {
+    match peer.socket_addr() {
+        Some(socket_addr) => matches_socket_addr(&socket_addr, env_name),
+        None => false,
+    }
+}
+
+pub fn matches_socket_addr(addr: &SocketAddr, env_name: &str) -> bool {
+    use std::env;
+    use addr::resolved_selectors;
 
     match env::var(env_name) {
-        Ok(p) => {
-            match IpAddr::from_str(p.as_ref()) {
-                Ok(ip_addr) => ip_addr == addr_to_match.ip(),
-                Err(_) => false,
-            }
-        }
+        Ok(p) => resolved_selectors(&p).iter().any(|selector| selector.matches(addr)),
         Err(_) => false,
     }
 }
@@ -282,11 +703,27 @@ pub unsafe fn matches_addr(addr: *const libc::sockaddr, env_name: &str) -> bool
 macro_rules! do_open(
     ($filename_:expr, $flags:expr, $mode:expr) =>
     ({
+        if oom::suspended() {
+            return get_libc_func!(OpenFunc, "open")($filename_, $flags, $mode);
+        }
+
         let filename: String = unsafe {
             std::ffi::CStr::from_ptr($filename_).to_string_lossy().into_owned()
         };
+        const WRITE_FLAGS: c_int = libc::O_WRONLY | libc::O_RDWR | libc::O_CREAT | libc::O_TRUNC;
+        if $flags & WRITE_FLAGS != 0 && fsinfo::read_only_path(std::path::Path::new(&filename)) {
+            errno::set_errno(errno::Errno(libc::EROFS));
+            return -1;
+        }
+
+        checkFdLimit!(1, -1);
+
+        let truncated = quota::size_before_open(&filename, $flags);
         let open_func = get_libc_func!(OpenFunc, "open");
         let fd: c_int = open_func($filename_, $flags, $mode);
+        quota::track_fd(fd, &filename, truncated);
+        track_open_fd(fd);
+        kind::track_opened(fd);
 
         if matchesPath!(filename, "LIBFAULTINJ_ERROR_PATH") {
             ERR_FDS.write().unwrap().insert(fd);
@@ -304,6 +741,85 @@ macro_rules! do_open(
     );
 
 
+/**
+ * Accepted connections inherit the faults of their listening socket, and
+ *   are also selected on their own when the peer's address matches
+ *   LIBFAULTINJ_{ERROR,DELAY}_ADDR.
+ */
+macro_rules! do_accept(
+    ($sockfd:expr, $funcname:expr, $accept_call:expr) =>
+    ({
+        use errno::set_errno;
+        use errors::{is_nonblocking, track_accepted_fd};
+
+        if let Some(err) = delayedErrno!($sockfd, $funcname) {
+            if err.0 == libc::ECONNABORTED && is_nonblocking($sockfd) {
+                // The kernel takes an aborted connection off of the
+                //   accept queue, so the one pending here goes too.  A
+                //   blocking listener would wait for a real client just to
+                //   drop it, so its queue is left alone.
+                let aborted_fd: c_int = $accept_call;
+                if aborted_fd >= 0 {
+                    close(aborted_fd);
+                }
+            }
+            set_errno(err);
+            return -1;
+        }
+        checkStorm!($sockfd, $funcname, 0, -1);
+        checkFdLimit!(1, -1);
+
+        let fd: c_int = $accept_call;
+        if fd >= 0 {
+            track_accepted_fd($sockfd, fd);
+        }
+
+        fd
+    })
+    );
+
+pub fn track_accepted_fd(listen_fd: c_int, fd: c_int) {
+    use std::mem;
+    use kind;
+
+    let mut err_match = ERR_FDS.read().unwrap().contains(&listen_fd);
+    let mut delay_match = DELAY_FDS.read().unwrap().contains(&listen_fd);
+
+    let mut peer: libc::sockaddr_storage = unsafe { mem::zeroed() };
+    let mut peer_len = mem::size_of::<libc::sockaddr_storage>() as socklen_t;
+    let peer_ptr = &mut peer as *mut libc::sockaddr_storage as *mut sockaddr;
+
+    if GETPEERNAME_FUNC(fd, peer_ptr, &mut peer_len) == 0 {
+        err_match |= unsafe { matches_addr(peer_ptr, "LIBFAULTINJ_ERROR_ADDR") };
+        delay_match |= unsafe { matches_addr(peer_ptr, "LIBFAULTINJ_DELAY_ADDR") };
+    }
+
+    track_new_fd(fd, err_match, delay_match);
+    kind::dup_fd(listen_fd, fd);
+}
+
+/**
+ * Tracks `fd`, just made by a hook, and selects it for errors and delays
+ *   as `err_match` and `delay_match` say.
+ */
+pub fn track_new_fd(fd: c_int, err_match: bool, delay_match: bool) {
+    if fd < 0 {
+        return;
+    }
+    // A stale entry might remain if the fd was closed behind our back, so
+    //   the fd starts out selected for what it's made as and nothing else.
+    remove_fd_if_present(fd);
+    track_open_fd(fd);
+
+    if err_match {
+        ERR_FDS.write().unwrap().insert(fd);
+    }
+
+    if delay_match {
+        DELAY_FDS.write().unwrap().insert(fd);
+    }
+}
+
 pub fn remove_fd_if_present(fd: c_int) {
     let mut err_fds = ERR_FDS.write().unwrap();
     if err_fds.contains(&fd) {
@@ -317,6 +833,64 @@ pub fn remove_fd_if_present(fd: c_int) {
 
 }
 
+fn is_open(fd: c_int) -> bool {
+    unsafe { libc::fcntl(fd, libc::F_GETFD) != -1 }
+}
+
+/**
+ * @return the fds open now, from /proc/self/fd.
+ */
+pub fn open_fds() -> Vec<c_int> {
+    let listed: Vec<c_int> = match fs::read_dir("/proc/self/fd") {
+        Ok(entries) => {
+            entries.filter_map(|entry| entry.ok()?.file_name().to_str()?.parse().ok()).collect()
+        }
+        Err(_) => Vec::new(),
+    };
+    // The directory was itself open while it was read.
+    listed.into_iter().filter(|&fd| is_open(fd)).collect()
+}
+
+/**
+ * Counts `fd`, if a call made it.
+ */
+pub fn track_open_fd(fd: c_int) {
+    if fd < 0 || !COUNTING.load(Ordering::Relaxed) {
+        return;
+    }
+    OPEN_FDS.lock().unwrap().insert(fd);
+}
+
+/**
+ * Forgets `fd`, and whatever it was selected for, as it's closed.
+ */
+pub fn forget_fd(fd: c_int) {
+    remove_fd_if_present(fd);
+    if COUNTING.load(Ordering::Relaxed) {
+        OPEN_FDS.lock().unwrap().remove(&fd);
+    }
+}
+
+/**
+ * @return how many fds are open.  With `weed`, those closed inside libc,
+ *      by fclose() say, which never reach the close() hook, are forgotten
+ *      first.
+ */
+pub fn open_fd_count(weed: bool) -> usize {
+    let mut open_fds = OPEN_FDS.lock().unwrap();
+    if !COUNTING.swap(true, Ordering::Relaxed) {
+        open_fds.extend(self::open_fds());
+    }
+    if weed {
+        let closed: Vec<c_int> = open_fds.iter().cloned().filter(|&fd| !is_open(fd)).collect();
+        for fd in closed {
+            open_fds.remove(&fd);
+            remove_fd_if_present(fd);
+        }
+    }
+    open_fds.len()
+}
+
 pub fn add_fd_if_old_present(oldfd: c_int, newfd: c_int) {
     let mut err_fds = ERR_FDS.write().unwrap();
     if err_fds.contains(&oldfd) {
@@ -342,10 +916,10 @@ mod test {
         use std::net::Ipv4Addr;
         use std::mem;
 
-        let ip = Ipv4Addr::new(1, 0, 0, 127); // FIXME lame hack do ntohl instead
+        let ip = Ipv4Addr::new(127, 0, 0, 1);
         let sock_ = libc::sockaddr_in {
-            sin_port: 0,
-            sin_addr: libc::in_addr { s_addr: libc::in_addr_t::from(ip) },
+            sin_port: 6379u16.to_be(),
+            sin_addr: libc::in_addr { s_addr: u32::from(ip).to_be() },
             sin_family: libc::AF_INET as u16,
             sin_zero: [0 as u8; 8],
         };
@@ -354,6 +928,12 @@ mod test {
 
         env::set_var("TEST_ADDR", "127.0.0.1");
         assert!(unsafe { matches_addr(sock, "TEST_ADDR") });
+
+        env::set_var("TEST_ADDR", "*:5432,127.0.0.0/8:6379");
+        assert!(unsafe { matches_addr(sock, "TEST_ADDR") });
+
+        env::set_var("TEST_ADDR", "*:5432");
+        assert!(!unsafe { matches_addr(sock, "TEST_ADDR") });
     }
 
     #[test]
@@ -404,4 +984,33 @@ mod test {
         env::set_var("LIBFAULTINJ_ERROR_PATH", bard_x_path.to_str().unwrap());
         assert!(matchesPath!(bard_x_path, "LIBFAULTINJ_ERROR_PATH"));
     }
+
+    #[test]
+    fn test_likelihood() {
+        use super::{Combine, delay_likelihood, error_likelihood};
+
+        env::set_var("LIBFAULTINJ_ERROR_LIKELIHOODTEST_LIKELIHOOD_PCT", "25");
+        env::set_var("LIBFAULTINJ_DELAY_LIKELIHOODTEST_LIKELIHOOD_PCT", "75");
+        assert_eq!(error_likelihood("likelihoodtest"), 25.);
+        assert_eq!(delay_likelihood("likelihoodtest"), 75.);
+        env::remove_var("LIBFAULTINJ_ERROR_LIKELIHOODTEST_LIKELIHOOD_PCT");
+        env::remove_var("LIBFAULTINJ_DELAY_LIKELIHOODTEST_LIKELIHOOD_PCT");
+
+        let rolls = |rolls: Vec<f32>| {
+            let mut rolls = rolls.into_iter();
+            move || rolls.next().unwrap()
+        };
+        let (delay, err) = (Some(50.), Some(30.));
+
+        assert_eq!(Combine::Independent.decide(delay, err, rolls(vec![10., 20.])), (true, true));
+        assert_eq!(Combine::Independent.decide(delay, err, rolls(vec![60., 20.])), (false, true));
+        assert_eq!(Combine::Independent.decide(None, err, rolls(vec![20.])), (false, true));
+
+        assert_eq!(Combine::Exclusive.decide(delay, err, rolls(vec![10.])), (true, false));
+        assert_eq!(Combine::Exclusive.decide(delay, err, rolls(vec![60., 20.])), (false, true));
+
+        assert_eq!(Combine::Correlated.decide(delay, err, rolls(vec![20.])), (true, true));
+        assert_eq!(Combine::Correlated.decide(delay, err, rolls(vec![40.])), (true, false));
+        assert_eq!(Combine::Correlated.decide(None, err, rolls(vec![20.])), (false, true));
+    }
 }
@@ -7,33 +7,86 @@ extern crate dylib;
 extern crate lazy_static;
 
 
-pub use libc::{c_char, c_int, c_ulong, c_void, off_t, size_t, mode_t, ssize_t};
+pub use libc::{c_char, c_int, c_uint, c_ulong, c_void, off_t, size_t, mode_t, ssize_t};
 
 #[macro_use]
 mod errors;
-use errors::{OpenFunc, ReadFunc, WriteFunc, SeekFunc, CloseFunc, MmapFunc, Dup2Func, Dup3Func,
-             IoctlFunc, BindFunc, StatFunc, FstatFunc, SocketFunc, ConnectFunc, SendRecvFunc,
+mod addr;
+mod dns;
+mod dgram;
+mod latency;
+mod hang;
+mod nonblock;
+#[macro_use]
+mod storm;
+#[macro_use]
+mod ready;
+#[macro_use]
+mod conn;
+mod throttle;
+#[macro_use]
+mod quota;
+#[macro_use]
+mod namespace;
+mod durable;
+#[macro_use]
+mod fsinfo;
+mod oom;
+#[macro_use]
+mod fdlimit;
+mod kind;
+mod sockopt;
+mod ioreq;
+mod inherited;
+use errors::{OpenFunc, ReadFunc, WriteFunc, SeekFunc, CloseFunc, Dup2Func, Dup3Func, IoctlFunc,
+             BindFunc, StatFunc, FstatFunc, SocketFunc, ConnectFunc, SendRecvFunc,
+             AcceptFunc, Accept4Func, SendToFunc, RecvFromFunc, SendMsgFunc, RecvMsgFunc,
+             SendMmsgFunc, RecvMmsgFunc, GetSockOptFunc, PollFunc, PpollFunc,
+             SelectFunc, PselectFunc, EpollWaitFunc, EpollPwaitFunc, GetAddrInfoFunc,
+             GetNameInfoFunc, GetHostByNameFunc, GetHostByNameRFunc, ResQueryFunc, StatfsFunc,
+             Statfs64Func, FstatfsFunc, Fstatfs64Func, FtruncateFunc, FallocateFunc, UnlinkFunc,
+             UnlinkatFunc, RmdirFunc, RenameFunc, RenameatFunc, Renameat2Func, MkdirFunc,
+             MkdiratFunc, LinkFunc, LinkatFunc, SymlinkFunc, SymlinkatFunc, FsyncFunc,
+             TruncateFunc, PosixFallocateFunc, CopyFileRangeFunc, SendfileFunc, SpliceFunc,
+             TeeFunc, StatvfsFunc, Statvfs64Func, FstatvfsFunc, Fstatvfs64Func, DupFunc,
+             PipeFunc, Pipe2Func, EventfdFunc, EpollCreateFunc, SetSockOptFunc, ShutdownFunc,
+             GetSockNameFunc, GetPeerNameFunc, SocketPairFunc, SignalfdFunc, TimerfdCreateFunc,
              ERR_FDS, DELAY_FDS};
-use self::errors::matches_addr;
-use errors::{remove_fd_if_present, add_fd_if_old_present};
+use self::errors::{matches_addr, matches_peer_addr, fd_or_addr_matches};
+use addr::PeerAddrBuf;
+use dgram::Datagram;
+use conn::Direction;
+use dns::Lookup;
+use namespace::{at_path, c_path};
+use kind::{FdKind, SocketKind};
+use errors::{add_fd_if_old_present, track_new_fd, track_open_fd};
+
+use std::{cmp, ptr, slice};
 
 // These functions are designed to conform to their
 //  libc counterparts, but may instead inject errors
 //  depending on conditions defined in various environment
 //  variables.
+//
+// Like their counterparts, they take raw pointers from C callers
+//  and can't be unsafe, so the ones that use them allow
+//  clippy::not_unsafe_ptr_arg_deref.
 
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn open64(filename_: *const c_char, flags: c_int, mode: mode_t) -> c_int {
     do_open!(filename_, flags, mode)
 }
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn open(filename_: *const c_char, flags: c_int, mode: mode_t) -> c_int {
     do_open!(filename_, flags, mode)
 }
 
 #[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn creat(filename_: *const c_char, mode: mode_t) -> c_int {
     const FLAGS: c_int = libc::O_CREAT | libc::O_WRONLY | libc::O_TRUNC;
 
@@ -45,15 +98,22 @@ pub extern "C" fn creat(filename_: *const c_char, mode: mode_t) -> c_int {
 const SSIZE_ERR: ssize_t = -1isize;
 
 #[no_mangle]
-pub extern "C" fn read(fd: c_int, buf: *mut c_void, nbytes: c_int) -> ssize_t {
+pub extern "C" fn read(fd: c_int, buf: *mut c_void, nbytes: size_t) -> ssize_t {
     lazy_static! {
         static ref READ_FUNC: ReadFunc = get_libc_func!(ReadFunc, "read");
     }
 
     injectFaults!(fd, "read", SSIZE_ERR);
+    checkStorm!(fd, "read", 0, SSIZE_ERR);
+    checkSpurious!(fd, SSIZE_ERR);
+    let nbytes = checkConnection!(fd, Direction::Recv, nbytes, 0);
+    let nbytes = throttle::limit(fd, nbytes);
 
+    let ret = READ_FUNC(fd, buf, nbytes);
+    conn::after_io(fd, ret);
+    throttle::after_io(fd, ret);
 
-    READ_FUNC(fd, buf, nbytes)
+    ret
 }
 
 #[no_mangle]
@@ -80,14 +140,24 @@ pub extern "C" fn lseek64(fd: c_int, offset: off_t, whence: c_int) -> off_t {
 
 
 #[no_mangle]
-pub extern "C" fn write(fd: c_int, buf: *mut c_void, nbytes: c_int) -> ssize_t {
+pub extern "C" fn write(fd: c_int, buf: *mut c_void, nbytes: size_t) -> ssize_t {
     lazy_static! {
         static ref WRITE_FUNC: WriteFunc = get_libc_func!(WriteFunc, "write");
     }
 
     injectFaults!(fd, "write", SSIZE_ERR);
-
-    WRITE_FUNC(fd, buf, nbytes)
+    checkReadOnly!(fd, SSIZE_ERR);
+    checkStorm!(fd, "write", 0, SSIZE_ERR);
+    let nbytes = checkConnection!(fd, Direction::Send, nbytes, 0);
+    let nbytes = throttle::limit(fd, nbytes);
+    let (nbytes, growth) = checkQuota!(quota::limit_write(fd, nbytes), SSIZE_ERR);
+
+    let ret = WRITE_FUNC(fd, buf, nbytes);
+    conn::after_io(fd, ret);
+    throttle::after_io(fd, ret);
+    quota::charge(growth);
+
+    ret
 }
 
 
@@ -97,7 +167,15 @@ pub extern "C" fn close(fd: c_int) -> c_int {
         static ref CLOSE_FUNC: CloseFunc = get_libc_func!(CloseFunc, "close");
     }
 
-    remove_fd_if_present(fd);
+    errors::forget_fd(fd);
+    dgram::flush_held(fd);
+    conn::forget_fd(fd);
+    ready::forget_fd(fd);
+    storm::forget_fd(fd);
+    throttle::forget_fd(fd);
+    nonblock::forget_fd(fd);
+    quota::forget_fd(fd);
+    kind::forget_fd(fd);
 
     CLOSE_FUNC(fd)
 }
@@ -111,8 +189,13 @@ pub extern "C" fn dup2(oldfd: c_int, newfd: c_int) -> c_int {
     }
 
     add_fd_if_old_present(oldfd, newfd);
+    quota::dup_fd(oldfd, newfd);
+
+    let ret = DUP2_FUNC(oldfd, newfd);
+    track_open_fd(ret);
+    kind::dup_fd(oldfd, ret);
 
-    DUP2_FUNC(oldfd, newfd)
+    ret
 }
 
 // For now we don't intercept this call for error injection,
@@ -124,38 +207,473 @@ pub extern "C" fn dup3(oldfd: c_int, newfd: c_int, flags: c_int) -> c_int {
     }
 
     add_fd_if_old_present(oldfd, newfd);
+    quota::dup_fd(oldfd, newfd);
+
+    let ret = DUP3_FUNC(oldfd, newfd, flags);
+    track_open_fd(ret);
+    kind::dup_fd(oldfd, ret);
+
+    ret
+}
+
+#[no_mangle]
+pub extern "C" fn dup(oldfd: c_int) -> c_int {
+    lazy_static! {
+        static ref DUP_FUNC: DupFunc = get_libc_func!(DupFunc, "dup");
+    }
+
+    checkFdLimit!(1, -1);
+
+    let fd = DUP_FUNC(oldfd);
+    if fd >= 0 {
+        add_fd_if_old_present(oldfd, fd);
+        quota::dup_fd(oldfd, fd);
+        track_open_fd(fd);
+        kind::dup_fd(oldfd, fd);
+    }
+
+    fd
+}
+
+#[no_mangle]
+pub extern "C" fn pipe(pipefd: *mut c_int) -> c_int {
+    lazy_static! {
+        static ref PIPE_FUNC: PipeFunc = get_libc_func!(PipeFunc, "pipe");
+    }
+
+    checkFdLimit!(2, -1);
+
+    let ret = PIPE_FUNC(pipefd);
+    if ret == 0 {
+        track_pair(pipefd, FdKind::Pipe);
+    }
+
+    ret
+}
+
+#[no_mangle]
+pub extern "C" fn pipe2(pipefd: *mut c_int, flags: c_int) -> c_int {
+    lazy_static! {
+        static ref PIPE2_FUNC: Pipe2Func = get_libc_func!(Pipe2Func, "pipe2");
+    }
+
+    checkFdLimit!(2, -1);
+
+    let ret = PIPE2_FUNC(pipefd, flags);
+    if ret == 0 {
+        track_pair(pipefd, FdKind::Pipe);
+    }
+
+    ret
+}
+
+/**
+ * Tracks both ends of a new pipe or socketpair, as `kind`.
+ */
+fn track_pair(fds: *const c_int, kind: FdKind) {
+    let fds = unsafe { slice::from_raw_parts(fds, 2) };
+    for &fd in fds {
+        track_new_fd(fd, false, false);
+        kind::track_fd(fd, kind);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn eventfd(initval: c_uint, flags: c_int) -> c_int {
+    lazy_static! {
+        static ref EVENTFD_FUNC: EventfdFunc = get_libc_func!(EventfdFunc, "eventfd");
+    }
+
+    checkFdLimit!(1, -1);
+
+    let fd = EVENTFD_FUNC(initval, flags);
+    track_new_fd(fd, false, false);
+    kind::track_fd(fd, FdKind::Eventfd);
+
+    fd
+}
+
+#[no_mangle]
+pub extern "C" fn signalfd(fd: c_int, mask: *const libc::sigset_t, flags: c_int) -> c_int {
+    lazy_static! {
+        static ref SIGNALFD_FUNC: SignalfdFunc = get_libc_func!(SignalfdFunc, "signalfd");
+    }
+
+    // Given an fd, it only changes the signals that one takes.
+    if fd != -1 {
+        return SIGNALFD_FUNC(fd, mask, flags);
+    }
+    checkFdLimit!(1, -1);
+
+    let new_fd = SIGNALFD_FUNC(fd, mask, flags);
+    track_new_fd(new_fd, false, false);
+    kind::track_fd(new_fd, FdKind::Signalfd);
+
+    new_fd
+}
+
+#[no_mangle]
+pub extern "C" fn timerfd_create(clockid: libc::clockid_t, flags: c_int) -> c_int {
+    lazy_static! {
+        static ref TIMERFD_CREATE_FUNC: TimerfdCreateFunc =
+            get_libc_func!(TimerfdCreateFunc, "timerfd_create");
+    }
 
-    DUP3_FUNC(oldfd, newfd, flags)
+    checkFdLimit!(1, -1);
+
+    let fd = TIMERFD_CREATE_FUNC(clockid, flags);
+    track_new_fd(fd, false, false);
+    kind::track_fd(fd, FdKind::Timerfd);
+
+    fd
 }
 
+// A variadic argument that goes on the stack isn't where the ioctl hook
+//   looks for its third argument.
+#[cfg(all(target_arch = "aarch64", target_vendor = "apple"))]
+compile_error!("the ioctl hook needs variadic arguments passed like named ones");
+
+/**
+ * Takes ioctl()'s variadic argument as the `*mut c_void` word it arrives in,
+ *   since x86_64 and aarch64 linux pass an int or pointer through `...` in
+ *   the same register as a named third argument.  See `ioreq` for the faults.
+ */
 #[no_mangle]
-pub extern "C" fn ioctl(fd: c_int, req: c_ulong, argp: *mut c_char) -> c_int {
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn ioctl(fd: c_int, req: c_ulong, argp: *mut c_void) -> c_int {
     lazy_static! {
         static ref IOCTL_FUNC: IoctlFunc = get_libc_func!(IoctlFunc, "ioctl");
     }
 
-    injectFaults!(fd, "ioctl", -1 as c_int);
+    if ioreq::request_selected(req) {
+        injectFaults!(fd, "ioctl", -1 as c_int);
+    }
+    if let Some(ret) = unsafe { ioreq::fake(fd, req, argp) } {
+        return ret;
+    }
 
     IOCTL_FUNC(fd, req, argp)
 }
 
 use libc::sockaddr;
-use self::errors::socklen_t;
+use libc::socklen_t;
+
+/**
+ * Sockets are selected by their kind when they're made, see `kind`.
+ */
+#[no_mangle]
+pub extern "C" fn socket(domain: c_int, type_: c_int, protocol: c_int) -> c_int {
+    lazy_static! {
+        static ref SOCKET_FUNC: SocketFunc = get_libc_func!(SocketFunc, "socket");
+    }
+
+    let kind = SocketKind::new(domain, type_, protocol);
+    let err_match = kind::matches_kind(&kind, "LIBFAULTINJ_ERROR_SOCKET_KIND");
+    let delay_match = kind::matches_kind(&kind, "LIBFAULTINJ_DELAY_SOCKET_KIND");
+
+    injectFaults!(matched: err_match, delay_match, "socket", -1);
+    checkFdLimit!(1, -1);
+
+    let fd = SOCKET_FUNC(domain, type_, protocol);
+    track_new_fd(fd, err_match, delay_match);
+    kind::track_fd(fd, FdKind::Socket(kind));
+
+    fd
+}
+
 #[no_mangle]
+pub extern "C" fn socketpair(domain: c_int, type_: c_int, protocol: c_int, sv: *mut c_int)
+                             -> c_int {
+    lazy_static! {
+        static ref SOCKETPAIR_FUNC: SocketPairFunc = get_libc_func!(SocketPairFunc, "socketpair");
+    }
+
+    checkFdLimit!(2, -1);
+
+    let ret = SOCKETPAIR_FUNC(domain, type_, protocol, sv);
+    if ret == 0 {
+        track_pair(sv, FdKind::SocketPair(SocketKind::new(domain, type_, protocol)));
+    }
+
+    ret
+}
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
 pub extern "C" fn connect(sockfd: c_int, addr: *const sockaddr, addrlen: socklen_t) -> c_int {
     lazy_static! {
         static ref CONNECT_FUNC: ConnectFunc = get_libc_func!(ConnectFunc, "connect");
     }
 
-    if unsafe { matches_addr(addr, "LIBFAULTINJ_ERROR_PATH") } {
+    if unsafe { matches_addr(addr, "LIBFAULTINJ_ERROR_ADDR") } {
         ERR_FDS.write().unwrap().insert(sockfd);
     }
 
-    if unsafe { matches_addr(addr, "LIBFAULTINJ_DELAY_PATH") } {
+    if unsafe { matches_addr(addr, "LIBFAULTINJ_DELAY_ADDR") } {
         DELAY_FDS.write().unwrap().insert(sockfd);
     }
 
-    CONNECT_FUNC(sockfd, addr, addrlen)
+    conn::connect(sockfd, || CONNECT_FUNC(sockfd, addr, addrlen))
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn getsockopt(sockfd: c_int,
+                             level: c_int,
+                             optname: c_int,
+                             optval: *mut c_void,
+                             optlen: *mut socklen_t)
+                             -> c_int {
+    lazy_static! {
+        static ref GETSOCKOPT_FUNC: GetSockOptFunc = get_libc_func!(GetSockOptFunc, "getsockopt");
+    }
+
+    if level == libc::SOL_SOCKET && optname == libc::SO_ERROR {
+        if let Some(ret) = unsafe { conn::getsockopt_so_error(sockfd, optval, optlen) } {
+            return ret;
+        }
+    }
+    if sockopt::option_selected(level, optname) {
+        injectFaults!(sockfd, "getsockopt", -1);
+    }
+
+    let ret = GETSOCKOPT_FUNC(sockfd, level, optname, optval, optlen);
+    if ret == 0 {
+        unsafe { sockopt::lie(sockfd, level, optname, optval, optlen) };
+    }
+
+    ret
+}
+
+#[no_mangle]
+pub extern "C" fn setsockopt(sockfd: c_int,
+                             level: c_int,
+                             optname: c_int,
+                             optval: *const c_void,
+                             optlen: socklen_t)
+                             -> c_int {
+    lazy_static! {
+        static ref SETSOCKOPT_FUNC: SetSockOptFunc = get_libc_func!(SetSockOptFunc, "setsockopt");
+    }
+
+    if sockopt::option_selected(level, optname) {
+        injectFaults!(sockfd, "setsockopt", -1);
+    }
+
+    SETSOCKOPT_FUNC(sockfd, level, optname, optval, optlen)
+}
+
+#[no_mangle]
+pub extern "C" fn shutdown(sockfd: c_int, how: c_int) -> c_int {
+    lazy_static! {
+        static ref SHUTDOWN_FUNC: ShutdownFunc = get_libc_func!(ShutdownFunc, "shutdown");
+    }
+
+    injectFaults!(sockfd, "shutdown", -1);
+
+    SHUTDOWN_FUNC(sockfd, how)
+}
+
+#[no_mangle]
+pub extern "C" fn getsockname(sockfd: c_int, addr: *mut sockaddr, addrlen: *mut socklen_t)
+                              -> c_int {
+    lazy_static! {
+        static ref GETSOCKNAME_FUNC: GetSockNameFunc =
+            get_libc_func!(GetSockNameFunc, "getsockname");
+    }
+
+    injectFaults!(sockfd, "getsockname", -1);
+
+    GETSOCKNAME_FUNC(sockfd, addr, addrlen)
+}
+
+#[no_mangle]
+pub extern "C" fn getpeername(sockfd: c_int, addr: *mut sockaddr, addrlen: *mut socklen_t)
+                              -> c_int {
+    lazy_static! {
+        static ref GETPEERNAME_FUNC: GetPeerNameFunc =
+            get_libc_func!(GetPeerNameFunc, "getpeername");
+    }
+
+    injectFaults!(sockfd, "getpeername", -1);
+
+    GETPEERNAME_FUNC(sockfd, addr, addrlen)
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn poll(fds: *mut libc::pollfd, nfds: libc::nfds_t, timeout: c_int) -> c_int {
+    lazy_static! {
+        static ref POLL_FUNC: PollFunc = get_libc_func!(PollFunc, "poll");
+    }
+
+    unsafe {
+        ready::poll(fds, nfds, ready::ms_duration(timeout), "poll", |fds, nfds, timeout| {
+            POLL_FUNC(fds, nfds, ready::duration_ms(timeout))
+        })
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn ppoll(fds: *mut libc::pollfd,
+                        nfds: libc::nfds_t,
+                        timeout: *const libc::timespec,
+                        sigmask: *const libc::sigset_t)
+                        -> c_int {
+    lazy_static! {
+        static ref PPOLL_FUNC: PpollFunc = get_libc_func!(PpollFunc, "ppoll");
+    }
+
+    unsafe {
+        ready::poll(fds, nfds, ready::timespec_duration(timeout), "poll", |fds, nfds, timeout| {
+            match timeout {
+                Some(timeout) => PPOLL_FUNC(fds, nfds, &ready::duration_timespec(timeout), sigmask),
+                None => PPOLL_FUNC(fds, nfds, ptr::null(), sigmask),
+            }
+        })
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn select(nfds: c_int,
+                         readfds: *mut libc::fd_set,
+                         writefds: *mut libc::fd_set,
+                         exceptfds: *mut libc::fd_set,
+                         timeout: *mut libc::timeval)
+                         -> c_int {
+    lazy_static! {
+        static ref SELECT_FUNC: SelectFunc = get_libc_func!(SelectFunc, "select");
+        static ref POLL_FUNC: PollFunc = get_libc_func!(PollFunc, "poll");
+    }
+
+    unsafe {
+        ready::select(nfds,
+                      [readfds, writefds, exceptfds],
+                      ready::timeval_duration(timeout),
+                      "select",
+                      || SELECT_FUNC(nfds, readfds, writefds, exceptfds, timeout),
+                      |fds, nfds, timeout| POLL_FUNC(fds, nfds, ready::duration_ms(timeout)))
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn pselect(nfds: c_int,
+                          readfds: *mut libc::fd_set,
+                          writefds: *mut libc::fd_set,
+                          exceptfds: *mut libc::fd_set,
+                          timeout: *const libc::timespec,
+                          sigmask: *const libc::sigset_t)
+                          -> c_int {
+    lazy_static! {
+        static ref PSELECT_FUNC: PselectFunc = get_libc_func!(PselectFunc, "pselect");
+        static ref PPOLL_FUNC: PpollFunc = get_libc_func!(PpollFunc, "ppoll");
+    }
+
+    unsafe {
+        ready::select(nfds,
+                      [readfds, writefds, exceptfds],
+                      ready::timespec_duration(timeout),
+                      "select",
+                      || PSELECT_FUNC(nfds, readfds, writefds, exceptfds, timeout, sigmask),
+                      |fds, nfds, timeout| match timeout {
+                          Some(timeout) => {
+                              PPOLL_FUNC(fds, nfds, &ready::duration_timespec(timeout), sigmask)
+                          }
+                          None => PPOLL_FUNC(fds, nfds, ptr::null(), sigmask),
+                      })
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn epoll_create(size: c_int) -> c_int {
+    lazy_static! {
+        static ref EPOLL_CREATE_FUNC: EpollCreateFunc =
+            get_libc_func!(EpollCreateFunc, "epoll_create");
+    }
+
+    checkFdLimit!(1, -1);
+
+    let fd = EPOLL_CREATE_FUNC(size);
+    track_new_fd(fd, false, false);
+
+    fd
+}
+
+#[no_mangle]
+pub extern "C" fn epoll_create1(flags: c_int) -> c_int {
+    lazy_static! {
+        static ref EPOLL_CREATE1_FUNC: EpollCreateFunc =
+            get_libc_func!(EpollCreateFunc, "epoll_create1");
+    }
+
+    checkFdLimit!(1, -1);
+
+    let fd = EPOLL_CREATE1_FUNC(flags);
+    track_new_fd(fd, false, false);
+
+    fd
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn epoll_ctl(epfd: c_int,
+                            op: c_int,
+                            fd: c_int,
+                            event: *mut libc::epoll_event)
+                            -> c_int {
+    unsafe {
+        ready::epoll_ctl(epfd, op, fd, event)
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn epoll_wait(epfd: c_int,
+                             events: *mut libc::epoll_event,
+                             maxevents: c_int,
+                             timeout: c_int)
+                             -> c_int {
+    lazy_static! {
+        static ref EPOLL_WAIT_FUNC: EpollWaitFunc = get_libc_func!(EpollWaitFunc, "epoll_wait");
+    }
+
+    unsafe {
+        ready::epoll_wait(epfd,
+                          events,
+                          maxevents,
+                          timeout,
+                          "epoll_wait",
+                          |events, maxevents, timeout| {
+                              EPOLL_WAIT_FUNC(epfd, events, maxevents, timeout)
+                          })
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn epoll_pwait(epfd: c_int,
+                              events: *mut libc::epoll_event,
+                              maxevents: c_int,
+                              timeout: c_int,
+                              sigmask: *const libc::sigset_t)
+                              -> c_int {
+    lazy_static! {
+        static ref EPOLL_PWAIT_FUNC: EpollPwaitFunc = get_libc_func!(EpollPwaitFunc,
+                                                                     "epoll_pwait");
+    }
+
+    unsafe {
+        ready::epoll_wait(epfd,
+                          events,
+                          maxevents,
+                          timeout,
+                          "epoll_wait",
+                          |events, maxevents, timeout| {
+                              EPOLL_PWAIT_FUNC(epfd, events, maxevents, timeout, sigmask)
+                          })
+    }
 }
 
 #[no_mangle]
@@ -165,8 +683,15 @@ pub extern "C" fn send(sockfd: c_int, buf: *mut c_void, len: size_t, flags: c_in
     }
 
     injectFaults!(sockfd, "send", -1);
+    checkStorm!(sockfd, "send", flags, -1);
+    let len = checkConnection!(sockfd, Direction::Send, len, flags);
+    let len = throttle::limit(sockfd, len);
 
-    SEND_FUNC(sockfd, buf, len, flags)
+    let ret = SEND_FUNC(sockfd, buf, len, flags);
+    conn::after_io(sockfd, ret);
+    throttle::after_io(sockfd, ret);
+
+    ret
 }
 
 #[no_mangle]
@@ -176,89 +701,1308 @@ pub extern "C" fn recv(sockfd: c_int, buf: *mut c_void, len: size_t, flags: c_in
     }
 
     injectFaults!(sockfd, "recv", -1);
+    checkStorm!(sockfd, "recv", flags, -1);
+    checkSpurious!(sockfd, -1);
+    let len = checkConnection!(sockfd, Direction::Recv, len, flags);
+    let len = throttle::limit(sockfd, len);
 
+    let ret = RECV_FUNC(sockfd, buf, len, flags);
+    conn::after_io(sockfd, ret);
+    throttle::after_io(sockfd, ret);
 
-    RECV_FUNC(sockfd, buf, len, flags)
+    ret
 }
 
+// The unconnected forms below are selected per call: by the destination
+//   address when sending, or by the source address of what was received.
+//   The source is only known after the real call, so a received datagram
+//   that's selected for an error is consumed and lost.
 
 #[no_mangle]
-pub extern "C" fn bind(sockfd: c_int, addr: *const sockaddr, addrlen: u8) -> c_int {
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn sendto(sockfd: c_int,
+                         buf: *const c_void,
+                         len: size_t,
+                         flags: c_int,
+                         dest_addr: *const sockaddr,
+                         addrlen: socklen_t)
+                         -> ssize_t {
     lazy_static! {
-        static ref BIND_FUNC: BindFunc = get_libc_func!(BindFunc, "bind");
+        static ref SENDTO_FUNC: SendToFunc = get_libc_func!(SendToFunc, "sendto");
     }
 
-    if unsafe { matches_addr(addr, "LIBFAULTINJ_ERROR_PATH") } {
-        ERR_FDS.write().unwrap().insert(sockfd);
+    let err_match = unsafe {
+        fd_or_addr_matches(&ERR_FDS, sockfd, dest_addr, "LIBFAULTINJ_ERROR_ADDR")
+    };
+    let delay_match = unsafe {
+        fd_or_addr_matches(&DELAY_FDS, sockfd, dest_addr, "LIBFAULTINJ_DELAY_ADDR")
+    };
+    injectFaults!(matched: err_match, delay_match, socket: sockfd, "sendto", SSIZE_ERR);
+    checkStorm!(matched: err_match, sockfd, "sendto", flags, SSIZE_ERR);
+    let len = checkConnection!(sockfd, Direction::Send, len, flags);
+    let len = throttle::limit(sockfd, len);
+
+    let action = unsafe { dgram::send_action(sockfd, dest_addr) };
+    let copy = || {
+        Datagram {
+            data: unsafe { slice::from_raw_parts(buf as *const u8, len).to_vec() },
+            addr: unsafe { dgram::raw_addr(dest_addr, addrlen) },
+            flags,
+        }
+    };
+
+    let ret = dgram::send(sockfd, action, len, copy, || {
+        SENDTO_FUNC(sockfd, buf, len, flags, dest_addr, addrlen)
+    });
+    conn::after_io(sockfd, ret);
+    throttle::after_io(sockfd, ret);
+
+    ret
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn recvfrom(sockfd: c_int,
+                           buf: *mut c_void,
+                           len: size_t,
+                           flags: c_int,
+                           src_addr: *mut sockaddr,
+                           addrlen: *mut socklen_t)
+                           -> ssize_t {
+    lazy_static! {
+        static ref RECVFROM_FUNC: RecvFromFunc = get_libc_func!(RecvFromFunc, "recvfrom");
     }
 
-    if unsafe { matches_addr(addr, "LIBFAULTINJ_DELAY_PATH") } {
-        DELAY_FDS.write().unwrap().insert(sockfd);
+    injectFaults!(sockfd, "recvfrom", SSIZE_ERR);
+    checkStorm!(sockfd, "recvfrom", flags, SSIZE_ERR);
+    checkSpurious!(sockfd, SSIZE_ERR);
+    let len = checkConnection!(sockfd, Direction::Recv, len, flags);
+    let len = throttle::limit(sockfd, len);
+
+    if let Some(duplicate) = dgram::take_duplicate(sockfd, flags) {
+        let n = cmp::min(len, duplicate.data.len());
+        unsafe {
+            ptr::copy_nonoverlapping(duplicate.data.as_ptr(), buf as *mut u8, n);
+            PeerAddrBuf::from_raw(&duplicate.addr).copy_out(src_addr, addrlen);
+        }
+        return n as ssize_t;
     }
 
-    BIND_FUNC(sockfd, addr, addrlen)
+    let (ret, peer) = loop {
+        let mut peer = PeerAddrBuf::default();
+        let peer_ptr = peer.as_mut_ptr();
+        let ret = RECVFROM_FUNC(sockfd, buf, len, flags, peer_ptr, &mut peer.len);
+        conn::after_io(sockfd, ret);
+        throttle::after_io(sockfd, ret);
+        if ret < 0 {
+            return ret;
+        }
+
+        let copy = |n| unsafe { slice::from_raw_parts(buf as *const u8, n).to_vec() };
+        if let Some((reported, _)) = dgram::on_receive(sockfd, flags, &peer, ret, copy) {
+            break (reported, peer);
+        }
+    };
+    unsafe { peer.copy_out(src_addr, addrlen) };
+
+    injectFaults!(matched: matches_peer_addr(&peer, "LIBFAULTINJ_ERROR_ADDR"),
+                  matches_peer_addr(&peer, "LIBFAULTINJ_DELAY_ADDR"),
+                  "recvfrom",
+                  SSIZE_ERR);
+
+    ret
 }
 
 #[no_mangle]
-pub extern "C" fn fstat(fd: c_int, buf: *const libc::stat) -> c_int {
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn sendmsg(sockfd: c_int, msg: *const libc::msghdr, flags: c_int) -> ssize_t {
     lazy_static! {
-        static ref FSTAT_FUNC: FstatFunc = get_libc_func!(FstatFunc, "fstat"); // fixme
+        static ref SENDMSG_FUNC: SendMsgFunc = get_libc_func!(SendMsgFunc, "sendmsg");
     }
 
-    injectFaults!(fd, "fstat", -1);
-
-    FSTAT_FUNC(fd, buf)
+    let dest_addr = if msg.is_null() {
+        ptr::null()
+    } else {
+        unsafe { (*msg).msg_name as *const sockaddr }
+    };
+    let err_match = unsafe {
+        fd_or_addr_matches(&ERR_FDS, sockfd, dest_addr, "LIBFAULTINJ_ERROR_ADDR")
+    };
+    let delay_match = unsafe {
+        fd_or_addr_matches(&DELAY_FDS, sockfd, dest_addr, "LIBFAULTINJ_DELAY_ADDR")
+    };
+    injectFaults!(matched: err_match, delay_match, socket: sockfd, "sendmsg", SSIZE_ERR);
+    checkStorm!(matched: err_match, sockfd, "sendmsg", flags, SSIZE_ERR);
+
+    if msg.is_null() {
+        return SENDMSG_FUNC(sockfd, msg, flags);
+    }
+    // The caller's msghdr is const, so a copy is clamped.
+    let mut hdr = unsafe { *msg };
+    let len = unsafe { conn::iov_len(hdr.msg_iov, hdr.msg_iovlen) };
+    let len = checkConnection!(sockfd, Direction::Send, len, flags);
+    let len = throttle::limit(sockfd, len);
+    let _clamped = unsafe { conn::clamp(&mut hdr, len) };
+
+    let action = unsafe { dgram::send_action(sockfd, dest_addr) };
+    let copy = || {
+        Datagram {
+            data: unsafe { dgram::gather(hdr.msg_iov, hdr.msg_iovlen) },
+            addr: unsafe { dgram::raw_addr(dest_addr, hdr.msg_namelen) },
+            flags,
+        }
+    };
+
+    let ret = dgram::send(sockfd, action, len, copy, || SENDMSG_FUNC(sockfd, &hdr, flags));
+    conn::after_io(sockfd, ret);
+    throttle::after_io(sockfd, ret);
+
+    ret
 }
 
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn recvmsg(sockfd: c_int, msg: *mut libc::msghdr, flags: c_int) -> ssize_t {
+    lazy_static! {
+        static ref RECVMSG_FUNC: RecvMsgFunc = get_libc_func!(RecvMsgFunc, "recvmsg");
+    }
+
+    injectFaults!(sockfd, "recvmsg", SSIZE_ERR);
+    checkStorm!(sockfd, "recvmsg", flags, SSIZE_ERR);
+    checkSpurious!(sockfd, SSIZE_ERR);
 
+    if msg.is_null() {
+        return RECVMSG_FUNC(sockfd, msg, flags);
+    }
+    let msg = unsafe { &mut *msg };
+    let len = unsafe { conn::iov_len(msg.msg_iov, msg.msg_iovlen) };
+    let len = checkConnection!(sockfd, Direction::Recv, len, flags);
+    let len = throttle::limit(sockfd, len);
+    let (caller_name, caller_namelen) = (msg.msg_name, msg.msg_namelen);
+
+    if let Some(duplicate) = dgram::take_duplicate(sockfd, flags) {
+        let n = unsafe { dgram::scatter(msg.msg_iov, msg.msg_iovlen, &duplicate.data) };
+        msg.msg_controllen = 0;
+        msg.msg_flags = if n < duplicate.data.len() {
+            libc::MSG_TRUNC
+        } else {
+            0
+        };
+        unsafe {
+            PeerAddrBuf::from_raw(&duplicate.addr).copy_out(caller_name as *mut sockaddr,
+                                                            &mut msg.msg_namelen)
+        };
+        return n as ssize_t;
+    }
 
-// DISABLED -- these calls are disabled until problems caused can be addressed
+    let (ret, peer) = loop {
+        let mut peer = PeerAddrBuf::default();
+        msg.msg_name = peer.as_mut_ptr() as *mut c_void;
+        msg.msg_namelen = peer.len;
+
+        let clamped = unsafe { conn::clamp(msg, len) };
+        let ret = RECVMSG_FUNC(sockfd, msg, flags);
+        conn::unclamp(msg, clamped);
+        conn::after_io(sockfd, ret);
+        throttle::after_io(sockfd, ret);
+
+        peer.len = msg.msg_namelen;
+        msg.msg_name = caller_name;
+        msg.msg_namelen = caller_namelen;
+        if ret < 0 {
+            return ret;
+        }
+
+        let copy = |n| {
+            let mut data = unsafe { dgram::gather(msg.msg_iov, msg.msg_iovlen) };
+            data.truncate(n);
+            data
+        };
+        if let Some((reported, truncated)) = dgram::on_receive(sockfd, flags, &peer, ret, copy) {
+            if truncated {
+                msg.msg_flags |= libc::MSG_TRUNC;
+            }
+            break (reported, peer);
+        }
+    };
+    unsafe { peer.copy_out(caller_name as *mut sockaddr, &mut msg.msg_namelen) };
+
+    injectFaults!(matched: matches_peer_addr(&peer, "LIBFAULTINJ_ERROR_ADDR"),
+                  matches_peer_addr(&peer, "LIBFAULTINJ_DELAY_ADDR"),
+                  "recvmsg",
+                  SSIZE_ERR);
+
+    ret
+}
+
+// sendmmsg() and recvmmsg() report an error for the first message only.  An
+//   error on a later message ends a sendmmsg() batch early with the count
+//   so far.  recvmmsg() has already taken its whole batch off of the
+//   socket, so unless that's a single message it returns all of it and
+//   fails the next call instead, as the kernel does.
 #[no_mangle]
-#[allow(private_no_mangle_fns)]
-#[allow(dead_code)]
-#[allow(unused_variables)]
-extern "C" fn stat(pathname: *const c_char, buf: *mut libc::stat) -> c_int {
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn sendmmsg(sockfd: c_int,
+                           msgvec: *mut libc::mmsghdr,
+                           vlen: c_uint,
+                           flags: c_int)
+                           -> c_int {
+    use errno::set_errno;
+
     lazy_static! {
-        static ref STAT_FUNC: StatFunc = get_libc_func!(StatFunc, "stat"); // fixme
+        static ref SENDMMSG_FUNC: SendMmsgFunc = get_libc_func!(SendMmsgFunc, "sendmmsg");
     }
-    // TO BE IMPLEMENTED
-    STAT_FUNC(pathname, buf) - 1
-}
 
+    injectFaults!(sockfd, "sendmmsg", -1);
+    checkStorm!(sockfd, "sendmmsg", flags, -1);
+
+    if msgvec.is_null() {
+        return SENDMMSG_FUNC(sockfd, msgvec, vlen, flags);
+    }
+    let msgs = unsafe { slice::from_raw_parts_mut(msgvec, vlen as usize) };
+    let dest_addr = |i: usize| msgs[i].msg_hdr.msg_name as *const sockaddr;
+
+    let delay_match = (0..msgs.len())
+                          .any(|i| unsafe { matches_addr(dest_addr(i), "LIBFAULTINJ_DELAY_ADDR") });
+    injectDelay!(matched: delay_match, socket: sockfd, "sendmmsg", -1);
+
+    for i in 0..msgs.len() {
+        let err_match = unsafe { matches_addr(dest_addr(i), "LIBFAULTINJ_ERROR_ADDR") };
+
+        if let Some(err) = injectedErrno!(matched: err_match, "sendmmsg") {
+            if i == 0 {
+                set_errno(err);
+                return -1;
+            }
+            return SENDMMSG_FUNC(sockfd, msgvec, i as c_uint, flags);
+        }
+    }
+
+    let len = unsafe { conn::mmsg_len(msgs) };
+    let len = match conn::before_io(sockfd, Direction::Send, len, flags) {
+        conn::Outcome::Proceed(len) => len,
+        conn::Outcome::Return(ret, _) => return ret as c_int,
+    };
+    let (count, clamped) = unsafe { conn::clamp_mmsgs(msgs, len) };
+    let ret = SENDMMSG_FUNC(sockfd, msgs.as_mut_ptr(), count as c_uint, flags);
+    conn::unclamp_mmsgs(msgs, clamped);
+    conn::after_io(sockfd, throttle::mmsg_bytes(msgs, ret));
+    throttle::after_io(sockfd, throttle::mmsg_bytes(msgs, ret));
+
+    ret
+}
 
 #[no_mangle]
-#[allow(private_no_mangle_fns)]
-#[allow(dead_code)]
-#[allow(unused_variables)]
-extern "C" fn socket(domain: c_int, type_: c_int, protocol: c_int) -> c_int {
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn recvmmsg(sockfd: c_int,
+                           msgvec: *mut libc::mmsghdr,
+                           vlen: c_uint,
+                           flags: c_int,
+                           timeout: *mut libc::timespec)
+                           -> c_int {
+    use errno::{Errno, set_errno};
+
     lazy_static! {
-        static ref SOCKET_FUNC: SocketFunc = get_libc_func!(SocketFunc, "socket");
+        static ref RECVMMSG_FUNC: RecvMmsgFunc = get_libc_func!(RecvMmsgFunc, "recvmmsg");
+    }
+
+    if let Some(err) = dgram::take_deferred_error(sockfd) {
+        set_errno(Errno(err));
+        return -1;
+    }
+    injectFaults!(sockfd, "recvmmsg", -1);
+    checkStorm!(sockfd, "recvmmsg", flags, -1);
+    checkSpurious!(sockfd, -1);
+
+    if msgvec.is_null() {
+        return RECVMMSG_FUNC(sockfd, msgvec, vlen, flags, timeout);
+    }
+    let msgs = unsafe { slice::from_raw_parts_mut(msgvec, vlen as usize) };
+    let len = unsafe { conn::mmsg_len(msgs) };
+    let len = match conn::before_io(sockfd, Direction::Recv, len, flags) {
+        conn::Outcome::Proceed(len) => len,
+        conn::Outcome::Return(ret, _) => return ret as c_int,
+    };
+
+    let callers: Vec<(*mut c_void, socklen_t)> =
+        msgs.iter().map(|m| (m.msg_hdr.msg_name, m.msg_hdr.msg_namelen)).collect();
+    let mut peers: Vec<PeerAddrBuf> = callers.iter().map(|_| PeerAddrBuf::default()).collect();
+    for (msg, peer) in msgs.iter_mut().zip(peers.iter_mut()) {
+        msg.msg_hdr.msg_name = peer.as_mut_ptr() as *mut c_void;
+        msg.msg_hdr.msg_namelen = peer.len;
+    }
+
+    let (count, clamped) = unsafe { conn::clamp_mmsgs(msgs, len) };
+    let ret = RECVMMSG_FUNC(sockfd, msgs.as_mut_ptr(), count as c_uint, flags, timeout);
+    conn::unclamp_mmsgs(msgs, clamped);
+
+    let restore = msgs.iter_mut().zip(peers.iter_mut()).zip(callers.iter());
+    for ((msg, peer), &(name, namelen)) in restore {
+        peer.len = msg.msg_hdr.msg_namelen;
+        msg.msg_hdr.msg_name = name;
+        msg.msg_hdr.msg_namelen = namelen;
+    }
+    conn::after_io(sockfd, throttle::mmsg_bytes(msgs, ret));
+    throttle::after_io(sockfd, throttle::mmsg_bytes(msgs, ret));
+    if ret <= 0 {
+        return ret;
+    }
+    let received = ret as usize;
+    for (msg, peer) in msgs.iter_mut().zip(peers.iter()).take(received) {
+        let name = msg.msg_hdr.msg_name as *mut sockaddr;
+        unsafe { peer.copy_out(name, &mut msg.msg_hdr.msg_namelen) };
     }
 
-    //  injection strategy TBD
+    // The messages are already taken off of the socket, so a delay for their
+    //   senders is slept even on a non-blocking one.
+    let delay_match = peers.iter()
+                           .take(received)
+                           .any(|peer| matches_peer_addr(peer, "LIBFAULTINJ_DELAY_ADDR"));
+    injectDelay!(matched: delay_match, "recvmmsg", -1);
+
+    for peer in peers.iter().take(received) {
+        let err_match = matches_peer_addr(peer, "LIBFAULTINJ_ERROR_ADDR");
+
+        if let Some(err) = injectedErrno!(matched: err_match, "recvmmsg") {
+            if received == 1 {
+                set_errno(err);
+                return -1;
+            }
+            dgram::defer_error(sockfd, err.0);
+            break;
+        }
+    }
 
-    SOCKET_FUNC(domain, type_, protocol)
+    ret
 }
 
-// mmap() interception is disabled for now.  deadlocks on
-//   malloc_init_hard()->mmap()->DynamicLibrary::open()->malloc_init_hard(), at least
-//   on systems w/jemalloc.
 #[no_mangle]
-#[allow(private_no_mangle_fns)]
-#[allow(dead_code)]
-// pub
-extern "C" fn mmap(addr: *mut c_void,
-                   length_: size_t,
-                   prot: c_int,
-                   flags: c_int,
-                   fd: c_int,
-                   offset: off_t)
-                   -> *mut c_void {
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn bind(sockfd: c_int, addr: *const sockaddr, addrlen: socklen_t) -> c_int {
     lazy_static! {
-        static ref MMAP_FUNC: MmapFunc = get_libc_func!(MmapFunc, "mmap");
+        static ref BIND_FUNC: BindFunc = get_libc_func!(BindFunc, "bind");
+    }
+
+    if unsafe { matches_addr(addr, "LIBFAULTINJ_ERROR_ADDR") } {
+        ERR_FDS.write().unwrap().insert(sockfd);
+    }
+
+    if unsafe { matches_addr(addr, "LIBFAULTINJ_DELAY_ADDR") } {
+        DELAY_FDS.write().unwrap().insert(sockfd);
     }
 
-    injectFaults!(fd, "mmap", libc::MAP_FAILED);
+    BIND_FUNC(sockfd, addr, addrlen)
+}
 
-    MMAP_FUNC(addr, length_, prot, flags, fd, offset)
+#[no_mangle]
+pub extern "C" fn accept(sockfd: c_int, addr: *mut sockaddr, addrlen: *mut socklen_t) -> c_int {
+    lazy_static! {
+        static ref ACCEPT_FUNC: AcceptFunc = get_libc_func!(AcceptFunc, "accept");
+    }
+
+    do_accept!(sockfd, "accept", ACCEPT_FUNC(sockfd, addr, addrlen))
+}
+
+#[no_mangle]
+pub extern "C" fn accept4(sockfd: c_int,
+                          addr: *mut sockaddr,
+                          addrlen: *mut socklen_t,
+                          flags: c_int)
+                          -> c_int {
+    lazy_static! {
+        static ref ACCEPT4_FUNC: Accept4Func = get_libc_func!(Accept4Func, "accept4");
+    }
+
+    do_accept!(sockfd, "accept", ACCEPT4_FUNC(sockfd, addr, addrlen, flags))
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn getaddrinfo(node: *const c_char,
+                              service: *const c_char,
+                              hints: *const libc::addrinfo,
+                              res: *mut *mut libc::addrinfo)
+                              -> c_int {
+    lazy_static! {
+        static ref GETADDRINFO_FUNC: GetAddrInfoFunc = get_libc_func!(GetAddrInfoFunc,
+                                                                      "getaddrinfo");
+    }
+
+    match unsafe { dns::lookup("getaddrinfo", node) } {
+        Lookup::Real => GETADDRINFO_FUNC(node, service, hints, res),
+        Lookup::Fail(err) => err,
+        Lookup::Substitute(ip) => GETADDRINFO_FUNC(ip.as_ptr(), service, hints, res),
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn getnameinfo(addr: *const sockaddr,
+                              addrlen: socklen_t,
+                              host: *mut c_char,
+                              hostlen: socklen_t,
+                              serv: *mut c_char,
+                              servlen: socklen_t,
+                              flags: c_int)
+                              -> c_int {
+    lazy_static! {
+        static ref GETNAMEINFO_FUNC: GetNameInfoFunc = get_libc_func!(GetNameInfoFunc,
+                                                                      "getnameinfo");
+    }
+
+    // Reverse lookups are selected by address, and only fail or slow down.
+    if !host.is_null() && flags & libc::NI_NUMERICHOST == 0 {
+        if let Some(peer) = unsafe { addr::sockaddr_to_socket_addr(addr) } {
+            if let Lookup::Fail(err) = dns::lookup_str("getnameinfo", &peer.ip().to_string()) {
+                return err;
+            }
+        }
+    }
+
+    GETNAMEINFO_FUNC(addr, addrlen, host, hostlen, serv, servlen, flags)
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn gethostbyname(name: *const c_char) -> *mut c_void {
+    lazy_static! {
+        static ref GETHOSTBYNAME_FUNC: GetHostByNameFunc = get_libc_func!(GetHostByNameFunc,
+                                                                          "gethostbyname");
+    }
+
+    match unsafe { dns::lookup("gethostbyname", name) } {
+        Lookup::Real => GETHOSTBYNAME_FUNC(name),
+        Lookup::Fail(err) => {
+            dns::set_h_errno(dns::eai_to_h_errno(err));
+            ptr::null_mut()
+        }
+        Lookup::Substitute(ip) => GETHOSTBYNAME_FUNC(ip.as_ptr()),
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn gethostbyname_r(name: *const c_char,
+                                  ret: *mut c_void,
+                                  buf: *mut c_char,
+                                  buflen: size_t,
+                                  result: *mut *mut c_void,
+                                  h_errnop: *mut c_int)
+                                  -> c_int {
+    lazy_static! {
+        static ref GETHOSTBYNAME_R_FUNC: GetHostByNameRFunc =
+            get_libc_func!(GetHostByNameRFunc, "gethostbyname_r");
+    }
+
+    match unsafe { dns::lookup("gethostbyname_r", name) } {
+        Lookup::Real => GETHOSTBYNAME_R_FUNC(name, ret, buf, buflen, result, h_errnop),
+        Lookup::Fail(err) => {
+            let h_err = dns::eai_to_h_errno(err);
+            unsafe {
+                *result = ptr::null_mut();
+                *h_errnop = h_err;
+            }
+            // Like glibc, only a temporary failure is an error.
+            if err == libc::EAI_AGAIN {
+                libc::EAGAIN
+            } else {
+                0
+            }
+        }
+        Lookup::Substitute(ip) => {
+            GETHOSTBYNAME_R_FUNC(ip.as_ptr(), ret, buf, buflen, result, h_errnop)
+        }
+    }
+}
+
+// <resolv.h> turns res_query() into __res_query(), but glibc 2.34 and later
+//   export both.  Answers are raw DNS messages, so substitutions don't apply.
+macro_rules! do_res_query(
+    ($funcname:expr, $dname:expr, $class:expr, $type_:expr, $answer:expr, $anslen:expr) =>
+    ({
+        lazy_static! {
+            static ref RES_QUERY_FUNC: ResQueryFunc = get_libc_func!(ResQueryFunc, $funcname);
+        }
+
+        if let Lookup::Fail(err) = unsafe { dns::lookup($funcname, $dname) } {
+            dns::set_h_errno(dns::eai_to_h_errno(err));
+            return -1;
+        }
+
+        RES_QUERY_FUNC($dname, $class, $type_, $answer, $anslen)
+    }));
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn res_query(dname: *const c_char,
+                            class: c_int,
+                            type_: c_int,
+                            answer: *mut u8,
+                            anslen: c_int)
+                            -> c_int {
+    do_res_query!("res_query", dname, class, type_, answer, anslen)
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn __res_query(dname: *const c_char,
+                              class: c_int,
+                              type_: c_int,
+                              answer: *mut u8,
+                              anslen: c_int)
+                              -> c_int {
+    do_res_query!("__res_query", dname, class, type_, answer, anslen)
+}
+
+#[no_mangle]
+pub extern "C" fn fstat(fd: c_int, buf: *const libc::stat) -> c_int {
+    lazy_static! {
+        static ref FSTAT_FUNC: FstatFunc = get_libc_func!(FstatFunc, "fstat"); // fixme
+    }
+
+    if oom::suspended() {
+        return FSTAT_FUNC(fd, buf);
+    }
+    injectFaults!(fd, "fstat", -1);
+
+    FSTAT_FUNC(fd, buf)
+}
+
+
+// The *64 forms take the same faults as the plain ones, as open64() does.
+//   Growing a file under LIBFAULTINJ_QUOTA_PATH also needs room in the quota.
+macro_rules! do_ftruncate(
+    ($symbol:expr, $fd:expr, $length:expr) =>
+    ({
+        lazy_static! {
+            static ref FTRUNCATE_FUNC: FtruncateFunc = get_libc_func!(FtruncateFunc, $symbol);
+        }
+
+        injectFaults!($fd, "ftruncate", -1);
+        checkReadOnly!($fd, -1);
+        let growth = checkQuota!(quota::limit_resize($fd, $length), -1);
+
+        let ret = FTRUNCATE_FUNC($fd, $length);
+        quota::charge(growth);
+
+        ret
+    }));
+
+#[no_mangle]
+pub extern "C" fn ftruncate(fd: c_int, length: off_t) -> c_int {
+    do_ftruncate!("ftruncate", fd, length)
+}
+
+#[no_mangle]
+pub extern "C" fn ftruncate64(fd: c_int, length: off_t) -> c_int {
+    do_ftruncate!("ftruncate64", fd, length)
+}
+
+macro_rules! do_truncate(
+    ($symbol:expr, $path_:expr, $length:expr) =>
+    ({
+        lazy_static! {
+            static ref TRUNCATE_FUNC: TruncateFunc = get_libc_func!(TruncateFunc, $symbol);
+        }
+
+        let path = unsafe { c_path($path_) };
+        injectPathFaults!("truncate", -1, path);
+        let growth = checkQuota!(quota::limit_truncate(&path, $length), -1);
+
+        let ret = TRUNCATE_FUNC($path_, $length);
+        quota::charge(growth);
+
+        ret
+    }));
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn truncate(path: *const c_char, length: off_t) -> c_int {
+    do_truncate!("truncate", path, length)
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn truncate64(path: *const c_char, length: off_t) -> c_int {
+    do_truncate!("truncate64", path, length)
+}
+
+/**
+ * @return the file's size for `quota::charge()`, or Err(errno) if
+ *      fallocate() on `fd` with `mode` can't have the room it needs.
+ */
+fn fallocate_room(fd: c_int,
+                  mode: c_int,
+                  offset: off_t,
+                  len: off_t)
+                  -> Result<Option<quota::Growth>, c_int> {
+    // Only calls that can make the file bigger need room for it.
+    if mode & (libc::FALLOC_FL_KEEP_SIZE | libc::FALLOC_FL_PUNCH_HOLE) == 0 {
+        quota::limit_resize(fd, offset.saturating_add(len))
+    } else {
+        Ok(None)
+    }
+}
+
+macro_rules! do_fallocate(
+    ($symbol:expr, $fd:expr, $mode:expr, $offset:expr, $len:expr) =>
+    ({
+        lazy_static! {
+            static ref FALLOCATE_FUNC: FallocateFunc = get_libc_func!(FallocateFunc, $symbol);
+        }
+
+        injectFaults!($fd, "fallocate", -1);
+        checkReadOnly!($fd, -1);
+        let growth = checkQuota!(fallocate_room($fd, $mode, $offset, $len), -1);
+
+        let ret = FALLOCATE_FUNC($fd, $mode, $offset, $len);
+        quota::charge(growth);
+
+        ret
+    }));
+
+#[no_mangle]
+pub extern "C" fn fallocate(fd: c_int, mode: c_int, offset: off_t, len: off_t) -> c_int {
+    do_fallocate!("fallocate", fd, mode, offset, len)
+}
+
+#[no_mangle]
+pub extern "C" fn fallocate64(fd: c_int, mode: c_int, offset: off_t, len: off_t) -> c_int {
+    do_fallocate!("fallocate64", fd, mode, offset, len)
+}
+
+// posix_fallocate() returns its error rather than setting errno.
+macro_rules! do_posix_fallocate(
+    ($symbol:expr, $fd:expr, $offset:expr, $len:expr) =>
+    ({
+        lazy_static! {
+            static ref POSIX_FALLOCATE_FUNC: PosixFallocateFunc =
+                get_libc_func!(PosixFallocateFunc, $symbol);
+        }
+
+        if let Some(err) = delayedErrno!($fd, "posix_fallocate") {
+            return err.0;
+        }
+        if fsinfo::read_only_fd($fd) {
+            return libc::EROFS;
+        }
+        let growth = match fallocate_room($fd, 0, $offset, $len) {
+            Ok(growth) => growth,
+            Err(err) => return err,
+        };
+
+        let ret = POSIX_FALLOCATE_FUNC($fd, $offset, $len);
+        quota::charge(growth);
+
+        ret
+    }));
+
+#[no_mangle]
+pub extern "C" fn posix_fallocate(fd: c_int, offset: off_t, len: off_t) -> c_int {
+    do_posix_fallocate!("posix_fallocate", fd, offset, len)
+}
+
+#[no_mangle]
+pub extern "C" fn posix_fallocate64(fd: c_int, offset: off_t, len: off_t) -> c_int {
+    do_posix_fallocate!("posix_fallocate64", fd, offset, len)
+}
+
+// Zero-copy transfers between two fds skip read() and write(), so they
+//   take the faults of both: errors and delays if either fd is in
+//   ERR_FDS or DELAY_FDS, connection resets on either, throttling by
+//   both, and the quota of the destination.  $transfer is called with how
+//   many bytes to move.
+macro_rules! do_transfer(
+    ($funcname:expr, $fd_in:expr, $fd_out:expr, $len:expr, $offset_out:expr, $transfer:expr) =>
+    ({
+        use errno::set_errno;
+        use latency::Selector;
+
+        let (fd_in, fd_out) = ($fd_in, $fd_out);
+        let err_match = {
+            let err_fds = ERR_FDS.read().unwrap();
+            err_fds.contains(&fd_in) || err_fds.contains(&fd_out)
+        };
+        let delayed_fd = {
+            let delay_fds = DELAY_FDS.read().unwrap();
+            [fd_out, fd_in].iter().cloned().find(|fd| delay_fds.contains(fd))
+        };
+        if let Some(err) = delayedErrno!(matched: err_match,
+                                         delayed_fd.is_some(),
+                                         $funcname,
+                                         Selector::Fd(delayed_fd.unwrap_or(fd_out))) {
+            set_errno(err);
+            return SSIZE_ERR;
+        }
+        checkReadOnly!(fd_out, SSIZE_ERR);
+
+        let len = checkConnection!(fd_in, Direction::Recv, $len, 0);
+        let len = checkConnection!(fd_out, Direction::Send, len, 0);
+        let len = throttle::limit(fd_in, len);
+        let len = throttle::limit(fd_out, len);
+        let (len, growth) = checkQuota!(quota::limit_write_at(fd_out, len, $offset_out),
+                                        SSIZE_ERR);
+
+        let ret: ssize_t = $transfer(len);
+        for &fd in &[fd_in, fd_out] {
+            conn::after_io(fd, ret);
+            throttle::after_io(fd, ret);
+        }
+        quota::charge(growth);
+
+        ret
+    }));
+
+/**
+ * @return the offset `offset` points to, if it isn't null.
+ */
+fn offset_at(offset: *const off_t) -> Option<off_t> {
+    if offset.is_null() {
+        None
+    } else {
+        Some(unsafe { *offset })
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn copy_file_range(fd_in: c_int,
+                                  off_in: *mut off_t,
+                                  fd_out: c_int,
+                                  off_out: *mut off_t,
+                                  len: size_t,
+                                  flags: c_uint)
+                                  -> ssize_t {
+    lazy_static! {
+        static ref COPY_FILE_RANGE_FUNC: CopyFileRangeFunc =
+            get_libc_func!(CopyFileRangeFunc, "copy_file_range");
+    }
+
+    do_transfer!("copy_file_range", fd_in, fd_out, len, offset_at(off_out), |len| {
+        COPY_FILE_RANGE_FUNC(fd_in, off_in, fd_out, off_out, len, flags)
+    })
+}
+
+macro_rules! do_sendfile(
+    ($symbol:expr, $out_fd:expr, $in_fd:expr, $offset:expr, $count:expr) =>
+    ({
+        lazy_static! {
+            static ref SENDFILE_FUNC: SendfileFunc = get_libc_func!(SendfileFunc, $symbol);
+        }
+
+        do_transfer!("sendfile", $in_fd, $out_fd, $count, None, |count| {
+            SENDFILE_FUNC($out_fd, $in_fd, $offset, count)
+        })
+    }));
+
+#[no_mangle]
+pub extern "C" fn sendfile(out_fd: c_int, in_fd: c_int, offset: *mut off_t, count: size_t)
+                           -> ssize_t {
+    do_sendfile!("sendfile", out_fd, in_fd, offset, count)
+}
+
+#[no_mangle]
+pub extern "C" fn sendfile64(out_fd: c_int, in_fd: c_int, offset: *mut off_t, count: size_t)
+                             -> ssize_t {
+    do_sendfile!("sendfile64", out_fd, in_fd, offset, count)
+}
+
+#[no_mangle]
+pub extern "C" fn splice(fd_in: c_int,
+                         off_in: *mut off_t,
+                         fd_out: c_int,
+                         off_out: *mut off_t,
+                         len: size_t,
+                         flags: c_uint)
+                         -> ssize_t {
+    lazy_static! {
+        static ref SPLICE_FUNC: SpliceFunc = get_libc_func!(SpliceFunc, "splice");
+    }
+
+    do_transfer!("splice", fd_in, fd_out, len, offset_at(off_out), |len| {
+        SPLICE_FUNC(fd_in, off_in, fd_out, off_out, len, flags)
+    })
+}
+
+// tee() only copies between pipes, so there's no quota or offset.
+#[no_mangle]
+pub extern "C" fn tee(fd_in: c_int, fd_out: c_int, len: size_t, flags: c_uint) -> ssize_t {
+    lazy_static! {
+        static ref TEE_FUNC: TeeFunc = get_libc_func!(TeeFunc, "tee");
+    }
+
+    do_transfer!("tee", fd_in, fd_out, len, None, |len| TEE_FUNC(fd_in, fd_out, len, flags))
+}
+
+// Free blocks are capped at what's left of the quota, and files under
+//   LIBFAULTINJ_STATFS_PATH report what they're told to.  See `fsinfo`.
+macro_rules! do_statfs(
+    (path: $func:expr, $path_:expr, $buf:expr) =>
+    ({
+        let ret = $func($path_, $buf);
+
+        if ret == 0 && !$buf.is_null() {
+            let path = unsafe { c_path($path_) };
+            fsinfo::adjust(unsafe { &mut *$buf },
+                           |block_size| quota::free_blocks_at(&path, block_size),
+                           || Some(path.clone()));
+        }
+
+        ret
+    });
+    (fd: $func:expr, $fd:expr, $buf:expr) =>
+    ({
+        let ret = $func($fd, $buf);
+
+        if ret == 0 && !$buf.is_null() {
+            fsinfo::adjust(unsafe { &mut *$buf },
+                           |block_size| quota::free_blocks($fd, block_size),
+                           || namespace::fd_path($fd));
+        }
+
+        ret
+    }));
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn statfs(path: *const c_char, buf: *mut libc::statfs) -> c_int {
+    lazy_static! {
+        static ref STATFS_FUNC: StatfsFunc = get_libc_func!(StatfsFunc, "statfs");
+    }
+
+    do_statfs!(path: *STATFS_FUNC, path, buf)
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn statfs64(path: *const c_char, buf: *mut libc::statfs64) -> c_int {
+    lazy_static! {
+        static ref STATFS64_FUNC: Statfs64Func = get_libc_func!(Statfs64Func, "statfs64");
+    }
+
+    do_statfs!(path: *STATFS64_FUNC, path, buf)
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn fstatfs(fd: c_int, buf: *mut libc::statfs) -> c_int {
+    lazy_static! {
+        static ref FSTATFS_FUNC: FstatfsFunc = get_libc_func!(FstatfsFunc, "fstatfs");
+    }
+
+    do_statfs!(fd: *FSTATFS_FUNC, fd, buf)
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn fstatfs64(fd: c_int, buf: *mut libc::statfs64) -> c_int {
+    lazy_static! {
+        static ref FSTATFS64_FUNC: Fstatfs64Func = get_libc_func!(Fstatfs64Func, "fstatfs64");
+    }
+
+    do_statfs!(fd: *FSTATFS64_FUNC, fd, buf)
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn statvfs(path: *const c_char, buf: *mut libc::statvfs) -> c_int {
+    lazy_static! {
+        static ref STATVFS_FUNC: StatvfsFunc = get_libc_func!(StatvfsFunc, "statvfs");
+    }
+
+    do_statfs!(path: *STATVFS_FUNC, path, buf)
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn statvfs64(path: *const c_char, buf: *mut libc::statvfs64) -> c_int {
+    lazy_static! {
+        static ref STATVFS64_FUNC: Statvfs64Func = get_libc_func!(Statvfs64Func, "statvfs64");
+    }
+
+    do_statfs!(path: *STATVFS64_FUNC, path, buf)
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn fstatvfs(fd: c_int, buf: *mut libc::statvfs) -> c_int {
+    lazy_static! {
+        static ref FSTATVFS_FUNC: FstatvfsFunc = get_libc_func!(FstatvfsFunc, "fstatvfs");
+    }
+
+    do_statfs!(fd: *FSTATVFS_FUNC, fd, buf)
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn fstatvfs64(fd: c_int, buf: *mut libc::statvfs64) -> c_int {
+    lazy_static! {
+        static ref FSTATVFS64_FUNC: Fstatvfs64Func = get_libc_func!(Fstatvfs64Func, "fstatvfs64");
+    }
+
+    do_statfs!(fd: *FSTATVFS64_FUNC, fd, buf)
+}
+
+// Unlinking a file under quota gives back the room it took.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn unlink(pathname: *const c_char) -> c_int {
+    lazy_static! {
+        static ref UNLINK_FUNC: UnlinkFunc = get_libc_func!(UnlinkFunc, "unlink");
+    }
+
+    let path = unsafe { c_path(pathname) };
+    injectPathFaults!("unlink", -1, path);
+    let unlinked = quota::unlinking(&path);
+
+    let ret = UNLINK_FUNC(pathname);
+    if ret == 0 {
+        quota::forget_path(unlinked);
+    }
+
+    ret
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn unlinkat(dirfd: c_int, pathname: *const c_char, flags: c_int) -> c_int {
+    lazy_static! {
+        static ref UNLINKAT_FUNC: UnlinkatFunc = get_libc_func!(UnlinkatFunc, "unlinkat");
+    }
+
+    let path = unsafe { at_path(dirfd, pathname) };
+    injectPathFaults!("unlinkat", -1, path);
+    let unlinked = quota::unlinking(&path);
+
+    let ret = UNLINKAT_FUNC(dirfd, pathname, flags);
+    if ret == 0 {
+        quota::forget_path(unlinked);
+    }
+
+    ret
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn rmdir(pathname: *const c_char) -> c_int {
+    lazy_static! {
+        static ref RMDIR_FUNC: RmdirFunc = get_libc_func!(RmdirFunc, "rmdir");
+    }
+
+    injectPathFaults!("rmdir", -1, unsafe { c_path(pathname) });
+
+    RMDIR_FUNC(pathname)
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn mkdir(pathname: *const c_char, mode: mode_t) -> c_int {
+    lazy_static! {
+        static ref MKDIR_FUNC: MkdirFunc = get_libc_func!(MkdirFunc, "mkdir");
+    }
+
+    injectPathFaults!("mkdir", -1, unsafe { c_path(pathname) });
+
+    MKDIR_FUNC(pathname, mode)
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn mkdirat(dirfd: c_int, pathname: *const c_char, mode: mode_t) -> c_int {
+    lazy_static! {
+        static ref MKDIRAT_FUNC: MkdiratFunc = get_libc_func!(MkdiratFunc, "mkdirat");
+    }
+
+    injectPathFaults!("mkdirat", -1, unsafe { at_path(dirfd, pathname) });
+
+    MKDIRAT_FUNC(dirfd, pathname, mode)
+}
+
+// Renames under LIBFAULTINJ_LAZY_RENAME_PATH aren't durable until their
+//   directories are fsync()ed.  See `durable`.
+macro_rules! do_rename(
+    ($funcname:expr, $from:expr, $to:expr, $rename_call:expr) =>
+    ({
+        let (from, to) = ($from, $to);
+        injectPathFaults!($funcname, -1, from, to);
+        let rename = durable::renaming(&from, &to);
+
+        let ret = $rename_call;
+        durable::renamed(rename, ret);
+
+        ret
+    }));
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn rename(oldpath: *const c_char, newpath: *const c_char) -> c_int {
+    lazy_static! {
+        static ref RENAME_FUNC: RenameFunc = get_libc_func!(RenameFunc, "rename");
+    }
+
+    do_rename!("rename",
+               unsafe { c_path(oldpath) },
+               unsafe { c_path(newpath) },
+               RENAME_FUNC(oldpath, newpath))
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn renameat(olddirfd: c_int,
+                           oldpath: *const c_char,
+                           newdirfd: c_int,
+                           newpath: *const c_char)
+                           -> c_int {
+    lazy_static! {
+        static ref RENAMEAT_FUNC: RenameatFunc = get_libc_func!(RenameatFunc, "renameat");
+    }
+
+    do_rename!("renameat",
+               unsafe { at_path(olddirfd, oldpath) },
+               unsafe { at_path(newdirfd, newpath) },
+               RENAMEAT_FUNC(olddirfd, oldpath, newdirfd, newpath))
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn renameat2(olddirfd: c_int,
+                            oldpath: *const c_char,
+                            newdirfd: c_int,
+                            newpath: *const c_char,
+                            flags: c_uint)
+                            -> c_int {
+    lazy_static! {
+        static ref RENAMEAT2_FUNC: Renameat2Func = get_libc_func!(Renameat2Func, "renameat2");
+    }
+
+    // Exchanging two files can't be undone by renaming one back.
+    if flags & libc::RENAME_EXCHANGE != 0 {
+        injectPathFaults!("renameat2",
+                          -1,
+                          unsafe { at_path(olddirfd, oldpath) },
+                          unsafe { at_path(newdirfd, newpath) });
+        return RENAMEAT2_FUNC(olddirfd, oldpath, newdirfd, newpath, flags);
+    }
+
+    do_rename!("renameat2",
+               unsafe { at_path(olddirfd, oldpath) },
+               unsafe { at_path(newdirfd, newpath) },
+               RENAMEAT2_FUNC(olddirfd, oldpath, newdirfd, newpath, flags))
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn link(oldpath: *const c_char, newpath: *const c_char) -> c_int {
+    lazy_static! {
+        static ref LINK_FUNC: LinkFunc = get_libc_func!(LinkFunc, "link");
+    }
+
+    injectPathFaults!("link", -1, unsafe { c_path(oldpath) }, unsafe { c_path(newpath) });
+
+    LINK_FUNC(oldpath, newpath)
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn linkat(olddirfd: c_int,
+                         oldpath: *const c_char,
+                         newdirfd: c_int,
+                         newpath: *const c_char,
+                         flags: c_int)
+                         -> c_int {
+    lazy_static! {
+        static ref LINKAT_FUNC: LinkatFunc = get_libc_func!(LinkatFunc, "linkat");
+    }
+
+    injectPathFaults!("linkat",
+                      -1,
+                      unsafe { at_path(olddirfd, oldpath) },
+                      unsafe { at_path(newdirfd, newpath) });
+
+    LINKAT_FUNC(olddirfd, oldpath, newdirfd, newpath, flags)
+}
+
+// A symlink's target is just its contents, so only where it's made is matched.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn symlink(target: *const c_char, linkpath: *const c_char) -> c_int {
+    lazy_static! {
+        static ref SYMLINK_FUNC: SymlinkFunc = get_libc_func!(SymlinkFunc, "symlink");
+    }
+
+    injectPathFaults!("symlink", -1, unsafe { c_path(linkpath) });
+
+    SYMLINK_FUNC(target, linkpath)
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn symlinkat(target: *const c_char,
+                            newdirfd: c_int,
+                            linkpath: *const c_char)
+                            -> c_int {
+    lazy_static! {
+        static ref SYMLINKAT_FUNC: SymlinkatFunc = get_libc_func!(SymlinkatFunc, "symlinkat");
+    }
+
+    injectPathFaults!("symlinkat", -1, unsafe { at_path(newdirfd, linkpath) });
+
+    SYMLINKAT_FUNC(target, newdirfd, linkpath)
+}
+
+// For now we don't intercept these calls for error injection, only to
+//   learn when renames become durable.
+#[no_mangle]
+pub extern "C" fn fsync(fd: c_int) -> c_int {
+    lazy_static! {
+        static ref FSYNC_FUNC: FsyncFunc = get_libc_func!(FsyncFunc, "fsync");
+    }
+
+    let ret = FSYNC_FUNC(fd);
+    if ret == 0 {
+        durable::synced(fd);
+    }
+
+    ret
+}
+
+#[no_mangle]
+pub extern "C" fn fdatasync(fd: c_int) -> c_int {
+    lazy_static! {
+        static ref FDATASYNC_FUNC: FsyncFunc = get_libc_func!(FsyncFunc, "fdatasync");
+    }
+
+    let ret = FDATASYNC_FUNC(fd);
+    if ret == 0 {
+        durable::synced(fd);
+    }
+
+    ret
+}
+
+// DISABLED -- these calls are disabled until problems caused can be addressed
+#[no_mangle]
+#[allow(private_no_mangle_fns)]
+#[allow(dead_code)]
+#[allow(unused_variables)]
+extern "C" fn stat(pathname: *const c_char, buf: *mut libc::stat) -> c_int {
+    lazy_static! {
+        static ref STAT_FUNC: StatFunc = get_libc_func!(StatFunc, "stat"); // fixme
+    }
+    // TO BE IMPLEMENTED
+    STAT_FUNC(pathname, buf) - 1
+}
+
+// The allocator fails with ENOMEM as `oom` decides.  Only what can fail
+//   is intercepted: free() is glibc's as usual.
+macro_rules! failAllocation(
+    ($size:expr, $err:expr) =>
+    ({
+        use errno::{Errno, set_errno};
+
+        if oom::should_fail($size) {
+            set_errno(Errno(libc::ENOMEM));
+            return $err;
+        }
+    }));
+
+#[no_mangle]
+pub extern "C" fn malloc(size: size_t) -> *mut c_void {
+    failAllocation!(size, ptr::null_mut());
+
+    unsafe { oom::__libc_malloc(size) }
+}
+
+#[no_mangle]
+pub extern "C" fn calloc(nmemb: size_t, size: size_t) -> *mut c_void {
+    failAllocation!(nmemb.saturating_mul(size), ptr::null_mut());
+
+    unsafe { oom::__libc_calloc(nmemb, size) }
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn realloc(ptr: *mut c_void, size: size_t) -> *mut c_void {
+    // With a size of 0, it's a free().
+    if ptr.is_null() || size > 0 {
+        failAllocation!(size, ptr::null_mut());
+    }
+
+    unsafe { oom::__libc_realloc(ptr, size) }
+}
+
+#[no_mangle]
+pub extern "C" fn memalign(alignment: size_t, size: size_t) -> *mut c_void {
+    failAllocation!(size, ptr::null_mut());
+
+    unsafe { oom::__libc_memalign(alignment, size) }
+}
+
+#[no_mangle]
+pub extern "C" fn aligned_alloc(alignment: size_t, size: size_t) -> *mut c_void {
+    failAllocation!(size, ptr::null_mut());
+
+    unsafe { oom::__libc_memalign(alignment, size) }
+}
+
+// posix_memalign() returns its error rather than setting errno.
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn posix_memalign(memptr: *mut *mut c_void,
+                                 alignment: size_t,
+                                 size: size_t)
+                                 -> c_int {
+    const PTR_SIZE: size_t = std::mem::size_of::<*mut c_void>();
+
+    if !alignment.is_power_of_two() || !alignment.is_multiple_of(PTR_SIZE) {
+        return libc::EINVAL;
+    }
+    if oom::should_fail(size) {
+        return libc::ENOMEM;
+    }
+
+    let allocated = unsafe { oom::__libc_memalign(alignment, size) };
+    if allocated.is_null() {
+        return libc::ENOMEM;
+    }
+    unsafe { *memptr = allocated };
+
+    0
+}
+
+// Only anonymous mappings are intercepted, as allocations.  glibc's own
+//   __mmap() is called directly: looking up mmap() with dlsym() can
+//   deadlock, as malloc_init_hard()->mmap()->DynamicLibrary::open()->
+//   malloc_init_hard(), at least on systems w/jemalloc.
+macro_rules! do_mmap(
+    ($addr:expr, $length:expr, $prot:expr, $flags:expr, $fd:expr, $offset:expr) =>
+    ({
+        if $flags & libc::MAP_ANONYMOUS != 0 {
+            failAllocation!($length, libc::MAP_FAILED);
+        }
+
+        unsafe { oom::__mmap($addr, $length, $prot, $flags, $fd, $offset) }
+    }));
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn mmap(addr: *mut c_void,
+                       length: size_t,
+                       prot: c_int,
+                       flags: c_int,
+                       fd: c_int,
+                       offset: off_t)
+                       -> *mut c_void {
+    do_mmap!(addr, length, prot, flags, fd, offset)
+}
+
+#[no_mangle]
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub extern "C" fn mmap64(addr: *mut c_void,
+                         length: size_t,
+                         prot: c_int,
+                         flags: c_int,
+                         fd: c_int,
+                         offset: off_t)
+                         -> *mut c_void {
+    do_mmap!(addr, length, prot, flags, fd, offset)
 }
@@ -0,0 +1,76 @@
+extern crate libc;
+
+// A virtual fd limit.  With LIBFAULTINJ_FD_LIMIT set, calls that make new
+//   fds, like open(), socket(), accept(), pipe(), dup(), eventfd() and
+//   epoll_create(), fail with EMFILE, or LIBFAULTINJ_FD_LIMIT_ERRNO, like
+//   ENFILE, when the process would hold more fds than that.  Only the limit
+//   is virtual: RLIMIT_NOFILE is left alone.
+//
+// The fds held are those the hooks saw made and not closed, along with
+//   those already open when the limit was first set.  They're counted in
+//   `errors`, beside the fds selected for faults, so the two agree.  Those
+//   closed inside libc, by fclose() say, never reach the close() hook, so
+//   they're weeded out of both before a call is failed.
+
+use libc::c_int;
+use errors::{get_env_value, open_fd_count};
+
+/**
+ * Returns $err from the hook, with errno set, if making $count more fds
+ *   would go past the limit.
+ */
+macro_rules! checkFdLimit(
+    ($count: expr, $err: expr) =>
+    ({
+        use errno::{Errno, set_errno};
+
+        if let Err(err) = fdlimit::check($count) {
+            set_errno(Errno(err));
+            return $err;
+        }
+    }));
+
+fn fd_limit() -> Option<usize> {
+    get_env_value::<usize>("LIBFAULTINJ_FD_LIMIT")
+}
+
+fn limit_errno() -> c_int {
+    get_env_value::<c_int>("LIBFAULTINJ_FD_LIMIT_ERRNO").unwrap_or(libc::EMFILE)
+}
+
+/**
+ * @return whether `held` fds, and `count` more, fit in `limit`.
+ */
+pub fn fits(held: usize, count: usize, limit: usize) -> bool {
+    held.saturating_add(count) <= limit
+}
+
+/**
+ * @return Err(errno) if making `count` more fds would go past the limit.
+ */
+pub fn check(count: usize) -> Result<(), c_int> {
+    let limit = match fd_limit() {
+        Some(limit) => limit,
+        None => return Ok(()),
+    };
+
+    if fits(open_fd_count(false), count, limit) || fits(open_fd_count(true), count, limit) {
+        Ok(())
+    } else {
+        Err(limit_errno())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::fits;
+
+    #[test]
+    fn test_fits() {
+        assert!(fits(3, 1, 4));
+        assert!(!fits(4, 1, 4));
+        // A pipe needs room for both ends.
+        assert!(!fits(3, 2, 4));
+        assert!(fits(0, 0, 0));
+    }
+}
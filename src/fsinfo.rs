@@ -0,0 +1,296 @@
+extern crate libc;
+
+// Filesystem information.  statfs(), fstatfs(), statvfs() and fstatvfs()
+//   on files under LIBFAULTINJ_STATFS_PATH report the free blocks and
+//   inodes set by LIBFAULTINJ_STATFS_{BAVAIL,FILES,FFREE}, and with
+//   LIBFAULTINJ_STATFS_RDONLY, a read-only filesystem.  With
+//   LIBFAULTINJ_STATFS_RDONLY=erofs, changes to those files then fail with
+//   EROFS too, as after a filesystem is remounted read-only on errors.
+
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+
+use libc::c_int;
+use errors::get_env_value;
+use namespace::fd_path;
+
+/// What statfs() and statvfs() have in common.
+pub trait FsStats {
+    /// The size of the blocks that free blocks are counted in.
+    fn block_size(&self) -> u64;
+    fn free_blocks(&self) -> u64;
+    /// Sets both the free blocks and those available to unprivileged users.
+    fn set_free_blocks(&mut self, blocks: u64);
+    fn free_files(&self) -> u64;
+    fn set_files(&mut self, files: u64);
+    fn set_free_files(&mut self, files: u64);
+    fn set_read_only(&mut self);
+}
+
+macro_rules! impl_statfs(
+    ($statfs:ty, $set_read_only:expr) =>
+    (
+        impl FsStats for $statfs {
+            fn block_size(&self) -> u64 {
+                self.f_bsize as u64
+            }
+
+            fn free_blocks(&self) -> u64 {
+                self.f_bavail as u64
+            }
+
+            fn set_free_blocks(&mut self, blocks: u64) {
+                self.f_bavail = blocks as _;
+                self.f_bfree = blocks as _;
+            }
+
+            fn free_files(&self) -> u64 {
+                self.f_ffree as u64
+            }
+
+            fn set_files(&mut self, files: u64) {
+                self.f_files = files as _;
+            }
+
+            fn set_free_files(&mut self, files: u64) {
+                self.f_ffree = files as _;
+            }
+
+            fn set_read_only(&mut self) {
+                $set_read_only(self)
+            }
+        }
+    ));
+
+macro_rules! impl_statvfs(
+    ($statvfs:ty) =>
+    (
+        impl FsStats for $statvfs {
+            fn block_size(&self) -> u64 {
+                self.f_frsize as u64
+            }
+
+            fn free_blocks(&self) -> u64 {
+                self.f_bavail as u64
+            }
+
+            fn set_free_blocks(&mut self, blocks: u64) {
+                self.f_bavail = blocks as _;
+                self.f_bfree = blocks as _;
+            }
+
+            fn free_files(&self) -> u64 {
+                self.f_favail as u64
+            }
+
+            fn set_files(&mut self, files: u64) {
+                self.f_files = files as _;
+            }
+
+            fn set_free_files(&mut self, files: u64) {
+                self.f_ffree = files as _;
+                self.f_favail = files as _;
+            }
+
+            fn set_read_only(&mut self) {
+                self.f_flag |= libc::ST_RDONLY;
+            }
+        }
+    ));
+
+// The libc crate hides statfs's f_flags in its padding, but glibc always
+//   puts it right after f_frsize, as statfs64 shows.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+impl_statfs!(libc::statfs, |stats: &mut libc::statfs| unsafe {
+    let offset = mem::offset_of!(libc::statfs, f_frsize) + mem::size_of::<libc::__fsword_t>();
+    let f_flags = (stats as *mut libc::statfs as *mut u8).add(offset) as *mut libc::__fsword_t;
+    *f_flags |= libc::ST_RDONLY as libc::__fsword_t;
+});
+impl_statfs!(libc::statfs64, |stats: &mut libc::statfs64| {
+    stats.f_flags |= libc::ST_RDONLY as libc::__fsword_t;
+});
+impl_statvfs!(libc::statvfs);
+impl_statvfs!(libc::statvfs64);
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Spoof {
+    free_blocks: Option<u64>,
+    files: Option<u64>,
+    free_files: Option<u64>,
+    read_only: bool,
+}
+
+impl Spoof {
+    /**
+     * @return what to report for files under LIBFAULTINJ_STATFS_PATH.
+     */
+    pub fn from_env() -> Spoof {
+        Spoof {
+            free_blocks: get_env_value::<u64>("LIBFAULTINJ_STATFS_BAVAIL"),
+            files: get_env_value::<u64>("LIBFAULTINJ_STATFS_FILES"),
+            free_files: get_env_value::<u64>("LIBFAULTINJ_STATFS_FFREE"),
+            read_only: read_only_mode().is_some(),
+        }
+    }
+
+    pub fn apply<S: FsStats>(&self, stats: &mut S) {
+        if let Some(blocks) = self.free_blocks {
+            stats.set_free_blocks(blocks);
+        }
+        if let Some(files) = self.files {
+            stats.set_files(files);
+            // There can't be more free than there are.
+            let free_files = stats.free_files().min(files);
+            stats.set_free_files(free_files);
+        }
+        if let Some(files) = self.free_files {
+            stats.set_free_files(files);
+        }
+        if self.read_only {
+            stats.set_read_only();
+        }
+    }
+}
+
+/**
+ * @return Some(true) if LIBFAULTINJ_STATFS_RDONLY=erofs, Some(false) for
+ *      any other value, or None if it isn't set.
+ */
+fn read_only_mode() -> Option<bool> {
+    match ::std::env::var("LIBFAULTINJ_STATFS_RDONLY") {
+        Ok(mode) => match mode.trim() {
+            "" | "0" => None,
+            "erofs" => Some(true),
+            _ => Some(false),
+        },
+        Err(_) => None,
+    }
+}
+
+/**
+ * @return `path` with symlinks resolved, or its directory's resolved if
+ *      it doesn't exist yet.
+ */
+fn resolve(path: &Path) -> Option<PathBuf> {
+    fs::canonicalize(path).ok().or_else(|| {
+        let dir = match path.parent() {
+            Some(dir) if dir != Path::new("") => dir,
+            _ => Path::new("."),
+        };
+        Some(fs::canonicalize(dir).ok()?.join(path.file_name()?))
+    })
+}
+
+/**
+ * @return true if `path` is under LIBFAULTINJ_STATFS_PATH, as given or
+ *      with symlinks resolved in both.
+ */
+pub fn matches(path: &Path) -> bool {
+    let prefix = match ::std::env::var_os("LIBFAULTINJ_STATFS_PATH") {
+        Some(prefix) => PathBuf::from(prefix),
+        None => return false,
+    };
+    if path.starts_with(&prefix) {
+        return true;
+    }
+    match (resolve(path), fs::canonicalize(&prefix)) {
+        (Some(path), Ok(prefix)) => path.starts_with(prefix),
+        _ => false,
+    }
+}
+
+/**
+ * Caps the free blocks in `stats` at `quota_blocks`, passed the block
+ *   size, then reports what LIBFAULTINJ_STATFS_* say if the file `path`
+ *   returns is under LIBFAULTINJ_STATFS_PATH.
+ */
+pub fn adjust<S, Q, P>(stats: &mut S, quota_blocks: Q, path: P)
+    where S: FsStats,
+          Q: FnOnce(u64) -> Option<u64>,
+          P: FnOnce() -> Option<PathBuf>
+{
+    if let Some(blocks) = quota_blocks(stats.block_size()) {
+        let free_blocks = stats.free_blocks().min(blocks);
+        stats.set_free_blocks(free_blocks);
+    }
+
+    let spoof = Spoof::from_env();
+    if spoof != Spoof::default() && path().is_some_and(|path| matches(&path)) {
+        spoof.apply(stats);
+    }
+}
+
+/**
+ * @return true if changing the file at `path` should fail with EROFS.
+ */
+pub fn read_only_path(path: &Path) -> bool {
+    read_only_mode() == Some(true) && matches(path)
+}
+
+/**
+ * @return true if writing to `fd` should fail with EROFS.
+ */
+pub fn read_only_fd(fd: c_int) -> bool {
+    if read_only_mode() != Some(true) {
+        return false;
+    }
+    // Sockets and pipes have no path to match.
+    fd_path(fd).is_some_and(|path| path.is_absolute() && matches(&path))
+}
+
+/**
+ * Returns $err from the hook, with errno set to EROFS, if $fd is on a
+ *   filesystem that's been made read-only.
+ */
+macro_rules! checkReadOnly(
+    ($fd:expr, $err:expr) =>
+    ({
+        use errno::{Errno, set_errno};
+
+        if fsinfo::read_only_fd($fd) {
+            set_errno(Errno(libc::EROFS));
+            return $err;
+        }
+    }));
+
+#[cfg(test)]
+mod test {
+    use super::{FsStats, Spoof};
+    use std::mem;
+    extern crate libc;
+
+    #[test]
+    fn test_spoof() {
+        let mut stats: libc::statvfs = unsafe { ::std::mem::zeroed() };
+        stats.f_bavail = 100;
+        stats.f_bfree = 120;
+        stats.f_files = 50;
+        stats.f_ffree = 40;
+        stats.f_favail = 40;
+
+        Spoof::default().apply(&mut stats);
+        assert_eq!(stats.f_bfree, 120);
+
+        let spoof = Spoof {
+            free_blocks: Some(7),
+            files: Some(10),
+            free_files: None,
+            read_only: true,
+        };
+        spoof.apply(&mut stats);
+        assert_eq!((stats.f_bavail, stats.f_bfree), (7, 7));
+        assert_eq!((stats.f_files, stats.f_ffree, stats.free_files()), (10, 10, 10));
+        assert_eq!(stats.f_flag & libc::ST_RDONLY, libc::ST_RDONLY);
+    }
+
+    // statfs is statfs64 on 64-bit targets, where the libc crate names f_flags.
+    #[test]
+    #[cfg(all(target_os = "linux", target_env = "gnu", target_pointer_width = "64"))]
+    fn test_statfs_read_only() {
+        let mut stats: libc::statfs = unsafe { mem::zeroed() };
+        stats.set_read_only();
+        let stats: libc::statfs64 = unsafe { mem::transmute(stats) };
+        assert_eq!(stats.f_flags, libc::ST_RDONLY as libc::__fsword_t);
+    }
+}
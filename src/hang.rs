@@ -0,0 +1,280 @@
+extern crate libc;
+
+// Hangs.  LIBFAULTINJ_DELAY_<FUNC>_MS=hang makes a delayed call block
+//   forever, like one on a stuck NFS mount.  Hung calls go on once
+//   released: by the file LIBFAULTINJ_HANG_RELEASE_PATH existing, or by
+//   the signal LIBFAULTINJ_HANG_RELEASE_SIGNAL.  Any other signal that's
+//   handled runs its handler, and then interrupts them with EINTR, or with
+//   SA_RESTART, leaves them hung, as it would a blocked syscall.
+//
+// Which signal arrived decides that, so a hung call waits for the handled
+//   ones with sigtimedwait(), and then queues the one it took again for
+//   the handler to run as usual.
+
+use std::ffi::CString;
+use std::ptr;
+use std::sync::Once;
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+
+use libc::{c_int, c_void};
+use errors::get_env_value;
+
+// How often a hung call checks whether it's been released.
+const RELEASE_CHECK_NS: i64 = 50_000_000;
+
+// Bumped by each release signal.
+static RELEASES: AtomicUsize = AtomicUsize::new(0);
+static INSTALL_HANDLER: Once = Once::new();
+static PREVIOUS_HANDLER: AtomicUsize = AtomicUsize::new(libc::SIG_DFL);
+static PREVIOUS_FLAGS: AtomicI32 = AtomicI32::new(0);
+
+/**
+ * Calls the handler the program had for the release signal, if it had one.
+ */
+fn chain(signo: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    let handler = PREVIOUS_HANDLER.load(Ordering::SeqCst);
+    if handler == libc::SIG_DFL || handler == libc::SIG_IGN {
+        return;
+    }
+
+    unsafe {
+        if PREVIOUS_FLAGS.load(Ordering::SeqCst) & libc::SA_SIGINFO != 0 {
+            let handler: extern "C" fn(c_int, *mut libc::siginfo_t, *mut c_void) =
+                ::std::mem::transmute(handler);
+            handler(signo, info, context);
+        } else {
+            let handler: extern "C" fn(c_int) = ::std::mem::transmute(handler);
+            handler(signo);
+        }
+    }
+}
+
+extern "C" fn on_release_signal(signo: c_int, info: *mut libc::siginfo_t, context: *mut c_void) {
+    RELEASES.fetch_add(1, Ordering::SeqCst);
+    chain(signo, info, context);
+}
+
+/**
+ * @return true if delayed calls of `funcname` should hang.
+ */
+pub fn hangs(funcname: &str) -> bool {
+    let env_name = "LIBFAULTINJ_DELAY_".to_string() + &funcname.to_uppercase() + "_MS";
+
+    match ::std::env::var(env_name) {
+        Ok(ms) => ms.trim() == "hang",
+        Err(_) => false,
+    }
+}
+
+/**
+ * Installs the handler for LIBFAULTINJ_HANG_RELEASE_SIGNAL, the first time
+ *   there is one.  Any handler the program had is called after it.
+ */
+fn install_release_handler() {
+    let signo = match get_env_value::<c_int>("LIBFAULTINJ_HANG_RELEASE_SIGNAL") {
+        Some(signo) if signo > 0 => signo,
+        _ => return,
+    };
+
+    INSTALL_HANDLER.call_once(|| unsafe {
+        let mut action: libc::sigaction = ::std::mem::zeroed();
+        action.sa_sigaction = on_release_signal as extern "C" fn(c_int, *mut libc::siginfo_t,
+                                                                  *mut c_void)
+                                                    as libc::sighandler_t;
+        action.sa_flags = libc::SA_SIGINFO;
+        libc::sigemptyset(&mut action.sa_mask);
+
+        let mut previous: libc::sigaction = ::std::mem::zeroed();
+        if libc::sigaction(signo, &action, &mut previous) == 0 {
+            PREVIOUS_HANDLER.store(previous.sa_sigaction, Ordering::SeqCst);
+            PREVIOUS_FLAGS.store(previous.sa_flags, Ordering::SeqCst);
+        }
+    });
+}
+
+/**
+ * @return the signals that would interrupt a blocked call on this thread
+ *      now: those with a handler that `blocked` doesn't hold back, besides
+ *      the release signal, whose handler is our own.
+ */
+fn handled_signals(blocked: &libc::sigset_t) -> libc::sigset_t {
+    let release = get_env_value::<c_int>("LIBFAULTINJ_HANG_RELEASE_SIGNAL");
+
+    unsafe {
+        let mut handled: libc::sigset_t = ::std::mem::zeroed();
+        libc::sigemptyset(&mut handled);
+        for signo in 1..=libc::SIGRTMAX() {
+            if Some(signo) == release || signo == libc::SIGKILL || signo == libc::SIGSTOP ||
+               libc::sigismember(blocked, signo) == 1 {
+                continue;
+            }
+            let mut action: libc::sigaction = ::std::mem::zeroed();
+            if libc::sigaction(signo, ptr::null(), &mut action) == 0 &&
+               action.sa_sigaction != libc::SIG_DFL &&
+               action.sa_sigaction != libc::SIG_IGN {
+                libc::sigaddset(&mut handled, signo);
+            }
+        }
+        handled
+    }
+}
+
+fn restarts(signo: c_int) -> bool {
+    let mut action: libc::sigaction = unsafe { ::std::mem::zeroed() };
+    let found = unsafe { libc::sigaction(signo, ptr::null(), &mut action) } == 0;
+    found && action.sa_flags & libc::SA_RESTART != 0
+}
+
+/**
+ * Waits up to `timeout` for one of the handled signals, with them blocked
+ *   meanwhile so that sigtimedwait() can take it.  It's then queued again,
+ *   with the same siginfo, so its handler runs once they're unblocked.
+ *
+ * @return the signal that arrived, if any.
+ */
+fn wait_for_signal(timeout: &libc::timespec) -> Option<c_int> {
+    unsafe {
+        let mut blocked: libc::sigset_t = ::std::mem::zeroed();
+        libc::pthread_sigmask(libc::SIG_BLOCK, ptr::null(), &mut blocked);
+        let handled = handled_signals(&blocked);
+
+        libc::pthread_sigmask(libc::SIG_BLOCK, &handled, ptr::null_mut());
+        let mut info: libc::siginfo_t = ::std::mem::zeroed();
+        let signo = libc::sigtimedwait(&handled, &mut info, timeout);
+        if signo > 0 {
+            libc::syscall(libc::SYS_rt_tgsigqueueinfo,
+                          libc::getpid(),
+                          libc::syscall(libc::SYS_gettid),
+                          signo,
+                          &info as *const libc::siginfo_t);
+        }
+        libc::pthread_sigmask(libc::SIG_SETMASK, &blocked, ptr::null_mut());
+
+        Some(signo).filter(|&signo| signo > 0)
+    }
+}
+
+fn released_by_file() -> bool {
+    match ::std::env::var("LIBFAULTINJ_HANG_RELEASE_PATH").map(CString::new) {
+        // access() isn't intercepted, so this can't fault itself.
+        Ok(Ok(path)) => unsafe { libc::access(path.as_ptr(), libc::F_OK) == 0 },
+        _ => false,
+    }
+}
+
+/**
+ * Blocks until released.
+ *
+ * @return Ok once released, or Err(EINTR) if a signal without SA_RESTART
+ *      interrupted it.
+ */
+pub fn hang() -> Result<(), c_int> {
+    install_release_handler();
+    let generation = RELEASES.load(Ordering::SeqCst);
+    let released = || RELEASES.load(Ordering::SeqCst) != generation || released_by_file();
+
+    let tick = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: RELEASE_CHECK_NS,
+    };
+    while !released() {
+        // Its handler has run by now, so it's asked about SA_RESTART
+        //   afterwards, as the kernel would.
+        if let Some(signo) = wait_for_signal(&tick) {
+            if !restarts(signo) && !released() {
+                return Err(libc::EINTR);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hang, hangs};
+    use std::{env, fs, mem, ptr, thread};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+    extern crate libc;
+
+    static CAUGHT: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn on_signal(_: libc::c_int) {
+        CAUGHT.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn handle(signo: libc::c_int, flags: libc::c_int) {
+        unsafe {
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+            action.sa_flags = flags;
+            libc::sigemptyset(&mut action.sa_mask);
+            libc::sigaction(signo, &action, ptr::null_mut());
+        }
+    }
+
+    fn signal_after(signo: libc::c_int, delay: Duration) -> thread::JoinHandle<()> {
+        let me = unsafe { libc::pthread_self() };
+        thread::spawn(move || {
+            thread::sleep(delay);
+            unsafe { libc::pthread_kill(me, signo) };
+        })
+    }
+
+    #[test]
+    fn test_hang_released() {
+        env::set_var("LIBFAULTINJ_DELAY_HANGTEST_MS", " hang");
+        assert!(hangs("hangtest"));
+        assert!(!hangs("nothangtest"));
+        env::remove_var("LIBFAULTINJ_DELAY_HANGTEST_MS");
+
+        let release_path = env::temp_dir().join(format!("faultinj-release-{}", unsafe {
+            libc::getpid()
+        }));
+        let _ = fs::remove_file(&release_path);
+        env::set_var("LIBFAULTINJ_HANG_RELEASE_PATH", &release_path);
+
+        let start = Instant::now();
+        let creator = {
+            let release_path = release_path.clone();
+            thread::spawn(move || {
+                thread::sleep(Duration::from_millis(100));
+                fs::write(&release_path, b"").unwrap();
+            })
+        };
+        assert_eq!(hang(), Ok(()));
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        creator.join().unwrap();
+
+        // Calls go straight through while the file's there.
+        assert_eq!(hang(), Ok(()));
+        fs::remove_file(&release_path).unwrap();
+
+        // A handler with SA_RESTART runs, and leaves the call hung.
+        handle(libc::SIGUSR2, libc::SA_RESTART);
+        let signaller = signal_after(libc::SIGUSR2, Duration::from_millis(50));
+        let creator = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            fs::write(&release_path, b"").unwrap();
+            release_path
+        });
+        assert_eq!(hang(), Ok(()));
+        assert_eq!(CAUGHT.load(Ordering::SeqCst), 1);
+        signaller.join().unwrap();
+        fs::remove_file(creator.join().unwrap()).unwrap();
+
+        // One without interrupts it.
+        handle(libc::SIGWINCH, 0);
+        let signaller = signal_after(libc::SIGWINCH, Duration::from_millis(50));
+        assert_eq!(hang(), Err(libc::EINTR));
+        assert_eq!(CAUGHT.load(Ordering::SeqCst), 2);
+        signaller.join().unwrap();
+
+        unsafe {
+            libc::signal(libc::SIGUSR2, libc::SIG_DFL);
+            libc::signal(libc::SIGWINCH, libc::SIG_DFL);
+        }
+        env::remove_var("LIBFAULTINJ_HANG_RELEASE_PATH");
+    }
+}
@@ -0,0 +1,135 @@
+extern crate libc;
+
+// Inherited fds.  stdin, stdout, stderr and whatever else a parent process
+//   or systemd passed in were opened before the library was loaded, so the
+//   open() and socket() hooks never saw them.  They're picked out once, when
+//   the library loads:
+//
+//   - LIBFAULTINJ_ERROR_FD and LIBFAULTINJ_DELAY_FD list fds by number, or
+//     as stdin, stdout and stderr, or `inherited` for every one open then.
+//   - An fd whose /proc/self/fd link leads under LIBFAULTINJ_ERROR_PATH or
+//     LIBFAULTINJ_DELAY_PATH is selected as though it had been opened there,
+//     like a shell's redirection, or /dev/pts for a terminal.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use libc::c_int;
+use errors::{self, ERR_FDS, DELAY_FDS};
+use oom;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FdSelector {
+    Fd(c_int),
+    /// Every fd open when the library was loaded.
+    Inherited,
+}
+
+impl FdSelector {
+    pub fn parse(selector: &str) -> Option<FdSelector> {
+        match &*selector.trim().to_lowercase() {
+            "stdin" => Some(FdSelector::Fd(libc::STDIN_FILENO)),
+            "stdout" => Some(FdSelector::Fd(libc::STDOUT_FILENO)),
+            "stderr" => Some(FdSelector::Fd(libc::STDERR_FILENO)),
+            "inherited" => Some(FdSelector::Inherited),
+            number => number.parse::<c_int>().ok().filter(|&fd| fd >= 0).map(FdSelector::Fd),
+        }
+    }
+
+    pub fn matches(&self, fd: c_int) -> bool {
+        match *self {
+            FdSelector::Fd(selected) => selected == fd,
+            FdSelector::Inherited => true,
+        }
+    }
+}
+
+/**
+ * @return every selector that could be parsed from the comma-separated list
+ *      `selectors`.  Unparseable entries are ignored.
+ */
+pub fn parse_selectors(selectors: &str) -> Vec<FdSelector> {
+    selectors.split(',').filter_map(FdSelector::parse).collect()
+}
+
+/**
+ * @return true if the inherited `fd`, which leads to `target`, is selected
+ *      by the fd selectors in std::env::var(`fd_env`) or lies under
+ *      std::env::var(`path_env`).
+ */
+fn selected(fd: c_int, target: Option<&Path>, fd_env: &str, path_env: &str) -> bool {
+    let by_number = match env::var(fd_env) {
+        Ok(selectors) => parse_selectors(&selectors).iter().any(|s| s.matches(fd)),
+        Err(_) => false,
+    };
+    let by_path = match (target, env::var(path_env)) {
+        // The link is absolute, where the selector may well not be.
+        (Some(target), Ok(path)) => {
+            let path = Path::new(&path);
+            target.starts_with(path) ||
+            fs::canonicalize(path).map(|path| target.starts_with(path)).unwrap_or(false)
+        }
+        _ => false,
+    };
+    by_number || by_path
+}
+
+/**
+ * @return the fds open now, with where /proc/self/fd says they lead.
+ */
+fn open_fds() -> Vec<(c_int, Option<PathBuf>)> {
+    errors::open_fds()
+        .into_iter()
+        .map(|fd| (fd, fs::read_link(format!("/proc/self/fd/{}", fd)).ok()))
+        .collect()
+}
+
+/**
+ * Adds the inherited fds that are selected to ERR_FDS and DELAY_FDS.
+ */
+pub fn select_inherited() {
+    const ENV_NAMES: &[&str] = &["LIBFAULTINJ_ERROR_FD",
+                                 "LIBFAULTINJ_DELAY_FD",
+                                 "LIBFAULTINJ_ERROR_PATH",
+                                 "LIBFAULTINJ_DELAY_PATH"];
+    if !ENV_NAMES.iter().any(|name| env::var_os(name).is_some()) {
+        return;
+    }
+
+    for (fd, target) in open_fds() {
+        let target = target.as_deref();
+        if selected(fd, target, "LIBFAULTINJ_ERROR_FD", "LIBFAULTINJ_ERROR_PATH") {
+            ERR_FDS.write().unwrap().insert(fd);
+        }
+        if selected(fd, target, "LIBFAULTINJ_DELAY_FD", "LIBFAULTINJ_DELAY_PATH") {
+            DELAY_FDS.write().unwrap().insert(fd);
+        }
+    }
+}
+
+extern "C" fn init() {
+    // This runs before main(), and the library's own reading of the fds
+    //   and the environment mustn't be failed, or faulted by its hooks.
+    let _suspended = oom::suspend();
+    select_inherited();
+}
+
+// Runs `init` as the library's loaded, before the program's own code.
+#[used]
+#[link_section = ".init_array"]
+static INIT: extern "C" fn() = init;
+
+#[cfg(test)]
+mod test {
+    use super::{FdSelector, parse_selectors};
+
+    #[test]
+    fn test_fd_selectors() {
+        assert_eq!(parse_selectors(" stdout,2, Inherited,-1,tty"),
+                   vec![FdSelector::Fd(1), FdSelector::Fd(2), FdSelector::Inherited]);
+        assert!(FdSelector::parse("STDIN").unwrap().matches(0));
+        assert!(!FdSelector::Fd(1).matches(2));
+        assert!(FdSelector::Inherited.matches(7));
+    }
+}
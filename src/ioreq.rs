@@ -0,0 +1,193 @@
+extern crate libc;
+
+// Ioctl requests.  ioctl() on fds in ERR_FDS fails with
+//   LIBFAULTINJ_ERROR_IOCTL_ERRNO, or is delayed on fds in DELAY_FDS, for
+//   any request unless LIBFAULTINJ_IOCTL_REQUESTS narrows it to those it
+//   lists, by name, like FIONREAD or BLKGETSIZE64, or by number, like
+//   0x541b.
+//
+// Requests that report a value can also be faked on fds in ERR_FDS:
+//   LIBFAULTINJ_IOCTL_VALUES lists NAME=VALUE pairs, like FIONREAD=0 or
+//   TIOCGWINSZ=24x80, and those requests get VALUE without reaching the
+//   kernel.  Requests given by number are faked as ints.
+
+use std::ptr;
+
+use libc::{c_int, c_ulong, c_void};
+use errors::ERR_FDS;
+
+/// What a request writes through its argument.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Output {
+    Nothing,
+    Int,
+    Ulong,
+    U64,
+    /// struct winsize, faked from ROWSxCOLS.
+    Winsize,
+}
+
+// The asm-generic encoding, as x86 and arm use; see <asm-generic/ioctl.h>.
+const fn ioc(dir: c_ulong, type_: c_ulong, nr: c_ulong, size: usize) -> c_ulong {
+    dir << 30 | (size as c_ulong) << 16 | type_ << 8 | nr
+}
+const IOC_WRITE: c_ulong = 1;
+const IOC_READ: c_ulong = 2;
+
+const BLKGETSIZE: c_ulong = ioc(0, 0x12, 96, 0);
+const BLKGETSIZE64: c_ulong = ioc(IOC_READ, 0x12, 114, 8);
+const FICLONE: c_ulong = ioc(IOC_WRITE, 0x94, 9, 4);
+const FICLONERANGE: c_ulong = ioc(IOC_WRITE, 0x94, 13, 32);
+const FIDEDUPERANGE: c_ulong = ioc(IOC_READ | IOC_WRITE, 0x94, 54, 24);
+
+const REQUESTS: &[(&str, c_ulong, Output)] = &[
+    ("fionread", libc::FIONREAD, Output::Int),
+    ("tiocinq", libc::TIOCINQ, Output::Int),
+    ("tiocoutq", libc::TIOCOUTQ, Output::Int),
+    ("fionbio", libc::FIONBIO, Output::Nothing),
+    ("fioclex", libc::FIOCLEX, Output::Nothing),
+    ("fionclex", libc::FIONCLEX, Output::Nothing),
+    ("tcgets", libc::TCGETS, Output::Nothing),
+    ("tiocgwinsz", libc::TIOCGWINSZ, Output::Winsize),
+    ("tiocswinsz", libc::TIOCSWINSZ, Output::Nothing),
+    ("blkgetsize", BLKGETSIZE, Output::Ulong),
+    ("blkgetsize64", BLKGETSIZE64, Output::U64),
+    ("blksszget", libc::BLKSSZGET, Output::Int),
+    ("blkpbszget", libc::BLKPBSZGET, Output::Int),
+    ("ficlone", FICLONE, Output::Nothing),
+    ("ficlonerange", FICLONERANGE, Output::Nothing),
+    ("fideduperange", FIDEDUPERANGE, Output::Nothing),
+];
+
+/**
+ * @return whether requests `a` and `b` are the same.  Only the low 32 bits
+ *      count, as in the kernel, since callers that pass them as an int
+ *      sign-extend the ones with the top bit set.
+ */
+fn same_request(a: c_ulong, b: c_ulong) -> bool {
+    a as u32 == b as u32
+}
+
+/**
+ * @return the request `name` stands for, with what it writes, or None if
+ *      it's not one we know.
+ */
+pub fn parse_request(name: &str) -> Option<(c_ulong, Output)> {
+    let name = name.trim().to_lowercase();
+    let number = match name.strip_prefix("0x") {
+        Some(hex) => c_ulong::from_str_radix(hex, 16).ok(),
+        None => name.parse::<c_ulong>().ok(),
+    };
+    if let Some(number) = number {
+        return Some((number, Output::Int));
+    }
+
+    REQUESTS.iter()
+            .find(|&&(known, _, _)| known == name)
+            .map(|&(_, request, output)| (request, output))
+}
+
+/// A faked value, as it's written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    Number(u64),
+    Winsize(u16, u16),
+}
+
+fn parse_value(value: &str, output: Output) -> Option<Value> {
+    let value = value.trim();
+    match output {
+        Output::Nothing => None,
+        Output::Winsize => {
+            let x = value.find('x')?;
+            let rows = value[..x].trim().parse().ok()?;
+            let cols = value[x + 1..].trim().parse().ok()?;
+            Some(Value::Winsize(rows, cols))
+        }
+        _ => value.parse::<u64>().ok().map(Value::Number),
+    }
+}
+
+/**
+ * @return the requests and values in the comma-separated NAME=VALUE list
+ *      `values`.  Unparseable entries, and requests that report nothing,
+ *      are ignored.
+ */
+pub fn parse_values(values: &str) -> Vec<(c_ulong, Output, Value)> {
+    values.split(',')
+          .filter_map(|entry| {
+              let eq = entry.find('=')?;
+              let (request, output) = parse_request(&entry[..eq])?;
+              Some((request, output, parse_value(&entry[eq + 1..], output)?))
+          })
+          .collect()
+}
+
+/**
+ * @return true if faults in ioctl() apply to `request`.
+ */
+pub fn request_selected(request: c_ulong) -> bool {
+    match ::std::env::var("LIBFAULTINJ_IOCTL_REQUESTS") {
+        Ok(names) => {
+            names.split(',')
+                 .filter_map(parse_request)
+                 .any(|(known, _)| same_request(known, request))
+        }
+        Err(_) => true,
+    }
+}
+
+/**
+ * Fakes `request` on `fd` if it's in ERR_FDS and LIBFAULTINJ_IOCTL_VALUES
+ *   gives it a value, writing that through `argp`.
+ *
+ * @return Some(return value) if it was faked, None otherwise.
+ */
+pub unsafe fn fake(fd: c_int, request: c_ulong, argp: *mut c_void) -> Option<c_int> {
+    let values = parse_values(&::std::env::var("LIBFAULTINJ_IOCTL_VALUES").ok()?);
+    let &(_, output, value) = values.iter().find(|&&(known, _, _)| same_request(known, request))?;
+    if argp.is_null() || !ERR_FDS.read().unwrap().contains(&fd) {
+        return None;
+    }
+
+    unsafe {
+        match (output, value) {
+            (Output::Int, Value::Number(n)) => ptr::write_unaligned(argp as *mut c_int, n as c_int),
+            (Output::Ulong, Value::Number(n)) => {
+                ptr::write_unaligned(argp as *mut c_ulong, n as c_ulong)
+            }
+            (Output::U64, Value::Number(n)) => ptr::write_unaligned(argp as *mut u64, n),
+            (Output::Winsize, Value::Winsize(rows, cols)) => {
+                let size = libc::winsize {
+                    ws_row: rows,
+                    ws_col: cols,
+                    ws_xpixel: 0,
+                    ws_ypixel: 0,
+                };
+                ptr::write_unaligned(argp as *mut libc::winsize, size)
+            }
+            _ => return None,
+        }
+    }
+    Some(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Output, Value, parse_request, parse_values, same_request, BLKGETSIZE64};
+    extern crate libc;
+
+    #[test]
+    fn test_parse_values() {
+        assert_eq!(BLKGETSIZE64, 0x80081272);
+        assert_eq!(parse_request(" FIONREAD"), Some((libc::FIONREAD, Output::Int)));
+        assert_eq!(parse_request("0x541b"), Some((0x541b, Output::Int)));
+        assert_eq!(parse_request("21531"), Some((0x541b, Output::Int)));
+        assert_eq!(parse_request("TIOCBOGUS"), None);
+        assert!(same_request(BLKGETSIZE64, 0xffffffff80081272));
+
+        assert_eq!(parse_values("FIONREAD=0, TIOCGWINSZ = 24x80,FIOCLEX=1,BLKGETSIZE64=x"),
+                   vec![(libc::FIONREAD, Output::Int, Value::Number(0)),
+                        (libc::TIOCGWINSZ, Output::Winsize, Value::Winsize(24, 80))]);
+    }
+}
@@ -0,0 +1,356 @@
+extern crate libc;
+
+// Socket kinds.  socket() is selected by what it's asked to make rather
+//   than by a path or address: LIBFAULTINJ_{ERROR,DELAY}_SOCKET_KIND are
+//   comma-separated selectors of the form FAMILY[:TYPE[:PROTOCOL]], like
+//
+//     inet6    unix:stream    inet:dgram:udp    *:raw    netlink:*:15
+//
+//   Names can be given as in C, AF_INET or SOCK_DGRAM, or as numbers.
+//   socket() fails with LIBFAULTINJ_ERROR_SOCKET_ERRNO, or is delayed, when
+//   one matches, and the socket it makes is selected from the start, as if
+//   it had been bound or connected to a selected address.
+//
+// Fds of other kinds, the channels processes and threads talk over, are
+//   selected by LIBFAULTINJ_{ERROR,DELAY}_FD_KIND, comma-separated lists of
+//
+//     pipe  fifo  socketpair  socket  eventfd  signalfd  timerfd
+//
+//   The fds made by pipe(), socketpair() and the rest are then in ERR_FDS
+//   or DELAY_FDS from the start, both ends of a pair, as are FIFOs once
+//   they're opened.
+//
+// The kind of each fd made is remembered, so it's known without asking
+//   the kernel.
+
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libc::{c_int, c_void};
+use errors::{SomeHashState, FstatFunc, ERR_FDS, DELAY_FDS};
+
+const ERROR_FD_KIND: &str = "LIBFAULTINJ_ERROR_FD_KIND";
+const DELAY_FD_KIND: &str = "LIBFAULTINJ_DELAY_FD_KIND";
+
+// Spares close() a lock until an fd's been made.
+static TRACKING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref KINDS: RwLock<HashMap<c_int, FdKind, SomeHashState>>
+            = RwLock::new(HashMap::with_hasher(SomeHashState::default()));
+    static ref FSTAT_FUNC: FstatFunc = get_libc_func!(FstatFunc, "fstat");
+}
+
+/// What an fd was made as.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FdKind {
+    Socket(SocketKind),
+    /// Either end of a socketpair().
+    SocketPair(SocketKind),
+    /// Either end of a pipe().
+    Pipe,
+    Fifo,
+    Eventfd,
+    Signalfd,
+    Timerfd,
+}
+
+impl FdKind {
+    /**
+     * @return the kind named `name` in LIBFAULTINJ_{ERROR,DELAY}_FD_KIND,
+     *      as it's matched: sockets stand for any socket.  It can also be
+     *      written `kind=NAME`.
+     */
+    fn parse(name: &str) -> Option<FdKind> {
+        let any_socket = SocketKind::new(0, 0, 0);
+        let name = name.trim().to_lowercase();
+        match name.trim_start_matches("kind=") {
+            "socket" => Some(FdKind::Socket(any_socket)),
+            "socketpair" => Some(FdKind::SocketPair(any_socket)),
+            "pipe" => Some(FdKind::Pipe),
+            "fifo" => Some(FdKind::Fifo),
+            "eventfd" => Some(FdKind::Eventfd),
+            "signalfd" => Some(FdKind::Signalfd),
+            "timerfd" => Some(FdKind::Timerfd),
+            _ => None,
+        }
+    }
+
+    /**
+     * @return true if `self` is of the kind `named`, whatever kind of
+     *      socket it is.
+     */
+    pub fn is(&self, named: &FdKind) -> bool {
+        match (*self, *named) {
+            (FdKind::Socket(_), FdKind::Socket(_)) |
+            (FdKind::SocketPair(_), FdKind::SocketPair(_)) => true,
+            (kind, named) => kind == named,
+        }
+    }
+
+    pub fn socket_kind(&self) -> Option<SocketKind> {
+        match *self {
+            FdKind::Socket(kind) | FdKind::SocketPair(kind) => Some(kind),
+            _ => None,
+        }
+    }
+
+    /**
+     * @return false for fds that must be read and written whole, like an
+     *      eventfd()'s eight byte counter.
+     */
+    pub fn is_stream(&self) -> bool {
+        match *self {
+            FdKind::Socket(kind) | FdKind::SocketPair(kind) => kind.type_ == libc::SOCK_STREAM,
+            FdKind::Pipe | FdKind::Fifo => true,
+            FdKind::Eventfd | FdKind::Signalfd | FdKind::Timerfd => false,
+        }
+    }
+}
+
+/// What socket() was asked to make.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SocketKind {
+    pub family: c_int,
+    pub type_: c_int,
+    pub protocol: c_int,
+}
+
+impl SocketKind {
+    /**
+     * @return the kind of socket `socket(domain, type_, protocol)` makes,
+     *      without SOCK_NONBLOCK or SOCK_CLOEXEC, and with the protocol an
+     *      AF_INET{,6} socket gets by default filled in.
+     */
+    pub fn new(domain: c_int, type_: c_int, protocol: c_int) -> SocketKind {
+        let type_ = type_ & !(libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC);
+        let inet = domain == libc::AF_INET || domain == libc::AF_INET6;
+        let protocol = match type_ {
+            libc::SOCK_STREAM if inet && protocol == 0 => libc::IPPROTO_TCP,
+            libc::SOCK_DGRAM if inet && protocol == 0 => libc::IPPROTO_UDP,
+            _ => protocol,
+        };
+
+        SocketKind {
+            family: domain,
+            type_,
+            protocol,
+        }
+    }
+}
+
+/// A single selector, as found in `LIBFAULTINJ_{ERROR,DELAY}_SOCKET_KIND`.
+///   None matches anything.
+#[derive(Debug, Default, PartialEq)]
+pub struct KindSelector {
+    family: Option<c_int>,
+    type_: Option<c_int>,
+    protocol: Option<c_int>,
+}
+
+/**
+ * @return `part` as a number or one of `names`, ignoring case and any of
+ *      the C `prefixes`; Some(None) for `*`; or None if it's neither.
+ */
+fn parse_part(part: &str, prefixes: &[&str], names: &[(&str, c_int)]) -> Option<Option<c_int>> {
+    let part = part.trim().to_lowercase();
+    if part == "*" {
+        return Some(None);
+    }
+    if let Ok(number) = part.parse::<c_int>() {
+        return Some(Some(number));
+    }
+
+    let name = prefixes.iter()
+                       .find(|prefix| part.starts_with(*prefix))
+                       .map_or(&part[..], |prefix| &part[prefix.len()..]);
+    names.iter().find(|&&(known, _)| known == name).map(|&(_, value)| Some(value))
+}
+
+impl KindSelector {
+    pub fn parse(selector: &str) -> Option<KindSelector> {
+        const FAMILIES: &[(&str, c_int)] = &[("inet", libc::AF_INET),
+                                             ("inet6", libc::AF_INET6),
+                                             ("unix", libc::AF_UNIX),
+                                             ("local", libc::AF_UNIX),
+                                             ("netlink", libc::AF_NETLINK),
+                                             ("packet", libc::AF_PACKET)];
+        const TYPES: &[(&str, c_int)] = &[("stream", libc::SOCK_STREAM),
+                                          ("dgram", libc::SOCK_DGRAM),
+                                          ("seqpacket", libc::SOCK_SEQPACKET),
+                                          ("raw", libc::SOCK_RAW)];
+        const PROTOCOLS: &[(&str, c_int)] = &[("tcp", libc::IPPROTO_TCP),
+                                              ("udp", libc::IPPROTO_UDP),
+                                              ("icmp", libc::IPPROTO_ICMP),
+                                              ("icmpv6", libc::IPPROTO_ICMPV6),
+                                              ("sctp", libc::IPPROTO_SCTP)];
+
+        let mut parts = selector.split(':');
+        let family = parse_part(parts.next()?, &["af_", "pf_"], FAMILIES)?;
+        let type_ = match parts.next() {
+            Some(part) => parse_part(part, &["sock_"], TYPES)?,
+            None => None,
+        };
+        let protocol = match parts.next() {
+            Some(part) => parse_part(part, &["ipproto_"], PROTOCOLS)?,
+            None => None,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+
+        Some(KindSelector {
+            family,
+            type_,
+            protocol,
+        })
+    }
+
+    pub fn matches(&self, kind: &SocketKind) -> bool {
+        self.family.is_none_or(|family| family == kind.family) &&
+        self.type_.is_none_or(|type_| type_ == kind.type_) &&
+        self.protocol.is_none_or(|protocol| protocol == kind.protocol)
+    }
+}
+
+/**
+ * @return every selector that could be parsed from the comma-separated
+ *      list `selectors`.  Unparseable entries are ignored.
+ */
+pub fn parse_selectors(selectors: &str) -> Vec<KindSelector> {
+    selectors.split(',')
+             .filter(|s| !s.trim().is_empty())
+             .filter_map(KindSelector::parse)
+             .collect()
+}
+
+/**
+ * @return true if `kind` matches any of the selectors in
+ *      std::env::var(`env_name`).
+ */
+pub fn matches_kind(kind: &SocketKind, env_name: &str) -> bool {
+    match ::std::env::var(env_name) {
+        Ok(p) => parse_selectors(&p).iter().any(|selector| selector.matches(kind)),
+        Err(_) => false,
+    }
+}
+
+/**
+ * @return true if `kind` is listed in std::env::var(`env_name`).
+ */
+pub fn matches_fd_kind(kind: &FdKind, env_name: &str) -> bool {
+    match ::std::env::var(env_name) {
+        Ok(p) => p.split(',').filter_map(FdKind::parse).any(|named| kind.is(&named)),
+        Err(_) => false,
+    }
+}
+
+/**
+ * Remembers that `fd` was made as `kind`, and selects it if
+ *   LIBFAULTINJ_{ERROR,DELAY}_FD_KIND list its kind.
+ */
+pub fn track_fd(fd: c_int, kind: FdKind) {
+    if fd < 0 {
+        return;
+    }
+    TRACKING.store(true, Ordering::Relaxed);
+    KINDS.write().unwrap().insert(fd, kind);
+
+    if matches_fd_kind(&kind, ERROR_FD_KIND) {
+        ERR_FDS.write().unwrap().insert(fd);
+    }
+
+    if matches_fd_kind(&kind, DELAY_FD_KIND) {
+        DELAY_FDS.write().unwrap().insert(fd);
+    }
+}
+
+/**
+ * Tracks `fd`, just opened, if it's a FIFO and FIFOs might be selected.
+ */
+pub fn track_opened(fd: c_int) {
+    if fd < 0 ||
+       (::std::env::var_os(ERROR_FD_KIND).is_none() &&
+        ::std::env::var_os(DELAY_FD_KIND).is_none()) {
+        return;
+    }
+
+    let mut st: libc::stat = unsafe { ::std::mem::zeroed() };
+    if FSTAT_FUNC(fd, &mut st) == 0 && st.st_mode & libc::S_IFMT == libc::S_IFIFO {
+        track_fd(fd, FdKind::Fifo);
+    }
+}
+
+/**
+ * @return what `fd` was made as, if the hooks saw it made.
+ */
+pub fn fd_kind(fd: c_int) -> Option<FdKind> {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return None;
+    }
+    KINDS.read().unwrap().get(&fd).cloned()
+}
+
+pub fn dup_fd(oldfd: c_int, newfd: c_int) {
+    if !TRACKING.load(Ordering::Relaxed) || oldfd == newfd || newfd < 0 {
+        return;
+    }
+    let mut kinds = KINDS.write().unwrap();
+    match kinds.get(&oldfd).cloned() {
+        Some(kind) => kinds.insert(newfd, kind),
+        None => kinds.remove(&newfd),
+    };
+}
+
+pub fn forget_fd(fd: c_int) {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    KINDS.write().unwrap().remove(&fd);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{FdKind, KindSelector, SocketKind, parse_selectors};
+    extern crate libc;
+
+    #[test]
+    fn test_kind_selectors() {
+        let tcp = SocketKind::new(libc::AF_INET, libc::SOCK_STREAM | libc::SOCK_NONBLOCK, 0);
+        let udp6 = SocketKind::new(libc::AF_INET6, libc::SOCK_DGRAM, 0);
+        let unix = SocketKind::new(libc::AF_UNIX, libc::SOCK_STREAM, 0);
+        assert_eq!((tcp.type_, tcp.protocol), (libc::SOCK_STREAM, libc::IPPROTO_TCP));
+        assert_eq!(unix.protocol, 0);
+
+        assert_eq!(KindSelector::parse("AF_INET:sock_stream:*"),
+                   KindSelector::parse("inet:stream"));
+        assert_eq!(KindSelector::parse("bogus"), None);
+        assert_eq!(KindSelector::parse("inet:stream:tcp:more"), None);
+
+        let selectors = parse_selectors("inet:*:tcp, unix:dgram,bogus:entry");
+        assert_eq!(selectors.len(), 2);
+        assert!(selectors.iter().any(|s| s.matches(&tcp)));
+        assert!(!selectors.iter().any(|s| s.matches(&udp6)));
+        assert!(!selectors.iter().any(|s| s.matches(&unix)));
+
+        let everything = parse_selectors("*");
+        assert!(everything.iter().all(|s| s.matches(&udp6)));
+        assert!(parse_selectors("10").iter().any(|s| s.matches(&udp6)));
+    }
+
+    #[test]
+    fn test_fd_kinds() {
+        let pair = FdKind::SocketPair(SocketKind::new(libc::AF_UNIX, libc::SOCK_DGRAM, 0));
+        assert!(pair.is(&FdKind::parse(" SocketPair").unwrap()));
+        assert!(!pair.is(&FdKind::parse("socket").unwrap()));
+        assert!(FdKind::Pipe.is(&FdKind::parse("pipe").unwrap()));
+        assert_eq!(FdKind::parse("kind=pipe"), Some(FdKind::Pipe));
+        assert_eq!(FdKind::parse("pipes"), None);
+
+        assert!(!pair.is_stream());
+        assert!(FdKind::Fifo.is_stream());
+        assert!(!FdKind::Eventfd.is_stream());
+        assert_eq!(FdKind::Timerfd.socket_kind(), None);
+    }
+}
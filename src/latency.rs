@@ -0,0 +1,350 @@
+extern crate libc;
+
+// Latency distributions.  Rather than the fixed LIBFAULTINJ_DELAY_<FUNC>_MS,
+//   a delay can be drawn from a distribution, given in milliseconds as one
+//   of
+//
+//     fixed:MS  uniform:MIN,MAX  normal:MEAN,STDDEV  exp:MEAN
+//     pareto:MIN,SHAPE  histogram:FILE
+//
+//   The first of these that's set decides a call's delay:
+//
+//     LIBFAULTINJ_DELAY_<FUNC>_DIST
+//     LIBFAULTINJ_DELAY_<FUNC>_MS
+//     LIBFAULTINJ_DELAY_PATH_DIST or LIBFAULTINJ_DELAY_ADDR_DIST, for fds
+//       selected by that selector
+//     LIBFAULTINJ_DELAY_DIST
+//
+//   falling back on 200ms.
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::thread::sleep;
+use std::time::Duration;
+
+use libc::c_int;
+use rand::Rng;
+use rand::distributions::{Exp, IndependentSample, Normal};
+use errors::{SomeHashState, get_env_value, get_socket_type};
+use hang::{hang, hangs};
+use nonblock;
+
+pub const DEFAULT_DELAY_MS: u64 = 200;
+
+thread_local! {
+    // Set while a histogram file is read, through our own open() and read().
+    static LOADING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Delays and their weights.
+pub type Bins = Arc<Vec<(f64, f64)>>;
+
+lazy_static! {
+    static ref HISTOGRAMS: Mutex<HashMap<String, Bins, SomeHashState>>
+            = Mutex::new(HashMap::with_hasher(SomeHashState::default()));
+}
+
+/// What selected the fd or call being delayed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Selector {
+    /// Either selector: LIBFAULTINJ_DELAY_ADDR for sockets,
+    ///   LIBFAULTINJ_DELAY_PATH for anything else.
+    Fd(c_int),
+    Addr,
+    /// Calls about many fds, like poll().
+    Any,
+}
+
+impl Selector {
+    /**
+     * @return the LIBFAULTINJ_DELAY_{PATH,ADDR}_DIST that applies, if any.
+     */
+    fn dist_env_name(self) -> Option<&'static str> {
+        const PATH_DIST: &str = "LIBFAULTINJ_DELAY_PATH_DIST";
+        const ADDR_DIST: &str = "LIBFAULTINJ_DELAY_ADDR_DIST";
+
+        match self {
+            Selector::Fd(fd) => {
+                // Spares a syscall when neither is set.
+                if ::std::env::var_os(PATH_DIST).is_none() &&
+                   ::std::env::var_os(ADDR_DIST).is_none() {
+                    None
+                } else if get_socket_type(fd).is_some() {
+                    Some(ADDR_DIST)
+                } else {
+                    Some(PATH_DIST)
+                }
+            }
+            Selector::Addr => Some(ADDR_DIST),
+            Selector::Any => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Distribution {
+    Fixed(f64),
+    Uniform(f64, f64),
+    Normal(f64, f64),
+    /// By its mean.
+    Exponential(f64),
+    /// By its minimum and shape; smaller shapes have longer tails.
+    Pareto(f64, f64),
+    Histogram(Bins),
+}
+
+/**
+ * @return the comma-separated numbers in `args`, or None if any can't be
+ *      parsed or there aren't `count` of them.
+ */
+fn parse_args(args: &str, count: usize) -> Option<Vec<f64>> {
+    let args: Vec<f64> = args.split(',')
+                             .map(|arg| arg.trim().parse::<f64>().ok())
+                             .collect::<Option<Vec<f64>>>()?;
+    if args.len() == count && args.iter().all(|arg| arg.is_finite() && *arg >= 0.) {
+        Some(args)
+    } else {
+        None
+    }
+}
+
+/**
+ * @return the (delay, weight) pairs in `text`, one "MS [WEIGHT]" a line,
+ *      or None if there are none.  Blank lines, "#" comments and lines
+ *      that can't be parsed are ignored.
+ */
+pub fn parse_histogram(text: &str) -> Option<Vec<(f64, f64)>> {
+    let bins: Vec<(f64, f64)> = text.lines()
+                                    .map(|line| line.split('#').next().unwrap_or(""))
+                                    .filter_map(|line| {
+                                        let mut fields = line.split_whitespace();
+                                        let ms = fields.next()?.parse::<f64>().ok()?;
+                                        let weight = match fields.next() {
+                                            Some(weight) => weight.parse::<f64>().ok()?,
+                                            None => 1.,
+                                        };
+                                        Some((ms, weight))
+                                    })
+                                    .filter(|&(ms, weight)| ms >= 0. && weight > 0.)
+                                    .collect();
+    if bins.is_empty() {
+        None
+    } else {
+        Some(bins)
+    }
+}
+
+/**
+ * @return the histogram in the file at `path`, which is only read once.
+ */
+fn load_histogram(path: &str) -> Option<Bins> {
+    use std::fs;
+
+    if let Some(bins) = HISTOGRAMS.lock().unwrap().get(path) {
+        return Some(bins.clone());
+    }
+
+    LOADING.with(|loading| loading.set(true));
+    let text = fs::read_to_string(path);
+    LOADING.with(|loading| loading.set(false));
+
+    let bins = Arc::new(parse_histogram(&text.ok()?)?);
+    HISTOGRAMS.lock().unwrap().insert(path.to_string(), bins.clone());
+    Some(bins)
+}
+
+impl Distribution {
+    pub fn parse(spec: &str) -> Option<Distribution> {
+        let mut parts = spec.trim().splitn(2, ':');
+        let kind = parts.next()?.trim().to_lowercase();
+        let args = parts.next()?.trim();
+
+        let dist = match kind.as_str() {
+            "fixed" => {
+                let args = parse_args(args, 1)?;
+                Distribution::Fixed(args[0])
+            }
+            "uniform" => {
+                let args = parse_args(args, 2)?;
+                Distribution::Uniform(args[0].min(args[1]), args[0].max(args[1]))
+            }
+            "normal" => {
+                let args = parse_args(args, 2)?;
+                Distribution::Normal(args[0], args[1])
+            }
+            "exp" => {
+                let args = parse_args(args, 1)?;
+                Distribution::Exponential(args[0])
+            }
+            "pareto" => {
+                let args = parse_args(args, 2)?;
+                if args[1] == 0. {
+                    return None;
+                }
+                Distribution::Pareto(args[0], args[1])
+            }
+            "histogram" => Distribution::Histogram(load_histogram(args)?),
+            _ => return None,
+        };
+        Some(dist)
+    }
+
+    /**
+     * @return a delay in milliseconds, never negative.
+     */
+    pub fn sample_ms<R: Rng>(&self, rng: &mut R) -> f64 {
+        let ms = match *self {
+            Distribution::Fixed(ms) => ms,
+            Distribution::Uniform(min, max) if min < max => rng.gen_range(min, max),
+            Distribution::Uniform(min, _) => min,
+            Distribution::Normal(mean, stddev) => Normal::new(mean, stddev).ind_sample(rng),
+            Distribution::Exponential(mean) if mean > 0. => {
+                Exp::new(1. / mean).ind_sample(rng)
+            }
+            Distribution::Exponential(_) => 0.,
+            Distribution::Pareto(min, shape) => {
+                let u: f64 = rng.gen();
+                min / (1. - u).powf(1. / shape)
+            }
+            Distribution::Histogram(ref bins) => {
+                let total: f64 = bins.iter().map(|&(_, weight)| weight).sum();
+                let mut pick = rng.gen::<f64>() * total;
+                let mut ms = bins[bins.len() - 1].0;
+                for &(bin_ms, weight) in bins.iter() {
+                    if pick < weight {
+                        ms = bin_ms;
+                        break;
+                    }
+                    pick -= weight;
+                }
+                ms
+            }
+        };
+        ms.max(0.)
+    }
+
+    pub fn sample(&self) -> Duration {
+        let ms = self.sample_ms(&mut ::rand::thread_rng());
+        // A long enough tail is as good as a hang.
+        Duration::try_from_secs_f64(ms / 1000.).unwrap_or(Duration::MAX)
+    }
+}
+
+fn dist_from_env(env_name: &str) -> Option<Distribution> {
+    Distribution::parse(&::std::env::var(env_name).ok()?)
+}
+
+/**
+ * @return how long to delay `funcname` on what `selector` picked.
+ */
+pub fn delay_for(funcname: &str, selector: Selector) -> Duration {
+    // The library's own reads of histogram files aren't delayed.
+    if LOADING.with(|loading| loading.get()) {
+        return Duration::from_millis(0);
+    }
+
+    let env_prefix = "LIBFAULTINJ_DELAY_".to_string() + &funcname.to_uppercase();
+
+    if let Some(dist) = dist_from_env(&(env_prefix.clone() + "_DIST")) {
+        return dist.sample();
+    }
+    if let Some(ms) = get_env_value::<u64>(&(env_prefix + "_MS")) {
+        return Duration::from_millis(ms);
+    }
+    if let Some(dist) = selector.dist_env_name().and_then(dist_from_env) {
+        return dist.sample();
+    }
+    if let Some(dist) = dist_from_env("LIBFAULTINJ_DELAY_DIST") {
+        return dist.sample();
+    }
+
+    Duration::from_millis(DEFAULT_DELAY_MS)
+}
+
+/**
+ * Delays a call of `funcname` on what `selector` picked: sleeps, hangs,
+ *   or for non-blocking fds, puts the call off.
+ *
+ * @return Some(errno) if the call should fail because it was put off or a
+ *      hang was interrupted, None otherwise.
+ */
+pub fn delay_call(funcname: &str, selector: Selector) -> Option<c_int> {
+    if hangs(funcname) {
+        return hang().err();
+    }
+
+    let delay = delay_for(funcname, selector);
+    if delay > Duration::from_millis(0) {
+        if let Some(err) = nonblock::defer(funcname, selector, delay) {
+            return Some(err);
+        }
+    }
+    sleep(delay);
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Distribution, Selector, delay_for, parse_histogram};
+    use rand::{SeedableRng, XorShiftRng};
+    use std::env;
+    use std::time::Duration;
+
+    #[test]
+    fn test_parse_distribution() {
+        assert_eq!(Distribution::parse("uniform:50, 10"), Some(Distribution::Uniform(10., 50.)));
+        assert_eq!(Distribution::parse("Normal:100,20"), Some(Distribution::Normal(100., 20.)));
+        assert_eq!(Distribution::parse("exp:50"), Some(Distribution::Exponential(50.)));
+        assert_eq!(Distribution::parse("pareto:10,1.5"), Some(Distribution::Pareto(10., 1.5)));
+        assert_eq!(Distribution::parse("pareto:10,0"), None);
+        assert_eq!(Distribution::parse("normal:100"), None);
+        assert_eq!(Distribution::parse("fixed:-5"), None);
+        assert_eq!(Distribution::parse("zipf:1"), None);
+        assert_eq!(Distribution::parse("histogram:/nonexistent"), None);
+
+        assert_eq!(parse_histogram("# ms weight\n1 90\n\n50 9 # slow\n500\nbogus\n"),
+                   Some(vec![(1., 90.), (50., 9.), (500., 1.)]));
+        assert_eq!(parse_histogram("# nothing\n"), None);
+    }
+
+    #[test]
+    fn test_sample_distribution() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let mut mean = |dist: &Distribution| {
+            let samples: Vec<f64> = (0..10000).map(|_| dist.sample_ms(&mut rng)).collect();
+            assert!(samples.iter().all(|&ms| ms >= 0.));
+            samples.iter().sum::<f64>() / samples.len() as f64
+        };
+
+        assert_eq!(mean(&Distribution::Fixed(7.)), 7.);
+        assert!((mean(&Distribution::Uniform(10., 50.)) - 30.).abs() < 1.);
+        assert!((mean(&Distribution::Normal(100., 20.)) - 100.).abs() < 1.);
+        assert!((mean(&Distribution::Exponential(50.)) - 50.).abs() < 2.);
+        // The mean of a Pareto distribution is min * shape / (shape - 1).
+        assert!((mean(&Distribution::Pareto(10., 3.)) - 15.).abs() < 1.);
+
+        let bins = parse_histogram("1 3\n100 1").unwrap();
+        let histogram = Distribution::Histogram(bins.into());
+        assert!((mean(&histogram) - 25.75).abs() < 2.);
+    }
+
+    #[test]
+    fn test_delay_precedence() {
+        assert_eq!(delay_for("delaytest", Selector::Addr), Duration::from_millis(200));
+
+        env::set_var("LIBFAULTINJ_DELAY_ADDR_DIST", "fixed:30");
+        assert_eq!(delay_for("delaytest", Selector::Addr), Duration::from_millis(30));
+        assert_eq!(delay_for("delaytest", Selector::Any), Duration::from_millis(200));
+
+        env::set_var("LIBFAULTINJ_DELAY_DELAYTEST_MS", "20");
+        assert_eq!(delay_for("delaytest", Selector::Addr), Duration::from_millis(20));
+
+        env::set_var("LIBFAULTINJ_DELAY_DELAYTEST_DIST", "uniform:10,10");
+        assert_eq!(delay_for("delaytest", Selector::Addr), Duration::from_millis(10));
+
+        env::remove_var("LIBFAULTINJ_DELAY_DELAYTEST_DIST");
+        env::remove_var("LIBFAULTINJ_DELAY_DELAYTEST_MS");
+        env::remove_var("LIBFAULTINJ_DELAY_ADDR_DIST");
+    }
+}
@@ -0,0 +1,98 @@
+extern crate libc;
+
+// Calls that change directories: unlink(), rename(), mkdir() and the
+//   rest.  They're selected by the paths they name, like open(), so they
+//   fail or are delayed if any of those paths is in
+//   LIBFAULTINJ_{ERROR,DELAY}_PATH, and fail with EROFS if one's on a
+//   filesystem `fsinfo` has made read-only.
+
+use std::ffi::{CStr, OsStr};
+use std::fs;
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+
+use libc::{c_char, c_int};
+
+/**
+ * Returns $err from the hook, with errno set, if a fault is injected into
+ *   $funcname on any of the $paths.
+ */
+macro_rules! injectPathFaults(
+    ($funcname:expr, $err:expr, $($path:expr),+) =>
+    ({
+        use errno::{Errno, set_errno};
+        use fsinfo::read_only_path;
+        use latency::Selector;
+
+        let paths = [$(&$path),+];
+        let err_match = paths.iter().any(|path| matchesPath!(path, "LIBFAULTINJ_ERROR_PATH"));
+        let delay_match = paths.iter().any(|path| matchesPath!(path, "LIBFAULTINJ_DELAY_PATH"));
+
+        if let Some(err) = delayedErrno!(matched: err_match, delay_match, $funcname,
+                                         Selector::Any) {
+            set_errno(err);
+            return $err;
+        }
+        if paths.iter().any(|path| read_only_path(path)) {
+            set_errno(Errno(libc::EROFS));
+            return $err;
+        }
+    }));
+
+/**
+ * @return the path `pathname` names, or an empty one if it's null so the
+ *      call itself can fail with EFAULT.
+ */
+pub unsafe fn c_path(pathname: *const c_char) -> PathBuf {
+    if pathname.is_null() {
+        return PathBuf::new();
+    }
+    let bytes = unsafe { CStr::from_ptr(pathname) }.to_bytes();
+    PathBuf::from(OsStr::from_bytes(bytes))
+}
+
+/**
+ * @return the directory `dirfd` is open on, if it's one.
+ */
+pub fn fd_path(dirfd: c_int) -> Option<PathBuf> {
+    fs::read_link(format!("/proc/self/fd/{}", dirfd)).ok()
+}
+
+/**
+ * @return the path `pathname` names relative to `dirfd`, as the *at()
+ *      calls take them.
+ */
+pub unsafe fn at_path(dirfd: c_int, pathname: *const c_char) -> PathBuf {
+    let path = c_path(pathname);
+    if dirfd == libc::AT_FDCWD || path.is_absolute() {
+        return path;
+    }
+    match fd_path(dirfd) {
+        Some(dir) => dir.join(path),
+        None => path,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::at_path;
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::os::unix::io::AsRawFd;
+    use std::path::PathBuf;
+    extern crate libc;
+
+    #[test]
+    fn test_at_path() {
+        let name = CString::new("foo").unwrap();
+        let absolute = CString::new("/foo").unwrap();
+        let tmp = ::std::env::temp_dir().canonicalize().unwrap();
+        let dir = File::open(&tmp).unwrap();
+
+        unsafe {
+            assert_eq!(at_path(libc::AT_FDCWD, name.as_ptr()), PathBuf::from("foo"));
+            assert_eq!(at_path(dir.as_raw_fd(), name.as_ptr()), tmp.join("foo"));
+            assert_eq!(at_path(dir.as_raw_fd(), absolute.as_ptr()), PathBuf::from("/foo"));
+        }
+    }
+}
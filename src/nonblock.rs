@@ -0,0 +1,128 @@
+extern crate libc;
+
+// Delays for non-blocking fds.  Sleeping in the hook would stall a whole
+//   event loop, when only the one peer should look slow.  So a delayed
+//   call on an O_NONBLOCK fd fails with EAGAIN instead, and the fd isn't
+//   reported ready by poll() and epoll_wait() until the delay is over.
+//   Calls until then fail with EAGAIN too, and the first one after goes
+//   through.  LIBFAULTINJ_ASYNC_DELAYS=0 sleeps in the hook as before.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use libc::c_int;
+use errors::{SomeHashState, is_nonblocking};
+use latency::Selector;
+use ready;
+
+// The calls that can fail with EAGAIN on a non-blocking fd.  accept4() goes
+//   by "accept", like its LIBFAULTINJ_*_ACCEPT_* variables.
+const NONBLOCKING_FUNCS: &[&str] = &["read", "write", "send", "recv", "sendto", "recvfrom",
+                                     "sendmsg", "recvmsg", "sendmmsg", "recvmmsg", "accept"];
+
+// Spares the hooks a lock when nothing's ever been deferred.
+static DEFERRING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // When each deferred fd's delay is over, where None is never.
+    static ref DEADLINES: Mutex<HashMap<c_int, Option<Instant>, SomeHashState>>
+            = Mutex::new(HashMap::with_hasher(SomeHashState::default()));
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Deferred {
+    /// Nothing's deferred.
+    No,
+    /// The delay isn't over, so the call fails with EAGAIN.
+    Waiting,
+    /// The delay is over, so the call goes through undelayed.
+    Done,
+}
+
+/**
+ * @return whether a call of `funcname` on the fd in `selector` was put
+ *      off by an earlier delay.
+ */
+pub fn deferred(funcname: &str, selector: Selector) -> Deferred {
+    let fd = match selector {
+        Selector::Fd(fd) if DEFERRING.load(Ordering::Relaxed) => fd,
+        _ => return Deferred::No,
+    };
+    if !NONBLOCKING_FUNCS.contains(&funcname) {
+        return Deferred::No;
+    }
+
+    let mut deadlines = DEADLINES.lock().unwrap();
+    match deadlines.get(&fd) {
+        None => Deferred::No,
+        Some(&None) => Deferred::Waiting,
+        Some(&Some(deadline)) if Instant::now() < deadline => Deferred::Waiting,
+        Some(_) => {
+            deadlines.remove(&fd);
+            Deferred::Done
+        }
+    }
+}
+
+/**
+ * Puts off a call of `funcname` on the fd in `selector` for `delay`, if
+ *   it's a non-blocking one.
+ *
+ * @return Some(EAGAIN) if it was put off, None if it should sleep instead.
+ */
+pub fn defer(funcname: &str, selector: Selector, delay: Duration) -> Option<c_int> {
+    let fd = match selector {
+        Selector::Fd(fd) => fd,
+        _ => return None,
+    };
+    if ::std::env::var("LIBFAULTINJ_ASYNC_DELAYS").map(|on| on.trim() == "0").unwrap_or(false) ||
+       !NONBLOCKING_FUNCS.contains(&funcname) || !is_nonblocking(fd) {
+        return None;
+    }
+
+    let deadline = Instant::now().checked_add(delay);
+    DEFERRING.store(true, Ordering::Relaxed);
+    DEADLINES.lock().unwrap().insert(fd, deadline);
+    ready::hide(fd, deadline);
+
+    Some(libc::EAGAIN)
+}
+
+pub fn forget_fd(fd: c_int) {
+    if !DEFERRING.load(Ordering::Relaxed) {
+        return;
+    }
+    DEADLINES.lock().unwrap().remove(&fd);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Deferred, defer, deferred};
+    use latency::Selector;
+    use std::os::unix::io::AsRawFd;
+    use std::net::UdpSocket;
+    use std::thread::sleep;
+    use std::time::Duration;
+    extern crate libc;
+
+    #[test]
+    fn test_deferred() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let fd = Selector::Fd(socket.as_raw_fd());
+        let delay = Duration::from_millis(50);
+
+        assert_eq!(defer("recvmmsg", fd, delay), None);
+        socket.set_nonblocking(true).unwrap();
+        assert_eq!(defer("lseek", fd, delay), None);
+        assert_eq!(defer("recvmmsg", fd, delay), Some(libc::EAGAIN));
+
+        assert_eq!(deferred("lseek", fd), Deferred::No);
+        assert_eq!(deferred("recvmmsg", fd), Deferred::Waiting);
+        assert_eq!(deferred("recvmmsg", Selector::Addr), Deferred::No);
+        sleep(delay);
+        assert_eq!(deferred("recvmmsg", fd), Deferred::Done);
+        assert_eq!(deferred("recvmmsg", fd), Deferred::No);
+    }
+}
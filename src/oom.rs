@@ -0,0 +1,320 @@
+extern crate libc;
+
+// Allocation failures.  malloc(), calloc(), realloc(), posix_memalign(),
+//   memalign(), aligned_alloc() and anonymous mmap()s fail with ENOMEM when
+//   LIBFAULTINJ_ALLOC_* say so: with LIBFAULTINJ_ALLOC_LIKELIHOOD_PCT, on
+//   the LIBFAULTINJ_ALLOC_NTH allocation, for allocations of at least
+//   LIBFAULTINJ_ALLOC_MIN_BYTES, or only for those made from a library
+//   whose path contains LIBFAULTINJ_ALLOC_CALLER.  Those that are set must
+//   all agree.  They're read once, at the first allocation the library
+//   isn't making itself.
+//
+// Everything here runs inside the allocator, so it mustn't allocate, take
+//   locks or read the environment through std::env, whose lock may be held
+//   by a set_var() that's calling setenv() that's calling malloc().  The
+//   library's own allocations go straight to glibc, see `LibcAllocator`,
+//   and anything it does in C, like looking up hooked functions, is
+//   wrapped in `suspend()`, which also lets the files it opens past the
+//   open() and fstat() hooks.
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+use std::ffi::CStr;
+use std::{cmp, ptr, str};
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, AtomicUsize, AtomicU64, Ordering};
+
+use libc::{c_char, c_int, c_void, off_t, size_t};
+
+// glibc's own entry points, so the hooks needn't look them up with dlsym(),
+//   which allocates.
+extern "C" {
+    pub fn __libc_malloc(size: size_t) -> *mut c_void;
+    pub fn __libc_calloc(nmemb: size_t, size: size_t) -> *mut c_void;
+    pub fn __libc_realloc(ptr: *mut c_void, size: size_t) -> *mut c_void;
+    pub fn __libc_memalign(alignment: size_t, size: size_t) -> *mut c_void;
+    pub fn __libc_free(ptr: *mut c_void);
+    pub fn __mmap(addr: *mut c_void,
+                  length: size_t,
+                  prot: c_int,
+                  flags: c_int,
+                  fd: c_int,
+                  offset: off_t)
+                  -> *mut c_void;
+    fn backtrace(buffer: *mut *mut c_void, size: c_int) -> c_int;
+    static environ: *const *const c_char;
+}
+
+const ENV_PREFIX: &[u8] = b"LIBFAULTINJ_ALLOC_";
+// How many frames to look through for the caller.
+const MAX_FRAMES: usize = 32;
+// What glibc's malloc() always aligns to.
+const MALLOC_ALIGN: usize = 16;
+
+thread_local! {
+    // Set while an allocation's being decided, or the library's at work.
+    static BUSY: Cell<bool> = const { Cell::new(false) };
+}
+
+// Allocations that could have failed, for LIBFAULTINJ_ALLOC_NTH.
+static CANDIDATES: AtomicUsize = AtomicUsize::new(0);
+static RNG_STATE: AtomicU64 = AtomicU64::new(0);
+static TRIGGERS: OnceLock<Triggers<'static>> = OnceLock::new();
+// Cleared once the environment turns out to set no trigger, so allocations
+//   needn't even suspend().
+static TRIGGERED: AtomicBool = AtomicBool::new(true);
+
+/// Sends the library's own allocations straight to glibc, so none of them
+///   are ever failed, and none reach the hooks.
+pub struct LibcAllocator;
+
+#[global_allocator]
+static ALLOCATOR: LibcAllocator = LibcAllocator;
+
+unsafe impl GlobalAlloc for LibcAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.align() <= MALLOC_ALIGN {
+            __libc_malloc(layout.size()) as *mut u8
+        } else {
+            __libc_memalign(layout.align(), layout.size()) as *mut u8
+        }
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        if layout.align() <= MALLOC_ALIGN {
+            __libc_calloc(1, layout.size()) as *mut u8
+        } else {
+            let ptr = self.alloc(layout);
+            if !ptr.is_null() {
+                ptr::write_bytes(ptr, 0, layout.size());
+            }
+            ptr
+        }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, _: Layout) {
+        __libc_free(ptr as *mut c_void)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if layout.align() <= MALLOC_ALIGN {
+            return __libc_realloc(ptr as *mut c_void, new_size) as *mut u8;
+        }
+
+        let new_ptr = self.alloc(Layout::from_size_align_unchecked(new_size, layout.align()));
+        if !new_ptr.is_null() {
+            ptr::copy_nonoverlapping(ptr, new_ptr, cmp::min(layout.size(), new_size));
+            self.dealloc(ptr, layout);
+        }
+        new_ptr
+    }
+}
+
+/// Keeps allocations from failing until dropped.
+pub struct Suspended {
+    was_busy: bool,
+}
+
+impl Drop for Suspended {
+    fn drop(&mut self) {
+        let was_busy = self.was_busy;
+        let _ = BUSY.try_with(|busy| busy.set(was_busy));
+    }
+}
+
+/**
+ * @return a guard that keeps allocations on this thread from failing while
+ *      the library does something that allocates in C.
+ */
+pub fn suspend() -> Suspended {
+    Suspended { was_busy: BUSY.try_with(|busy| busy.replace(true)).unwrap_or(true) }
+}
+
+/**
+ * @return whether the library's at work on this thread, inside `suspend()`.
+ */
+pub fn suspended() -> bool {
+    BUSY.try_with(|busy| busy.get()).unwrap_or(true)
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Triggers<'a> {
+    likelihood_pct: Option<f32>,
+    nth: Option<usize>,
+    min_bytes: Option<usize>,
+    caller: Option<&'a [u8]>,
+}
+
+impl<'a> Triggers<'a> {
+    /**
+     * Reads the LIBFAULTINJ_ALLOC_* in `env`, "NAME=value" entries like
+     *   `environ`'s.
+     */
+    pub fn parse<I: Iterator<Item = &'a [u8]>>(env: I) -> Triggers<'a> {
+        fn number<T: str::FromStr>(value: &[u8]) -> Option<T> {
+            str::from_utf8(value).ok()?.trim().parse::<T>().ok()
+        }
+
+        let mut triggers = Triggers::default();
+        for entry in env.filter(|entry| entry.starts_with(ENV_PREFIX)) {
+            let entry = &entry[ENV_PREFIX.len()..];
+            let eq = match entry.iter().position(|&b| b == b'=') {
+                Some(eq) => eq,
+                None => continue,
+            };
+            let (name, value) = (&entry[..eq], &entry[eq + 1..]);
+
+            match name {
+                b"LIKELIHOOD_PCT" => triggers.likelihood_pct = number(value),
+                b"NTH" => triggers.nth = number(value),
+                b"MIN_BYTES" => triggers.min_bytes = number(value),
+                b"CALLER" if !value.is_empty() => triggers.caller = Some(value),
+                _ => (),
+            }
+        }
+        triggers
+    }
+
+    pub fn from_env() -> Triggers<'static> {
+        let mut entries = unsafe { environ };
+        Triggers::parse(::std::iter::from_fn(move || unsafe {
+            if entries.is_null() || (*entries).is_null() {
+                return None;
+            }
+            let entry = CStr::from_ptr(*entries).to_bytes();
+            entries = entries.add(1);
+            Some(entry)
+        }))
+    }
+
+    pub fn is_set(&self) -> bool {
+        *self != Triggers::default()
+    }
+
+    /**
+     * @return whether an allocation of `size` bytes is one that could fail,
+     *      before counting it or rolling for it.
+     */
+    pub fn selects(&self, size: usize) -> bool {
+        self.min_bytes.is_none_or(|min_bytes| size >= min_bytes) &&
+        self.caller.is_none_or(caller_matches)
+    }
+
+    /**
+     * @return whether the `candidate`th allocation that could fail does,
+     *      given `roll` out of 100.
+     */
+    pub fn fails(&self, candidate: usize, roll: f32) -> bool {
+        if let Some(nth) = self.nth {
+            if candidate != nth {
+                return false;
+            }
+        }
+        self.likelihood_pct.is_none_or(|pct| roll < pct)
+    }
+}
+
+/**
+ * @return a number from 0 up to 100, from a generator that won't allocate.
+ */
+fn roll() -> f32 {
+    let mut x = RNG_STATE.load(Ordering::Relaxed);
+    if x == 0 {
+        let mut now: libc::timespec = unsafe { ::std::mem::zeroed() };
+        unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+        x = (now.tv_nsec as u64 ^ (now.tv_sec as u64) << 32) | 1;
+    }
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    RNG_STATE.store(x, Ordering::Relaxed);
+
+    (x >> 40) as f32 / (1u64 << 24) as f32 * 100.
+}
+
+/**
+ * @return true if the first caller outside this library is from a library
+ *      whose path contains `pattern`.
+ */
+fn caller_matches(pattern: &[u8]) -> bool {
+    let mut frames = [ptr::null_mut(); MAX_FRAMES];
+    let count = unsafe { backtrace(frames.as_mut_ptr(), MAX_FRAMES as c_int) };
+    let own_base = object_base(caller_matches as *const c_void);
+
+    for &frame in frames.iter().take(count.max(0) as usize) {
+        let mut info: libc::Dl_info = unsafe { ::std::mem::zeroed() };
+        if unsafe { libc::dladdr(frame, &mut info) } == 0 || info.dli_fname.is_null() {
+            continue;
+        }
+        if ptr::eq(info.dli_fbase, own_base) {
+            continue;
+        }
+        let name = unsafe { CStr::from_ptr(info.dli_fname) }.to_bytes();
+        return name.windows(pattern.len()).any(|window| window == pattern);
+    }
+    false
+}
+
+fn object_base(addr: *const c_void) -> *const c_void {
+    let mut info: libc::Dl_info = unsafe { ::std::mem::zeroed() };
+    unsafe { libc::dladdr(addr, &mut info) };
+    info.dli_fbase
+}
+
+/**
+ * @return true if an allocation of `size` bytes should fail.
+ */
+pub fn should_fail(size: usize) -> bool {
+    if !TRIGGERED.load(Ordering::Relaxed) {
+        return false;
+    }
+    let guard = suspend();
+    // Allocations made before libc has set up `environ` can't be judged yet.
+    if guard.was_busy || unsafe { environ }.is_null() {
+        return false;
+    }
+
+    let triggers = TRIGGERS.get_or_init(|| {
+        let triggers = Triggers::from_env();
+        TRIGGERED.store(triggers.is_set(), Ordering::Relaxed);
+        triggers
+    });
+    if !triggers.is_set() || !triggers.selects(size) {
+        return false;
+    }
+    let candidate = CANDIDATES.fetch_add(1, Ordering::Relaxed) + 1;
+    triggers.fails(candidate, roll())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Triggers, suspend};
+
+    #[test]
+    fn test_triggers() {
+        let env: &[&[u8]] = &[b"PATH=/bin",
+                              b"LIBFAULTINJ_ALLOC_NTH=3",
+                              b"LIBFAULTINJ_ALLOC_MIN_BYTES= 1024",
+                              b"LIBFAULTINJ_ALLOC_LIKELIHOOD_PCT=oops"];
+        let triggers = Triggers::parse(env.iter().cloned());
+        assert!(triggers.is_set());
+        assert_eq!((triggers.nth, triggers.min_bytes, triggers.likelihood_pct),
+                   (Some(3), Some(1024), None));
+
+        assert!(!triggers.selects(1023));
+        assert!(triggers.selects(1024));
+        assert!(!triggers.fails(2, 0.));
+        assert!(triggers.fails(3, 99.));
+
+        let env: &[&[u8]] = &[b"LIBFAULTINJ_ALLOC_LIKELIHOOD_PCT=25"];
+        let triggers = Triggers::parse(env.iter().cloned());
+        assert!(triggers.fails(1, 10.));
+        assert!(!triggers.fails(1, 30.));
+        assert!(!Triggers::parse([].iter().cloned()).is_set());
+
+        // Nothing fails while suspended.
+        let outer = suspend();
+        assert!(!outer.was_busy);
+        assert!(suspend().was_busy);
+    }
+}
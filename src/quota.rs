@@ -0,0 +1,360 @@
+extern crate libc;
+
+// Disk quotas.  Files opened under LIBFAULTINJ_QUOTA_PATH share
+//   LIBFAULTINJ_QUOTA_BYTES of room to grow.  As it runs out, writes
+//   become short, then fail with ENOSPC, or LIBFAULTINJ_QUOTA_ERRNO, like
+//   EDQUOT.  Resizing files and copying into them is held to the same
+//   limit, statfs() and statvfs() report no more free space than is left,
+//   and unlinking a file gives back what it used.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libc::{c_int, c_void, off_t, size_t};
+use errors::{SomeHashState, FstatFunc, SeekFunc, get_env_value};
+
+/**
+ * @return what $limit allowed, or returns $err from the hook with errno
+ *      set if the quota's used up.
+ */
+macro_rules! checkQuota(
+    ($limit: expr, $err: expr) =>
+    ({
+        use errno::{Errno, set_errno};
+
+        match $limit {
+            Ok(allowed) => allowed,
+            Err(err) => {
+                set_errno(Errno(err));
+                return $err;
+            }
+        }
+    }));
+
+// Spares the hooks a lock when no file's ever been under quota.
+static TRACKING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref FILES: Mutex<Files> = Mutex::new(Files::default());
+    static ref FSTAT_FUNC: FstatFunc = get_libc_func!(FstatFunc, "fstat");
+    static ref SEEK_FUNC: SeekFunc = get_libc_func!(SeekFunc, "lseek");
+}
+
+#[derive(Default)]
+struct Files {
+    /// The file each fd under quota was opened on.
+    fds: HashMap<c_int, PathBuf, SomeHashState>,
+    /// How much of the quota each file has used.
+    used: HashMap<PathBuf, u64, SomeHashState>,
+}
+
+/// The size of a file under quota before a call that may resize it.
+pub struct Growth {
+    path: PathBuf,
+    /// The fd it was resized through, if any.
+    fd: Option<c_int>,
+    size: u64,
+}
+
+/**
+ * @return the space files under quota may grow by, or None for no quota.
+ */
+fn quota_bytes() -> Option<u64> {
+    get_env_value::<u64>("LIBFAULTINJ_QUOTA_BYTES")
+}
+
+fn quota_errno() -> c_int {
+    get_env_value::<c_int>("LIBFAULTINJ_QUOTA_ERRNO").unwrap_or(libc::ENOSPC)
+}
+
+/**
+ * @return how much of `quota` is left when `used` has been.
+ */
+pub fn remaining(quota: u64, used: &HashMap<PathBuf, u64, SomeHashState>) -> u64 {
+    quota.saturating_sub(used.values().sum())
+}
+
+/**
+ * @return how many of `len` bytes written at `pos` to a file of `size`
+ *      fit when `available` more may be used, or None if none do.
+ */
+pub fn fitting_len(pos: u64, len: u64, size: u64, available: u64) -> Option<u64> {
+    let overwritten = size.saturating_sub(pos);
+    let allowed = overwritten.saturating_add(available);
+
+    if len == 0 || allowed > 0 {
+        Some(len.min(allowed))
+    } else {
+        None
+    }
+}
+
+fn file_size(fd: c_int) -> Option<u64> {
+    let mut st: libc::stat = unsafe { ::std::mem::zeroed() };
+    if FSTAT_FUNC(fd, &mut st) == 0 {
+        Some(st.st_size as u64)
+    } else {
+        None
+    }
+}
+
+fn growth_size(growth: &Growth) -> Option<u64> {
+    match growth.fd {
+        Some(fd) => file_size(fd),
+        None => fs::metadata(&growth.path).ok().map(|meta| meta.len()),
+    }
+}
+
+/**
+ * @return the file at `filename`, if it's under quota.  It's matched both
+ *      as given and with symlinks resolved.
+ */
+fn quota_path(filename: &Path) -> Option<PathBuf> {
+    quota_bytes()?;
+    let path = fs::canonicalize(filename).ok()?;
+    if matchesPath!(filename, "LIBFAULTINJ_QUOTA_PATH") ||
+       matchesPath!(path, "LIBFAULTINJ_QUOTA_PATH") {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/**
+ * @return the size of the file at `filename` if it's under quota and
+ *      opening it with `flags` will truncate it.
+ */
+pub fn size_before_open(filename: &str, flags: c_int) -> Option<u64> {
+    if flags & libc::O_TRUNC == 0 {
+        return None;
+    }
+    quota_path(Path::new(filename))?;
+    fs::metadata(filename).ok().map(|meta| meta.len())
+}
+
+/**
+ * Puts `fd`, just opened on `filename`, under quota if it's in
+ *   LIBFAULTINJ_QUOTA_PATH, crediting the quota with what opening it
+ *   truncated away.
+ */
+pub fn track_fd(fd: c_int, filename: &str, truncated: Option<u64>) {
+    if fd < 0 {
+        return;
+    }
+    if let Some(path) = quota_path(Path::new(filename)) {
+        TRACKING.store(true, Ordering::Relaxed);
+        let mut files = FILES.lock().unwrap();
+        if let Some(size) = truncated {
+            let used = files.used.entry(path.clone()).or_insert(0);
+            *used = used.saturating_sub(size);
+        }
+        files.fds.insert(fd, path);
+    }
+}
+
+pub fn dup_fd(oldfd: c_int, newfd: c_int) {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut files = FILES.lock().unwrap();
+    match files.fds.get(&oldfd).cloned() {
+        Some(path) => files.fds.insert(newfd, path),
+        None => files.fds.remove(&newfd),
+    };
+}
+
+pub fn forget_fd(fd: c_int) {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return;
+    }
+    FILES.lock().unwrap().fds.remove(&fd);
+}
+
+/**
+ * @return the file at `path`, which is about to be unlinked, if it's used
+ *      any of the quota.  Pass it to `forget_path()` once it's gone.
+ */
+pub fn unlinking(path: &Path) -> Option<PathBuf> {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return None;
+    }
+    // Even if the quota's since been lifted, what it used is given back.
+    fs::canonicalize(path).ok()
+}
+
+/**
+ * Gives back what the unlinked file at `path` used.
+ */
+pub fn forget_path(path: Option<PathBuf>) {
+    if let Some(path) = path {
+        FILES.lock().unwrap().used.remove(&path);
+    }
+}
+
+/**
+ * @return the size of `fd`'s file if it's under quota, with what's left of
+ *      the quota.
+ */
+fn growth_room(fd: c_int) -> Option<(Growth, u64)> {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return None;
+    }
+    let quota = quota_bytes()?;
+
+    let files = FILES.lock().unwrap();
+    let path = files.fds.get(&fd)?.clone();
+    let size = file_size(fd)?;
+    Some((Growth { path, fd: Some(fd), size }, remaining(quota, &files.used)))
+}
+
+/**
+ * @return how many of `len` bytes a write to `fd` may move, with the
+ *      file's size for `charge()`, or Err(errno) if there's no room at all.
+ */
+pub fn limit_write(fd: c_int, len: size_t) -> Result<(size_t, Option<Growth>), c_int> {
+    limit_write_at(fd, len, None)
+}
+
+/**
+ * @return how many of `len` bytes written to `fd` at `offset`, or at the
+ *      fd's own offset for None, may move, as for `limit_write()`.
+ */
+pub fn limit_write_at(fd: c_int,
+                      len: size_t,
+                      offset: Option<off_t>)
+                      -> Result<(size_t, Option<Growth>), c_int> {
+    let (growth, available) = match growth_room(fd) {
+        Some(room) => room,
+        None => return Ok((len, None)),
+    };
+
+    let appending = unsafe { libc::fcntl(fd, libc::F_GETFL) } & libc::O_APPEND != 0;
+    let pos = match offset.unwrap_or_else(|| SEEK_FUNC(fd, 0, libc::SEEK_CUR)) {
+        _ if appending => growth.size,
+        pos if pos >= 0 => pos as u64,
+        // Not seekable, so it's appended to.
+        _ => growth.size,
+    };
+
+    match fitting_len(pos, len as u64, growth.size, available) {
+        Some(allowed) => Ok((allowed as size_t, Some(growth))),
+        None => Err(quota_errno()),
+    }
+}
+
+/**
+ * @return the file's size for `charge()`, or Err(errno) if `fd`'s file
+ *      can't grow to `new_size`.
+ */
+pub fn limit_resize(fd: c_int, new_size: off_t) -> Result<Option<Growth>, c_int> {
+    let (growth, available) = match growth_room(fd) {
+        Some(room) => room,
+        None => return Ok(None),
+    };
+
+    check_resize(growth, available, new_size)
+}
+
+/**
+ * @return the file's size for `charge()`, or Err(errno) if the file at
+ *      `path` can't grow to `new_size`.
+ */
+pub fn limit_truncate(path: &Path, new_size: off_t) -> Result<Option<Growth>, c_int> {
+    let quota = match quota_bytes() {
+        Some(quota) => quota,
+        None => return Ok(None),
+    };
+    let path = match quota_path(path) {
+        Some(path) => path,
+        None => return Ok(None),
+    };
+
+    let growth = Growth {
+        size: fs::metadata(&path).map(|meta| meta.len()).unwrap_or(0),
+        path,
+        fd: None,
+    };
+    TRACKING.store(true, Ordering::Relaxed);
+    let available = remaining(quota, &FILES.lock().unwrap().used);
+    check_resize(growth, available, new_size)
+}
+
+fn check_resize(growth: Growth, available: u64, new_size: off_t) -> Result<Option<Growth>, c_int> {
+    if (new_size.max(0) as u64).saturating_sub(growth.size) > available {
+        Err(quota_errno())
+    } else {
+        Ok(Some(growth))
+    }
+}
+
+/**
+ * Charges the quota for how much the file in `growth` grew, or credits it
+ *   for how much it shrank.
+ */
+pub fn charge(growth: Option<Growth>) {
+    let growth = match growth {
+        Some(growth) => growth,
+        None => return,
+    };
+    let size = match growth_size(&growth) {
+        Some(size) => size,
+        None => return,
+    };
+
+    let mut files = FILES.lock().unwrap();
+    let used = files.used.entry(growth.path).or_insert(0);
+    if size > growth.size {
+        *used += size - growth.size;
+    } else {
+        *used = used.saturating_sub(growth.size - size);
+    }
+}
+
+/**
+ * @return how many blocks of `block_size` are left in the quota, if
+ *      `path` is under it.
+ */
+pub fn free_blocks_at(path: &Path, block_size: u64) -> Option<u64> {
+    let quota = quota_bytes()?;
+    quota_path(path)?;
+    Some(remaining(quota, &FILES.lock().unwrap().used) / block_size.max(1))
+}
+
+/**
+ * @return how many blocks of `block_size` are left in the quota, if `fd`
+ *      is under it.
+ */
+pub fn free_blocks(fd: c_int, block_size: u64) -> Option<u64> {
+    if !TRACKING.load(Ordering::Relaxed) {
+        return None;
+    }
+    let quota = quota_bytes()?;
+
+    let files = FILES.lock().unwrap();
+    files.fds.get(&fd)?;
+    Some(remaining(quota, &files.used) / block_size.max(1))
+}
+
+#[cfg(test)]
+mod test {
+    use super::fitting_len;
+
+    #[test]
+    fn test_fitting_len() {
+        // Appending.
+        assert_eq!(fitting_len(100, 10, 100, 50), Some(10));
+        assert_eq!(fitting_len(100, 10, 100, 4), Some(4));
+        assert_eq!(fitting_len(100, 10, 100, 0), None);
+        assert_eq!(fitting_len(100, 0, 100, 0), Some(0));
+
+        // Overwriting takes no more room, past the end does.
+        assert_eq!(fitting_len(0, 10, 100, 0), Some(10));
+        assert_eq!(fitting_len(95, 10, 100, 2), Some(7));
+
+        // Writing past the end leaves a hole that's free.
+        assert_eq!(fitting_len(200, 10, 100, 20), Some(10));
+    }
+}
@@ -0,0 +1,790 @@
+extern crate libc;
+
+// Readiness faults.  Other modules can hide an fd from poll(), select() and
+//   epoll_wait() until a deadline, or report events on it that the kernel
+//   doesn't; the readiness hooks apply them around the real calls.  Events
+//   on fds in DELAY_FDS are also held back, dropped or made up.
+//
+// epoll keeps its interest list in the kernel, so a hidden fd is taken out
+//   of it ("parked") and put back once it's visible again.  That needs every
+//   registration, which the epoll_ctl() hook records and close() forgets,
+//   since few programs call EPOLL_CTL_DEL first.  Events are told apart
+//   by their data, so fds registered with the same data as another are left
+//   out of the faults that add or change events.
+
+use std::cmp;
+use std::collections::HashMap;
+use std::ptr;
+use std::slice;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use libc::{c_int, c_short, c_void, epoll_event, fd_set, nfds_t, pollfd, timespec, timeval};
+use storm;
+use errors::{SomeHashState, EpollCtlFunc, DELAY_FDS, ERR_FDS, chance, roll_faults};
+
+// poll() reports these whether or not they were asked for.  The POLL* and
+//   EPOLL* bits have the same values, so they're used for both.
+const ALWAYS_REPORTED: c_short = libc::POLLERR | libc::POLLHUP;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Override {
+    /// Some(None) hides the fd for good, Some(Some(t)) until t.
+    hidden: Option<Option<Instant>>,
+    /// Events reported on top of the kernel's.
+    extra: c_short,
+    /// Its readiness was held back, so it's reported next time.
+    delayed: bool,
+    /// It was reported readable when it wasn't.
+    spurious: bool,
+}
+
+const NO_OVERRIDE: Override = Override {
+    hidden: None,
+    extra: 0,
+    delayed: false,
+    spurious: false,
+};
+
+struct Registration {
+    events: u32,
+    data: u64,
+    parked: bool,
+    /// Another fd on the same epoll fd has the same data.
+    ambiguous: bool,
+}
+
+type Registrations = HashMap<(c_int, c_int), Registration, SomeHashState>;
+
+// Spares the readiness hooks a lock when there are no overrides.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+// Spares close() a lock when nothing's been registered with epoll.
+static REGISTERED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref OVERRIDES: Mutex<HashMap<c_int, Override, SomeHashState>>
+            = Mutex::new(HashMap::with_hasher(SomeHashState::default()));
+    static ref EPOLL_REGS: Mutex<Registrations>
+            = Mutex::new(HashMap::with_hasher(SomeHashState::default()));
+    static ref EPOLL_CTL_FUNC: EpollCtlFunc = get_libc_func!(EpollCtlFunc, "epoll_ctl");
+}
+
+/**
+ * Returns $err from the hook, with errno EAGAIN, if $fd was reported
+ *   readable when it wasn't.
+ */
+macro_rules! checkSpurious(
+    ($fd: expr, $err: expr) =>
+    ({
+        use errno::{Errno, set_errno};
+
+        if ready::take_spurious($fd) {
+            set_errno(Errno(libc::EAGAIN));
+            return $err;
+        }
+    }));
+
+impl Override {
+    pub fn is_hidden(&self, now: Instant) -> bool {
+        match self.hidden {
+            Some(Some(until)) => now < until,
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    /**
+     * @return the events to add to those reported for an fd that asked
+     *      for `requested`, or 0 while it's hidden.
+     */
+    pub fn extra_events(&self, requested: c_short, now: Instant) -> c_short {
+        if self.is_hidden(now) {
+            0
+        } else {
+            self.extra & (requested | ALWAYS_REPORTED)
+        }
+    }
+
+    fn hidden_until(&self) -> Option<Instant> {
+        self.hidden.unwrap_or_default()
+    }
+}
+
+fn with_override<T, F>(fd: c_int, f: F) -> T
+    where F: FnOnce(&mut Override) -> T
+{
+    ACTIVE.store(true, Ordering::Relaxed);
+    f(OVERRIDES.lock().unwrap().entry(fd).or_default())
+}
+
+/**
+ * Hides `fd` from the readiness hooks until `until`, or for good if that's
+ *   None.
+ */
+pub fn hide(fd: c_int, until: Option<Instant>) {
+    with_override(fd, |over| over.hidden = Some(until));
+}
+
+/**
+ * Reports `events` on `fd` whenever it isn't hidden, whether or not the
+ *   kernel does.  Zero stops doing so.
+ */
+pub fn set_extra_events(fd: c_int, events: c_short) {
+    with_override(fd, |over| over.extra = events);
+}
+
+/**
+ * @return true if `fd` was reported readable when it wasn't, and nothing
+ *      has tried to read it since.
+ */
+pub fn take_spurious(fd: c_int) -> bool {
+    if !ACTIVE.load(Ordering::Relaxed) {
+        return false;
+    }
+    match OVERRIDES.lock().unwrap().get_mut(&fd) {
+        Some(over) => {
+            let spurious = over.spurious;
+            over.spurious = false;
+            spurious
+        }
+        None => false,
+    }
+}
+
+pub fn forget_fd(fd: c_int) {
+    if ACTIVE.load(Ordering::Relaxed) {
+        OVERRIDES.lock().unwrap().remove(&fd);
+    }
+    if !REGISTERED.load(Ordering::Relaxed) {
+        return;
+    }
+
+    let mut regs = EPOLL_REGS.lock().unwrap();
+    let epfds: Vec<c_int> =
+        regs.keys().filter(|&&(_, reg_fd)| reg_fd == fd).map(|&(epfd, _)| epfd).collect();
+    regs.retain(|&(epfd, reg_fd), _| epfd != fd && reg_fd != fd);
+    for epfd in epfds {
+        mark_ambiguous(&mut regs, epfd);
+    }
+}
+
+/**
+ * @return true if a spurious readable event should be made up for an fd
+ *      that asked for `requested`.
+ */
+fn spurious_chance(requested: c_short) -> bool {
+    requested & libc::POLLIN != 0 && chance("LIBFAULTINJ_POLL_SPURIOUS_PCT")
+}
+
+/**
+ * Applies the faults to `events`, which the kernel reported on `fd`, an fd
+ *   in DELAY_FDS.  Readiness is held back for `delay`, after which it's
+ *   reported.
+ *
+ * @return the events to report now.
+ */
+fn fault_events(fd: c_int,
+                events: c_short,
+                delay: Duration,
+                funcname: &str,
+                now: Instant)
+                -> c_short {
+    if events == 0 {
+        return 0;
+    }
+
+    let held_back = with_override(fd, |over| {
+        if over.delayed {
+            over.delayed = false;
+            false
+        } else if delay > Duration::from_millis(0) && roll_faults(funcname, true, false).0 {
+            over.hidden = Some(Some(now + delay));
+            over.delayed = true;
+            true
+        } else {
+            false
+        }
+    });
+
+    if held_back || chance("LIBFAULTINJ_POLL_DROP_PCT") {
+        0
+    } else {
+        events
+    }
+}
+
+/**
+ * @return the earlier of two deadlines, where None is never.
+ */
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(cmp::min(a, b)),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+fn remaining(deadline: Option<Instant>, now: Instant) -> Option<Duration> {
+    deadline.map(|deadline| deadline.saturating_duration_since(now))
+}
+
+fn has_expired(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/**
+ * @return a poll()-style timeout in milliseconds, rounded up so that the
+ *      caller isn't woken just short of it.
+ */
+pub fn duration_ms(timeout: Option<Duration>) -> c_int {
+    match timeout {
+        None => -1,
+        Some(timeout) => {
+            let ms = timeout.as_secs() * 1000 +
+                     u64::from(timeout.subsec_nanos().div_ceil(1_000_000));
+            cmp::min(ms, c_int::MAX as u64) as c_int
+        }
+    }
+}
+
+pub fn ms_duration(timeout: c_int) -> Option<Duration> {
+    if timeout < 0 {
+        None
+    } else {
+        Some(Duration::from_millis(timeout as u64))
+    }
+}
+
+pub fn duration_timespec(timeout: Duration) -> timespec {
+    timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long,
+    }
+}
+
+pub unsafe fn timespec_duration(timeout: *const timespec) -> Option<Duration> {
+    if timeout.is_null() {
+        return None;
+    }
+    let timeout = unsafe { *timeout };
+    Some(Duration::new(cmp::max(timeout.tv_sec, 0) as u64,
+                       cmp::max(timeout.tv_nsec, 0) as u32))
+}
+
+pub unsafe fn timeval_duration(timeout: *const timeval) -> Option<Duration> {
+    if timeout.is_null() {
+        return None;
+    }
+    let timeout = unsafe { *timeout };
+    Some(Duration::new(cmp::max(timeout.tv_sec, 0) as u64,
+                       cmp::max(timeout.tv_usec, 0) as u32 * 1000))
+}
+
+/**
+ * @return whether any of `fds` are in ERR_FDS, and which are in DELAY_FDS.
+ */
+fn tracked_fds<I>(fds: I) -> (bool, Vec<bool>)
+    where I: Iterator<Item = c_int>
+{
+    let err_fds = ERR_FDS.read().unwrap();
+    let delay_fds = DELAY_FDS.read().unwrap();
+    let mut err_match = false;
+
+    let delayed = fds.map(|fd| {
+                         err_match |= err_fds.contains(&fd);
+                         delay_fds.contains(&fd)
+                     })
+                     .collect();
+
+    (err_match, delayed)
+}
+
+/**
+ * poll() with the faults applied.  `real_poll` is called with the same
+ *   fds, possibly several times and with other timeouts, so that hidden fds
+ *   are revealed on time.
+ */
+pub unsafe fn poll<F>(fds: *mut pollfd,
+               nfds: nfds_t,
+               timeout: Option<Duration>,
+               funcname: &'static str,
+               real_poll: F)
+               -> c_int
+    where F: Fn(*mut pollfd, nfds_t, Option<Duration>) -> c_int
+{
+    if fds.is_null() || nfds == 0 {
+        return real_poll(fds, nfds, timeout);
+    }
+
+    let fds = unsafe { slice::from_raw_parts_mut(fds, nfds as usize) };
+    let (err_match, delayed) = tracked_fds(fds.iter().map(|pfd| pfd.fd));
+    if !ACTIVE.load(Ordering::Relaxed) && !err_match && !delayed.contains(&true) {
+        return real_poll(fds.as_mut_ptr(), nfds, timeout);
+    }
+
+    returnError!(matched: err_match, funcname, -1);
+    checkStorm!(matched: err_match, -1, funcname, 0, -1);
+
+    let delay = get_delay_amount_ms!(funcname);
+    let mut spurious: Vec<bool> = fds.iter()
+                                     .zip(&delayed)
+                                     .map(|(pfd, &delayed)| delayed && spurious_chance(pfd.events))
+                                     .collect();
+    let give_up = timeout.map(|timeout| Instant::now() + timeout);
+    let mut polled = fds.to_vec();
+
+    loop {
+        let now = Instant::now();
+        let overrides: Vec<Option<Override>> = {
+            let overrides = OVERRIDES.lock().unwrap();
+            fds.iter().map(|pfd| overrides.get(&pfd.fd).copied()).collect()
+        };
+
+        let mut wake = give_up;
+        let mut report_now = spurious.contains(&true);
+        for ((pfd, polled), over) in fds.iter().zip(polled.iter_mut()).zip(&overrides) {
+            *polled = *pfd;
+            polled.revents = 0;
+            if let Some(over) = *over {
+                if over.is_hidden(now) {
+                    // poll() skips negative fds.
+                    polled.fd = -1;
+                    wake = earliest(wake, over.hidden_until());
+                }
+                report_now |= over.extra_events(pfd.events, now) != 0;
+            }
+        }
+
+        let wait = if report_now {
+            Some(Duration::from_millis(0))
+        } else {
+            remaining(wake, now)
+        };
+        let ret = real_poll(polled.as_mut_ptr(), nfds, wait);
+        if ret < 0 {
+            return ret;
+        }
+
+        let mut ready = 0;
+        for (i, pfd) in fds.iter_mut().enumerate() {
+            let mut revents = polled[i].revents;
+            if delayed[i] {
+                revents = fault_events(pfd.fd, revents, delay, funcname, now);
+            }
+            if spurious[i] && revents & libc::POLLIN == 0 {
+                with_override(pfd.fd, |over| over.spurious = true);
+                revents |= libc::POLLIN;
+            }
+            if let Some(over) = overrides[i] {
+                revents |= over.extra_events(pfd.events, now);
+            }
+
+            pfd.revents = revents;
+            if revents != 0 {
+                ready += 1;
+            }
+        }
+        spurious.clear();
+        spurious.resize(fds.len(), false);
+
+        if ready > 0 || has_expired(give_up) {
+            return ready;
+        }
+    }
+}
+
+/**
+ * select() with the faults applied, by way of poll().  `sets` are the read,
+ *   write and except sets.  `real_select` makes the untouched call when
+ *   none of the fds are affected.
+ */
+pub unsafe fn select<S, P>(nfds: c_int,
+                    sets: [*mut fd_set; 3],
+                    timeout: Option<Duration>,
+                    funcname: &'static str,
+                    real_select: S,
+                    real_poll: P)
+                    -> c_int
+    where S: FnOnce() -> c_int,
+          P: Fn(*mut pollfd, nfds_t, Option<Duration>) -> c_int
+{
+    use errno::{Errno, set_errno};
+
+    let is_set = |fd: c_int, set: *mut fd_set| !set.is_null() && unsafe { libc::FD_ISSET(fd, set) };
+
+    let [readfds, writefds, exceptfds] = sets;
+    let mut pfds = Vec::new();
+    for fd in 0..cmp::min(nfds, libc::FD_SETSIZE as c_int) {
+        let mut events = 0;
+        if is_set(fd, readfds) {
+            events |= libc::POLLIN;
+        }
+        if is_set(fd, writefds) {
+            events |= libc::POLLOUT;
+        }
+        if is_set(fd, exceptfds) {
+            events |= libc::POLLPRI;
+        }
+        if events != 0 {
+            pfds.push(pollfd {
+                fd,
+                events,
+                revents: 0,
+            });
+        }
+    }
+
+    let (err_match, delayed) = tracked_fds(pfds.iter().map(|pfd| pfd.fd));
+    if pfds.is_empty() ||
+       !ACTIVE.load(Ordering::Relaxed) && !err_match && !delayed.contains(&true) {
+        return real_select();
+    }
+
+    let ret = poll(pfds.as_mut_ptr(), pfds.len() as nfds_t, timeout, funcname, real_poll);
+    if ret < 0 {
+        return ret;
+    }
+    if pfds.iter().any(|pfd| pfd.revents & libc::POLLNVAL != 0) {
+        set_errno(Errno(libc::EBADF));
+        return -1;
+    }
+
+    // These are the kernel's POLLIN_SET, POLLOUT_SET and POLLEX_SET.
+    let sets = [(readfds, libc::POLLIN | libc::POLLHUP | libc::POLLERR),
+                (writefds, libc::POLLOUT | libc::POLLERR),
+                (exceptfds, libc::POLLPRI)];
+    let mut ready = 0;
+    for &(set, revents) in &sets {
+        if set.is_null() {
+            continue;
+        }
+        unsafe { libc::FD_ZERO(set) };
+        for pfd in &pfds {
+            if pfd.events & revents != 0 && pfd.revents & revents != 0 {
+                unsafe { libc::FD_SET(pfd.fd, set) };
+                ready += 1;
+            }
+        }
+    }
+
+    ready
+}
+
+/**
+ * Marks the registrations with `epfd` that share their data with another.
+ */
+fn mark_ambiguous(regs: &mut Registrations, epfd: c_int) {
+    let mut counts: HashMap<u64, usize> = HashMap::new();
+    for (_, reg) in regs.iter().filter(|&(&(reg_epfd, _), _)| reg_epfd == epfd) {
+        *counts.entry(reg.data).or_insert(0) += 1;
+    }
+    for (_, reg) in regs.iter_mut().filter(|&(&(reg_epfd, _), _)| reg_epfd == epfd) {
+        reg.ambiguous = counts[&reg.data] > 1;
+    }
+}
+
+/**
+ * epoll_ctl(), recording each registration and leaving hidden fds parked.
+ */
+pub unsafe fn epoll_ctl(epfd: c_int, op: c_int, fd: c_int, event: *mut epoll_event) -> c_int {
+    use errno::{Errno, set_errno};
+
+    REGISTERED.store(true, Ordering::Relaxed);
+    let mut regs = EPOLL_REGS.lock().unwrap();
+    let parked = regs.get(&(epfd, fd)).is_some_and(|reg| reg.parked);
+
+    match op {
+        libc::EPOLL_CTL_ADD | libc::EPOLL_CTL_MOD if !event.is_null() => {
+            let ev = unsafe { *event };
+            let hidden = ACTIVE.load(Ordering::Relaxed) &&
+                         OVERRIDES.lock()
+                                  .unwrap()
+                                  .get(&fd)
+                                  .is_some_and(|over| over.is_hidden(Instant::now()));
+            let reg = Registration {
+                events: ev.events,
+                data: ev.u64,
+                parked: parked || (op == libc::EPOLL_CTL_ADD && hidden),
+                ambiguous: false,
+            };
+
+            if parked && op == libc::EPOLL_CTL_ADD {
+                set_errno(Errno(libc::EEXIST));
+                return -1;
+            }
+            if reg.parked {
+                regs.insert((epfd, fd), reg);
+                mark_ambiguous(&mut regs, epfd);
+                return 0;
+            }
+
+            let ret = EPOLL_CTL_FUNC(epfd, op, fd, event);
+            if ret == 0 {
+                regs.insert((epfd, fd), reg);
+                mark_ambiguous(&mut regs, epfd);
+            }
+            ret
+        }
+        libc::EPOLL_CTL_DEL => {
+            if parked {
+                regs.remove(&(epfd, fd));
+                mark_ambiguous(&mut regs, epfd);
+                return 0;
+            }
+
+            let ret = EPOLL_CTL_FUNC(epfd, op, fd, event);
+            if ret == 0 {
+                regs.remove(&(epfd, fd));
+                mark_ambiguous(&mut regs, epfd);
+            }
+            ret
+        }
+        _ => EPOLL_CTL_FUNC(epfd, op, fd, event),
+    }
+}
+
+/**
+ * @return the (fd, events, data) registered with `epfd`, where data is
+ *      None if it's ambiguous.
+ */
+fn registrations(epfd: c_int) -> Vec<(c_int, u32, Option<u64>)> {
+    EPOLL_REGS.lock()
+              .unwrap()
+              .iter()
+              .filter(|&(&(reg_epfd, _), _)| reg_epfd == epfd)
+              .map(|(&(_, fd), reg)| (fd, reg.events, Some(reg.data).filter(|_| !reg.ambiguous)))
+              .collect()
+}
+
+/**
+ * Parks the fds registered with `epfd` that are hidden and puts back those
+ *   that no longer are.
+ *
+ * @return when the next parked fd is revealed, and the extra events of
+ *      those that aren't parked as (data, events) pairs.
+ */
+fn sync_epoll(epfd: c_int, now: Instant) -> (Option<Instant>, Vec<(u64, u32)>) {
+    let mut regs = EPOLL_REGS.lock().unwrap();
+    let overrides = OVERRIDES.lock().unwrap();
+    let mut wake = None;
+    let mut extra = Vec::new();
+
+    for (&(reg_epfd, fd), reg) in regs.iter_mut() {
+        if reg_epfd != epfd {
+            continue;
+        }
+        let over = match overrides.get(&fd) {
+            Some(over) => over,
+            None if reg.parked => &NO_OVERRIDE,
+            None => continue,
+        };
+
+        let hidden = over.is_hidden(now);
+        if hidden && !reg.parked {
+            EPOLL_CTL_FUNC(epfd, libc::EPOLL_CTL_DEL, fd, ptr::null_mut());
+            reg.parked = true;
+        } else if !hidden && reg.parked {
+            let mut ev = epoll_event {
+                events: reg.events,
+                u64: reg.data,
+            };
+            EPOLL_CTL_FUNC(epfd, libc::EPOLL_CTL_ADD, fd, &mut ev);
+            reg.parked = false;
+        }
+
+        // Parking goes by fd, and puts an fd back with its own data, but
+        //   made-up events would be put down to whichever fd came first.
+        if hidden {
+            wake = earliest(wake, over.hidden_until());
+        } else if !reg.ambiguous {
+            let events = over.extra_events(reg.events as c_short, now) as u32;
+            if events != 0 {
+                extra.push((reg.data, events));
+            }
+        }
+    }
+
+    (wake, extra)
+}
+
+/**
+ * epoll_wait() with the faults applied.  `real_wait` takes the events
+ *   buffer, its size and a timeout.
+ */
+pub unsafe fn epoll_wait<F>(epfd: c_int,
+                     events: *mut epoll_event,
+                     maxevents: c_int,
+                     timeout: c_int,
+                     funcname: &'static str,
+                     real_wait: F)
+                     -> c_int
+    where F: Fn(*mut epoll_event, c_int, c_int) -> c_int
+{
+    if events.is_null() || maxevents <= 0 {
+        return real_wait(events, maxevents, timeout);
+    }
+
+    let targets = registrations(epfd);
+    let (err_match, delayed) = tracked_fds(targets.iter().map(|&(fd, _, _)| fd));
+    if !ACTIVE.load(Ordering::Relaxed) && !err_match && !delayed.contains(&true) {
+        return real_wait(events, maxevents, timeout);
+    }
+
+    returnError!(matched: err_match, funcname, -1);
+    checkStorm!(matched: err_match, -1, funcname, 0, -1);
+
+    let delay = get_delay_amount_ms!(funcname);
+    let mut spurious: Vec<(c_int, u64)> =
+        targets.iter()
+               .zip(&delayed)
+               .filter_map(|(&(fd, events, data), &delayed)| {
+                   Some((fd, data?)).filter(|_| delayed && spurious_chance(events as c_short))
+               })
+               .collect();
+    let give_up = ms_duration(timeout).map(|timeout| Instant::now() + timeout);
+    let events = unsafe { slice::from_raw_parts_mut(events, maxevents as usize) };
+
+    loop {
+        let now = Instant::now();
+        let (wake, mut extra) = sync_epoll(epfd, now);
+
+        let wait = if extra.is_empty() && spurious.is_empty() {
+            duration_ms(remaining(earliest(give_up, wake), now))
+        } else {
+            0
+        };
+        let ret = real_wait(events.as_mut_ptr(), maxevents, wait);
+        if ret < 0 {
+            return ret;
+        }
+
+        let mut ready = 0;
+        for i in 0..ret as usize {
+            let data = events[i].u64;
+            let mut revents = events[i].events;
+
+            if let Some(pos) = targets.iter().position(|&(_, _, reg_data)| reg_data == Some(data)) {
+                let fd = targets[pos].0;
+                if delayed[pos] {
+                    let faulted = fault_events(fd, revents as c_short, delay, funcname, now);
+                    revents = faulted as u16 as u32;
+                }
+                if revents & libc::EPOLLIN as u32 != 0 {
+                    spurious.retain(|&(spurious_fd, _)| spurious_fd != fd);
+                }
+            }
+            if let Some(pos) = extra.iter().position(|&(extra_data, _)| extra_data == data) {
+                revents |= extra.swap_remove(pos).1;
+            }
+
+            if revents != 0 {
+                events[ready] = epoll_event {
+                    events: revents,
+                    u64: data,
+                };
+                ready += 1;
+            }
+        }
+
+        let made_up = extra.into_iter()
+                           .chain(spurious.drain(..).map(|(fd, data)| {
+                               with_override(fd, |over| over.spurious = true);
+                               (data, libc::EPOLLIN as u32)
+                           }));
+        for (data, more) in made_up {
+            if ready == events.len() {
+                break;
+            }
+            events[ready] = epoll_event {
+                events: more,
+                u64: data,
+            };
+            ready += 1;
+        }
+
+        if ready > 0 || has_expired(give_up) {
+            return ready as c_int;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Registration, Registrations, duration_ms, epoll_ctl, fault_events, forget_fd,
+                mark_ambiguous, registrations, take_spurious, with_override};
+    use errors::SomeHashState;
+    use std::time::{Duration, Instant};
+    extern crate libc;
+
+    #[test]
+    fn test_duration_ms() {
+        assert_eq!(duration_ms(None), -1);
+        assert_eq!(duration_ms(Some(Duration::from_millis(0))), 0);
+        assert_eq!(duration_ms(Some(Duration::new(1, 1))), 1001);
+        assert_eq!(duration_ms(Some(Duration::from_secs(1 << 40))), i32::MAX);
+    }
+
+    #[test]
+    fn test_delayed_readiness() {
+        // Well clear of any fd the test harness might poll.
+        let fd = 100_000;
+        let now = Instant::now();
+        let delay = Duration::from_millis(50);
+
+        assert_eq!(fault_events(fd, 0, delay, "poll", now), 0);
+        assert_eq!(fault_events(fd, libc::POLLIN, delay, "poll", now), 0);
+        assert!(with_override(fd, |over| over.is_hidden(now)));
+        assert!(!with_override(fd, |over| over.is_hidden(now + delay)));
+        assert_eq!(fault_events(fd, libc::POLLIN, delay, "poll", now + delay), libc::POLLIN);
+
+        with_override(fd, |over| over.spurious = true);
+        assert!(take_spurious(fd));
+        assert!(!take_spurious(fd));
+    }
+
+    #[test]
+    fn test_ambiguous_data() {
+        let reg = |data| {
+            Registration {
+                events: libc::EPOLLIN as u32,
+                data,
+                parked: false,
+                ambiguous: false,
+            }
+        };
+        let mut regs = Registrations::with_hasher(SomeHashState::default());
+        regs.insert((5, 7), reg(0));
+        regs.insert((5, 8), reg(0));
+        regs.insert((5, 9), reg(9));
+        regs.insert((6, 10), reg(0));
+
+        mark_ambiguous(&mut regs, 5);
+        assert!(regs[&(5, 7)].ambiguous && regs[&(5, 8)].ambiguous);
+        assert!(!regs[&(5, 9)].ambiguous && !regs[&(6, 10)].ambiguous);
+
+        regs.remove(&(5, 8));
+        mark_ambiguous(&mut regs, 5);
+        assert!(!regs[&(5, 7)].ambiguous);
+    }
+
+    #[test]
+    fn test_forget_registered() {
+        let epfd = unsafe { libc::epoll_create1(0) };
+        let fd = unsafe { libc::eventfd(0, 0) };
+        let mut ev = libc::epoll_event {
+            events: libc::EPOLLIN as u32,
+            u64: 1,
+        };
+        assert_eq!(unsafe { epoll_ctl(epfd, libc::EPOLL_CTL_ADD, fd, &mut ev) }, 0);
+        assert_eq!(registrations(epfd), vec![(fd, libc::EPOLLIN as u32, Some(1))]);
+
+        // Closed without EPOLL_CTL_DEL first.
+        forget_fd(fd);
+        assert_eq!(registrations(epfd), vec![]);
+        unsafe {
+            libc::close(fd);
+            libc::close(epfd);
+        }
+    }
+}
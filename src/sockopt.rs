@@ -0,0 +1,140 @@
+extern crate libc;
+
+// Socket options.  setsockopt(), getsockopt(), shutdown(), getsockname()
+//   and getpeername() fail with LIBFAULTINJ_ERROR_<FUNC>_ERRNO, or are
+//   delayed, on selected sockets like any other call.
+//   LIBFAULTINJ_SOCKOPT_NAMES narrows the faults in setsockopt() and
+//   getsockopt() to the options it lists.
+//
+// getsockopt() can also lie about sockets in ERR_FDS:
+//   LIBFAULTINJ_SOCKOPT_VALUES lists NAME=VALUE pairs to report instead of
+//   what the kernel says, like SO_SNDBUF=4096,SO_ERROR=104.  Only options
+//   that hold an int can be lied about.
+//
+// Options are named as in C, SO_* at SOL_SOCKET, TCP_* at IPPROTO_TCP and
+//   IPV6_V6ONLY, or given as LEVEL:OPTNAME numbers.
+
+use std::mem::size_of;
+
+use libc::{c_int, c_void, socklen_t};
+use errors::ERR_FDS;
+
+const OPTIONS: &[(&str, c_int, c_int)] = &[
+    ("so_error", libc::SOL_SOCKET, libc::SO_ERROR),
+    ("so_sndbuf", libc::SOL_SOCKET, libc::SO_SNDBUF),
+    ("so_rcvbuf", libc::SOL_SOCKET, libc::SO_RCVBUF),
+    ("so_keepalive", libc::SOL_SOCKET, libc::SO_KEEPALIVE),
+    ("so_reuseaddr", libc::SOL_SOCKET, libc::SO_REUSEADDR),
+    ("so_reuseport", libc::SOL_SOCKET, libc::SO_REUSEPORT),
+    ("so_broadcast", libc::SOL_SOCKET, libc::SO_BROADCAST),
+    ("so_type", libc::SOL_SOCKET, libc::SO_TYPE),
+    ("so_linger", libc::SOL_SOCKET, libc::SO_LINGER),
+    ("so_rcvtimeo", libc::SOL_SOCKET, libc::SO_RCVTIMEO),
+    ("so_sndtimeo", libc::SOL_SOCKET, libc::SO_SNDTIMEO),
+    ("tcp_nodelay", libc::IPPROTO_TCP, libc::TCP_NODELAY),
+    ("tcp_maxseg", libc::IPPROTO_TCP, libc::TCP_MAXSEG),
+    ("tcp_keepidle", libc::IPPROTO_TCP, libc::TCP_KEEPIDLE),
+    ("tcp_keepintvl", libc::IPPROTO_TCP, libc::TCP_KEEPINTVL),
+    ("tcp_keepcnt", libc::IPPROTO_TCP, libc::TCP_KEEPCNT),
+    ("tcp_user_timeout", libc::IPPROTO_TCP, libc::TCP_USER_TIMEOUT),
+    ("ipv6_v6only", libc::IPPROTO_IPV6, libc::IPV6_V6ONLY),
+];
+
+// Options in OPTIONS that hold a struct rather than an int.
+const STRUCT_OPTIONS: &[(c_int, c_int)] = &[
+    (libc::SOL_SOCKET, libc::SO_LINGER),
+    (libc::SOL_SOCKET, libc::SO_RCVTIMEO),
+    (libc::SOL_SOCKET, libc::SO_SNDTIMEO),
+];
+
+/**
+ * @return the level and optname of the option `name`, or None if it's
+ *      not one we know.
+ */
+pub fn parse_option(name: &str) -> Option<(c_int, c_int)> {
+    let name = name.trim().to_lowercase();
+    if let Some(colon) = name.find(':') {
+        let level = name[..colon].trim().parse::<c_int>().ok()?;
+        let optname = name[colon + 1..].trim().parse::<c_int>().ok()?;
+        return Some((level, optname));
+    }
+
+    OPTIONS.iter()
+           .find(|&&(known, _, _)| known == name)
+           .map(|&(_, level, optname)| (level, optname))
+}
+
+/**
+ * @return the options and values in the comma-separated NAME=VALUE list
+ *      `values`.  Unparseable entries, and options that don't hold an int,
+ *      are ignored.
+ */
+pub fn parse_values(values: &str) -> Vec<((c_int, c_int), c_int)> {
+    values.split(',')
+          .filter_map(|entry| {
+              let eq = entry.find('=')?;
+              let option = parse_option(&entry[..eq])?;
+              if STRUCT_OPTIONS.contains(&option) {
+                  return None;
+              }
+              let value = entry[eq + 1..].trim().parse::<c_int>().ok()?;
+              Some((option, value))
+          })
+          .collect()
+}
+
+/**
+ * @return true if faults in setsockopt() and getsockopt() apply to the
+ *      option `optname` at `level`.
+ */
+pub fn option_selected(level: c_int, optname: c_int) -> bool {
+    match ::std::env::var("LIBFAULTINJ_SOCKOPT_NAMES") {
+        Ok(names) => names.split(',').filter_map(parse_option).any(|o| o == (level, optname)),
+        Err(_) => true,
+    }
+}
+
+/**
+ * Replaces what getsockopt() just put in `optval` with the value
+ *   LIBFAULTINJ_SOCKOPT_VALUES gives the option, if `fd` is in ERR_FDS.
+ *   Only options the kernel reported as an int are lied about.
+ */
+pub unsafe fn lie(fd: c_int,
+           level: c_int,
+           optname: c_int,
+           optval: *mut c_void,
+           optlen: *mut socklen_t) {
+    let values = match ::std::env::var("LIBFAULTINJ_SOCKOPT_VALUES") {
+        Ok(values) => parse_values(&values),
+        Err(_) => return,
+    };
+    let value = match values.iter().find(|&&(option, _)| option == (level, optname)) {
+        Some(&(_, value)) => value,
+        None => return,
+    };
+    if optval.is_null() || optlen.is_null() ||
+       unsafe { *optlen as usize != size_of::<c_int>() } ||
+       !ERR_FDS.read().unwrap().contains(&fd) {
+        return;
+    }
+
+    unsafe { *(optval as *mut c_int) = value };
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_option, parse_values};
+    extern crate libc;
+
+    #[test]
+    fn test_parse_values() {
+        assert_eq!(parse_option(" TCP_NODELAY"), Some((libc::IPPROTO_TCP, libc::TCP_NODELAY)));
+        assert_eq!(parse_option("1:7"), Some((1, 7)));
+        assert_eq!(parse_option("SO_BOGUS"), None);
+
+        assert_eq!(parse_values("SO_SNDBUF=4096, so_error = 104,SO_BOGUS=1,TCP_NODELAY=x"),
+                   vec![((libc::SOL_SOCKET, libc::SO_SNDBUF), 4096),
+                        ((libc::SOL_SOCKET, libc::SO_ERROR), 104)]);
+        assert_eq!(parse_values("SO_LINGER=1,SO_RCVTIMEO=5,SO_SNDTIMEO=5"), vec![]);
+    }
+}
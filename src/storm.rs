@@ -0,0 +1,119 @@
+extern crate libc;
+
+// EINTR and EAGAIN storms.  A matched call fails LIBFAULTINJ_STORM_COUNT
+//   times in a row, or LIBFAULTINJ_STORM_<FUNC>_COUNT for that function,
+//   before one gets through.  That checks that callers retry rather than
+//   give up, which returnError! can't.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use libc::c_int;
+use errors::{SomeHashState, get_env_value, is_nonblocking};
+
+// Spares close() a lock when there's never been a storm.
+static STORMING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // How many times in a row each (fd, function) has failed.
+    static ref STORMS: Mutex<HashMap<(c_int, &'static str), u64, SomeHashState>>
+            = Mutex::new(HashMap::with_hasher(SomeHashState::default()));
+}
+
+/**
+ * Returns $err from the hook, with errno EINTR, or EAGAIN if $fd is
+ *   non-blocking or $flags has MSG_DONTWAIT, while a storm is under way.
+ *   Calls that don't take flags pass 0, and those not about one fd pass -1
+ *   for $fd.
+ */
+macro_rules! checkStorm(
+    (matched: $matched:expr, $fd:expr, $funcname:expr, $flags:expr, $err:expr) =>
+    ({
+        use errno::{Errno, set_errno};
+
+        if $matched {
+            if let Some(err) = storm::storm_errno($fd, $funcname, $flags) {
+                set_errno(Errno(err));
+                return $err;
+            }
+        }
+    });
+    ($fd:expr, $funcname:expr, $flags:expr, $err:expr) =>
+    (
+        checkStorm!(matched: ERR_FDS.read().unwrap().contains(&$fd), $fd, $funcname, $flags,
+                    $err)
+    ));
+
+/**
+ * @return the length of storms for `funcname`, or None for no storms.
+ */
+pub fn storm_count(funcname: &str) -> Option<u64> {
+    let env_name = "LIBFAULTINJ_STORM_".to_string() + &funcname.to_uppercase() + "_COUNT";
+
+    get_env_value::<u64>(&env_name)
+        .or_else(|| get_env_value::<u64>("LIBFAULTINJ_STORM_COUNT"))
+        .filter(|&count| count > 0)
+}
+
+/**
+ * Counts a failure against `failed`.
+ *
+ * @return true if this call fails, false if it's let through, which
+ *      starts the next storm.
+ */
+pub fn next_in_storm(failed: &mut u64, count: u64) -> bool {
+    if *failed >= count {
+        *failed = 0;
+        false
+    } else {
+        *failed += 1;
+        true
+    }
+}
+
+/**
+ * @return Some(errno) if this call of `funcname` on `fd` fails as part of
+ *      a storm, None if it's let through.
+ */
+pub fn storm_errno(fd: c_int, funcname: &'static str, flags: c_int) -> Option<c_int> {
+    let count = storm_count(funcname)?;
+
+    STORMING.store(true, Ordering::Relaxed);
+    let mut storms = STORMS.lock().unwrap();
+    if !next_in_storm(storms.entry((fd, funcname)).or_insert(0), count) {
+        return None;
+    }
+
+    if flags & libc::MSG_DONTWAIT != 0 || (fd >= 0 && is_nonblocking(fd)) {
+        Some(libc::EAGAIN)
+    } else {
+        Some(libc::EINTR)
+    }
+}
+
+pub fn forget_fd(fd: c_int) {
+    if !STORMING.load(Ordering::Relaxed) {
+        return;
+    }
+    STORMS.lock().unwrap().retain(|&(storm_fd, _), _| storm_fd != fd);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{next_in_storm, storm_count};
+    use std::env;
+
+    #[test]
+    fn test_storm() {
+        let mut failed = 0;
+        let calls: Vec<bool> = (0..7).map(|_| next_in_storm(&mut failed, 3)).collect();
+        assert_eq!(calls, vec![true, true, true, false, true, true, true]);
+
+        env::set_var("LIBFAULTINJ_STORM_STORMTEST_COUNT", "2");
+        assert_eq!(storm_count("stormtest"), Some(2));
+        env::set_var("LIBFAULTINJ_STORM_STORMTEST_COUNT", "0");
+        assert_eq!(storm_count("stormtest"), None);
+        env::remove_var("LIBFAULTINJ_STORM_STORMTEST_COUNT");
+    }
+}
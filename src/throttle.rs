@@ -0,0 +1,219 @@
+extern crate libc;
+
+// Bandwidth throttling.  Each fd in DELAY_FDS gets a token bucket that
+//   fills at LIBFAULTINJ_THROTTLE_BPS bytes per second and holds up to
+//   LIBFAULTINJ_THROTTLE_BURST bytes.  A call that moves more than the
+//   bucket holds sleeps until the bytes would have trickled through, so
+//   the delay grows with the size of the transfer.  With
+//   LIBFAULTINJ_THROTTLE_MODE=short, a stream transfer is instead cut down
+//   to what the bucket holds, as a slow disk or congested link would.
+
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+use libc::{c_int, size_t, ssize_t};
+use errors::{SomeHashState, DELAY_FDS, get_env_value, get_socket_type};
+use kind::fd_kind;
+
+// Spares close() a lock when nothing has been throttled.
+static THROTTLING: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    static ref BUCKETS: Mutex<HashMap<c_int, Bucket, SomeHashState>>
+            = Mutex::new(HashMap::with_hasher(SomeHashState::default()));
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    /// Bytes per second.
+    bps: f64,
+    /// Bytes that may move at once without waiting.
+    burst: f64,
+}
+
+impl Rate {
+    pub fn new(bps: u64, burst: Option<u64>) -> Rate {
+        // A tenth of a second's worth unless told otherwise, and at least
+        //   one byte so short transfers always make progress.
+        let burst = burst.unwrap_or(bps / 10).max(1);
+        Rate {
+            bps: bps as f64,
+            burst: burst as f64,
+        }
+    }
+
+    /**
+     * @return the configured rate, or None if throttling is off.
+     */
+    pub fn from_env() -> Option<Rate> {
+        get_env_value::<u64>("LIBFAULTINJ_THROTTLE_BPS")
+            .filter(|&bps| bps > 0)
+            .map(|bps| Rate::new(bps, get_env_value::<u64>("LIBFAULTINJ_THROTTLE_BURST")))
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Bucket {
+    /// Negative while paying off a transfer bigger than what was held.
+    tokens: f64,
+    filled_at: Instant,
+}
+
+impl Bucket {
+    pub fn new(rate: Rate, now: Instant) -> Bucket {
+        Bucket {
+            tokens: rate.burst,
+            filled_at: now,
+        }
+    }
+
+    pub fn refill(&mut self, rate: Rate, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.filled_at);
+        self.tokens = (self.tokens + elapsed.as_secs_f64() * rate.bps).min(rate.burst);
+        self.filled_at = now;
+    }
+
+    /**
+     * @return how long until the bucket holds `bytes`.
+     */
+    pub fn wait_for(&self, rate: Rate, bytes: f64) -> Duration {
+        if self.tokens >= bytes {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64((bytes - self.tokens) / rate.bps)
+        }
+    }
+
+    pub fn take(&mut self, bytes: f64) {
+        self.tokens -= bytes;
+    }
+}
+
+/**
+ * @return the rate for `fd`, or None if it isn't throttled.
+ */
+fn rate_for(fd: c_int) -> Option<Rate> {
+    let rate = Rate::from_env()?;
+    if DELAY_FDS.read().unwrap().contains(&fd) {
+        Some(rate)
+    } else {
+        None
+    }
+}
+
+/**
+ * Runs `f` on the bucket for `fd`, creating a full one if needed.
+ */
+fn with_bucket<T, F>(fd: c_int, rate: Rate, f: F) -> T
+    where F: FnOnce(&mut Bucket) -> T
+{
+    let now = Instant::now();
+
+    THROTTLING.store(true, Ordering::Relaxed);
+    let mut buckets = BUCKETS.lock().unwrap();
+    let bucket = buckets.entry(fd).or_insert_with(|| Bucket::new(rate, now));
+    bucket.refill(rate, now);
+    f(bucket)
+}
+
+/**
+ * Waits for the bucket of `fd` to hold at least a byte when short
+ *   transfers are on.
+ *
+ * @return how many of `len` bytes a stream transfer on `fd` should move.
+ */
+pub fn limit(fd: c_int, len: size_t) -> size_t {
+    let rate = match rate_for(fd) {
+        Some(rate) => rate,
+        None => return len,
+    };
+    let short = ::std::env::var("LIBFAULTINJ_THROTTLE_MODE").map(|mode| mode == "short");
+    if !short.unwrap_or(false) || len == 0 {
+        return len;
+    }
+    // Shortening a datagram would truncate it rather than slow it down, and
+    //   eventfd()s and the like can't be read or written in part.
+    if get_socket_type(fd).is_some_and(|kind| kind != libc::SOCK_STREAM) ||
+       fd_kind(fd).is_some_and(|kind| !kind.is_stream()) {
+        return len;
+    }
+
+    sleep(with_bucket(fd, rate, |bucket| bucket.wait_for(rate, 1.0)));
+    with_bucket(fd, rate, |bucket| short_len(len, bucket.tokens))
+}
+
+fn short_len(len: size_t, tokens: f64) -> size_t {
+    len.min(tokens.max(1.0) as size_t)
+}
+
+/**
+ * Charges the `ret` bytes a call on `fd` moved, sleeping until they'd have
+ *   got through at the configured rate.
+ */
+pub fn after_io(fd: c_int, ret: ssize_t) {
+    if ret <= 0 {
+        return;
+    }
+    let rate = match rate_for(fd) {
+        Some(rate) => rate,
+        None => return,
+    };
+
+    let bytes = ret as f64;
+    let wait = with_bucket(fd, rate, |bucket| {
+        let wait = bucket.wait_for(rate, bytes);
+        bucket.take(bytes);
+        wait
+    });
+    sleep(wait);
+}
+
+/**
+ * @return the bytes moved by the first `ret` of `msgs`, as sendmmsg() and
+ *      recvmmsg() report them.
+ */
+pub fn mmsg_bytes(msgs: &[libc::mmsghdr], ret: c_int) -> ssize_t {
+    if ret <= 0 {
+        return 0;
+    }
+    msgs.iter().take(ret as usize).map(|msg| msg.msg_len as ssize_t).sum()
+}
+
+pub fn forget_fd(fd: c_int) {
+    if !THROTTLING.load(Ordering::Relaxed) {
+        return;
+    }
+    BUCKETS.lock().unwrap().remove(&fd);
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Bucket, Rate, short_len};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_token_bucket() {
+        let rate = Rate::new(1000, Some(100));
+        let start = Instant::now();
+        let mut bucket = Bucket::new(rate, start);
+
+        assert_eq!(bucket.wait_for(rate, 100.0), Duration::from_secs(0));
+        assert_eq!(bucket.wait_for(rate, 600.0), Duration::from_millis(500));
+        bucket.take(600.0);
+
+        // Half a second pays off the debt, and refilling stops at the burst.
+        bucket.refill(rate, start + Duration::from_millis(500));
+        assert!(bucket.tokens.abs() < 1e-6);
+        bucket.refill(rate, start + Duration::from_secs(10));
+        assert_eq!(bucket.wait_for(rate, 100.0), Duration::from_secs(0));
+        assert_eq!(bucket.wait_for(rate, 200.0), Duration::from_millis(100));
+
+        assert_eq!(Rate::new(5, None), Rate::new(5, Some(1)));
+        assert_eq!(short_len(4096, 100.0), 100);
+        assert_eq!(short_len(4096, 0.5), 1);
+        assert_eq!(short_len(10, 100.0), 10);
+    }
+}
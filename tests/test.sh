@@ -19,6 +19,24 @@ LIBFAULTINJ_ERROR_PATH=Cargo.toml \
     LIBFAULTINJ_ERROR_OPEN_ERRNO=35 \
     cat src/fault.rs > /dev/null
 
+# lsattr's FS_IOC_GETFLAGS isn't one of the requests selected.
+LIBFAULTINJ_ERROR_PATH=Cargo.toml \
+    LIBFAULTINJ_ERROR_IOCTL_ERRNO=5 \
+    LIBFAULTINJ_IOCTL_REQUESTS=FIONREAD,TIOCGWINSZ lsattr Cargo.toml > /dev/null
+
+# A block device's size can be faked on a file.
+BLOCKDEV_SIZE=$(LIBFAULTINJ_ERROR_PATH=Cargo.toml \
+    LIBFAULTINJ_IOCTL_VALUES=BLKGETSIZE64=1048576 blockdev --getsize64 Cargo.toml)
+[ "${BLOCKDEV_SIZE}" = 1048576 ] || error_handler $LINENO
+
+# stdin isn't selected, only stderr.
+LIBFAULTINJ_ERROR_FD=stderr \
+    LIBFAULTINJ_ERROR_READ_ERRNO=5 cat < Cargo.toml > /dev/null
+
+# fd 3 isn't selected, only fd 4.
+LIBFAULTINJ_ERROR_FD=4 \
+    LIBFAULTINJ_ERROR_READ_ERRNO=5 bash -c 'read -u 3 LINE' 3< Cargo.toml
+
 trap - ERR
 set +e
 
@@ -45,9 +63,22 @@ LIBFAULTINJ_ERROR_PATH=tests/ \
 #   LIBFAULTINJ_ERROR_LSEEK_ERRNO=1 dd if=/dev/zero of=tests/discard count=1 seek=1 > /dev/null 2>&1  ; [ $? -eq 1 ] || error_handler $LINENO
 
 
-# TODO: We need an injection test for ioctl(). So far, no simple shell 
-#     commands spring to mind.  hwclock requires superuser to access /dev/rtc
-#     and tty control (reset, stty) interact with STD{IN,OUT}_FILENO that skips
-#     our open() hook.
+LIBFAULTINJ_ERROR_PATH=Cargo.toml \
+    LIBFAULTINJ_ERROR_IOCTL_ERRNO=5 lsattr Cargo.toml > /dev/null 2>&1  ; [ $? -eq 1 ] || error_handler $LINENO
+
+# Fds inherited from the shell, by number and by where they lead.
+LIBFAULTINJ_ERROR_FD=stdin \
+    LIBFAULTINJ_ERROR_READ_ERRNO=5 cat < Cargo.toml > /dev/null 2>&1  ; [ $? -eq 1 ] || error_handler $LINENO
+
+LIBFAULTINJ_ERROR_FD=stdout \
+    LIBFAULTINJ_ERROR_WRITE_ERRNO=32 dd if=Cargo.toml > /dev/null 2>&1  ; [ $? -eq 1 ] || error_handler $LINENO
+
+STDOUT_FILE=$(mktemp)
+trap 'rm -f "${STDOUT_FILE}"' EXIT
+LIBFAULTINJ_ERROR_PATH=${STDOUT_FILE} \
+    LIBFAULTINJ_ERROR_WRITE_ERRNO=5 dd if=Cargo.toml > ${STDOUT_FILE} 2>/dev/null  ; [ $? -eq 1 ] || error_handler $LINENO
+
+LIBFAULTINJ_ERROR_FD=3 \
+    LIBFAULTINJ_ERROR_READ_ERRNO=5 bash -c 'read -u 3 LINE' 3< Cargo.toml 2>/dev/null  ; [ $? -eq 1 ] || error_handler $LINENO
 
 exit 0